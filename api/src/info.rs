@@ -300,6 +300,11 @@ pub struct TlsTemplate {
     ///
     /// Corresponds to the combined length of the `.tdata` and `.tbss` sections.
     pub mem_size: u64,
+    /// The required alignment of the thread local storage block.
+    ///
+    /// Corresponds to the alignment of the TLS segment. Defaults to 8 if the
+    /// segment doesn't specify an alignment.
+    pub align: u64,
 }
 
 /// FFI-safe variant of [`Option`].
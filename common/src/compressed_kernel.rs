@@ -0,0 +1,395 @@
+use crate::{
+    level_4_entries::UsedLevel4Entries,
+    load_kernel::{
+        load_kernel_from_source, KernelSource, LoadCallbacks, LoadKernelError, LoadedKernel,
+    },
+};
+use core::{cmp, ops::Range};
+use miniz_oxide::inflate::{
+    core::{decompress, inflate_flags, DecompressorOxide},
+    TINFLStatus,
+};
+use x86_64::structures::paging::{mapper::MapperAllSizes, FrameAllocator, Size4KiB, Translate};
+
+/// The first bytes of a gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// The size of the fixed part of the gzip header.
+const GZIP_HEADER_SIZE: usize = 10;
+/// The size of the gzip trailer, which contains a CRC-32 and the size of the
+/// uncompressed file.
+const GZIP_TRAILER_SIZE: usize = 8;
+/// The compression method of gzip files that use deflate.
+const GZIP_METHOD_DEFLATE: u8 = 8;
+/// The gzip header flags for the optional header fields.
+const GZIP_FHCRC: u8 = 1 << 1;
+const GZIP_FEXTRA: u8 = 1 << 2;
+const GZIP_FNAME: u8 = 1 << 3;
+const GZIP_FCOMMENT: u8 = 1 << 4;
+/// The size of the decompression window passed to [`CompressedKernel::new`],
+/// which is the maximum distance of back-references in a deflate stream.
+pub const WINDOW_SIZE: usize = 32 * 1024;
+
+/// A gzip or zlib compressed kernel ELF file.
+///
+/// The kernel is decompressed on demand while it's loaded by
+/// [`Loader::from_source`](crate::load_kernel::Loader::from_source), so the
+/// segments are decompressed directly into the frames allocated for them and
+/// no buffer is needed for the decompressed file.
+///
+/// Decompression only moves forward. Reading bytes before the last 32 KiB
+/// that were decompressed restarts decompression from the beginning of the
+/// file. The loader reads the segments in the order of their program
+/// headers, which usually matches their order in the file.
+///
+/// The 32 KiB decompression window is provided by the caller, so that it
+/// doesn't have to be moved through the small stacks of the bootloader. It
+/// can be stored in a `static` or on the heap.
+pub struct CompressedKernel<'a> {
+    /// The deflate stream. For zlib files, this includes the zlib header.
+    stream: &'a [u8],
+    /// The flags passed to the decompressor.
+    flags: u32,
+    file_size: u64,
+    decompressor: DecompressorOxide,
+    /// The number of bytes of `stream` consumed so far.
+    stream_pos: usize,
+    /// The number of bytes decompressed so far.
+    file_pos: u64,
+    /// The last decompressed bytes, stored at their file offset modulo the
+    /// window size.
+    window: &'a mut [u8; WINDOW_SIZE],
+}
+
+impl<'a> CompressedKernel<'a> {
+    /// Prepares decompressing the given gzip or zlib file, using `window` as
+    /// the decompression window.
+    ///
+    /// zlib files don't store the size of the uncompressed file, so they are
+    /// decompressed once to determine it.
+    pub fn new(data: &'a [u8], window: &'a mut [u8; WINDOW_SIZE]) -> Result<Self, &'static str> {
+        if data.starts_with(&GZIP_MAGIC) {
+            let (stream, file_size) = parse_gzip(data)?;
+            return Ok(Self::with_stream(stream, 0, file_size, window));
+        }
+
+        let mut kernel = Self::with_stream(
+            data,
+            inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER,
+            u64::MAX,
+            window,
+        );
+        while !kernel.decompress_chunk()?.is_empty() {}
+        kernel.file_size = kernel.file_pos;
+        kernel.restart();
+        Ok(kernel)
+    }
+
+    fn with_stream(
+        stream: &'a [u8],
+        flags: u32,
+        file_size: u64,
+        window: &'a mut [u8; WINDOW_SIZE],
+    ) -> Self {
+        Self {
+            stream,
+            flags,
+            file_size,
+            decompressor: DecompressorOxide::new(),
+            stream_pos: 0,
+            file_pos: 0,
+            window,
+        }
+    }
+
+    /// Restarts decompression at the beginning of the file.
+    fn restart(&mut self) {
+        self.decompressor.init();
+        self.stream_pos = 0;
+        self.file_pos = 0;
+    }
+
+    /// Decompresses the next bytes into the window.
+    ///
+    /// Returns the range of file offsets that were decompressed, which is
+    /// empty at the end of the file.
+    fn decompress_chunk(&mut self) -> Result<Range<u64>, &'static str> {
+        let start = self.file_pos;
+        let (status, consumed, written) = decompress(
+            &mut self.decompressor,
+            &self.stream[self.stream_pos..],
+            &mut self.window[..],
+            window_offset(start),
+            self.flags,
+        );
+        self.stream_pos += consumed;
+        self.file_pos += written as u64;
+        if self.file_pos > self.file_size {
+            return Err("compressed kernel is larger than its recorded size");
+        }
+        match status {
+            TINFLStatus::Done | TINFLStatus::HasMoreOutput => Ok(start..self.file_pos),
+            TINFLStatus::NeedsMoreInput => Err("compressed kernel is truncated"),
+            _ => Err("compressed kernel is corrupted"),
+        }
+    }
+
+    /// Copies the decompressed bytes in `range` that overlap the range of
+    /// `buf` at `offset` into `buf`.
+    ///
+    /// `range` must still be in the window.
+    fn copy_from_window(&self, range: Range<u64>, offset: u64, buf: &mut [u8]) {
+        let end = cmp::min(range.end, offset + buf.len() as u64);
+        let mut pos = cmp::max(range.start, offset);
+        while pos < end {
+            // Copy up to the end of the window before wrapping around.
+            let window_offset = window_offset(pos);
+            let len = cmp::min(end - pos, (WINDOW_SIZE - window_offset) as u64) as usize;
+            buf[(pos - offset) as usize..][..len]
+                .copy_from_slice(&self.window[window_offset..][..len]);
+            pos += len as u64;
+        }
+    }
+}
+
+impl KernelSource for CompressedKernel<'_> {
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let window_start = self.file_pos.saturating_sub(WINDOW_SIZE as u64);
+        if offset < window_start {
+            self.restart();
+        }
+
+        let end = offset + buf.len() as u64;
+        let mut range = self.file_pos.saturating_sub(WINDOW_SIZE as u64)..self.file_pos;
+        loop {
+            self.copy_from_window(range.clone(), offset, buf);
+            if range.end >= end {
+                return Ok(());
+            }
+            range = self.decompress_chunk()?;
+            if range.is_empty() {
+                return Err("compressed kernel is smaller than its recorded size");
+            }
+        }
+    }
+}
+
+/// Returns the position of the given file offset in the window.
+fn window_offset(file_offset: u64) -> usize {
+    (file_offset % WINDOW_SIZE as u64) as usize
+}
+
+/// Returns the deflate stream of a gzip file and the size of the
+/// uncompressed file.
+fn parse_gzip(data: &[u8]) -> Result<(&[u8], u64), &'static str> {
+    let invalid = "invalid gzip header";
+    if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE {
+        return Err(invalid);
+    }
+    if data[2] != GZIP_METHOD_DEFLATE {
+        return Err("gzip file isn't compressed with deflate");
+    }
+    let flags = data[3];
+    let stream_end = data.len() - GZIP_TRAILER_SIZE;
+
+    // Skip the optional header fields.
+    let mut pos = GZIP_HEADER_SIZE;
+    if flags & GZIP_FEXTRA != 0 {
+        let len = data.get(pos..pos + 2).ok_or(invalid)?;
+        pos += 2 + usize::from(u16::from_le_bytes([len[0], len[1]]));
+    }
+    for field in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & field != 0 {
+            // Both fields are zero-terminated strings.
+            let len = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or(invalid)?;
+            pos += len + 1;
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > stream_end {
+        return Err(invalid);
+    }
+
+    // The size is stored modulo 2^32, which is enough for any kernel.
+    let size = &data[data.len() - 4..];
+    let file_size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+    Ok((&data[pos..stream_end], u64::from(file_size)))
+}
+
+/// Decompresses and loads a gzip or zlib compressed kernel.
+///
+/// The kernel is loaded like by [`load_kernel_from_source`], so its segments
+/// are decompressed directly into the frames allocated from
+/// `frame_allocator`. No contiguous or page-aligned buffer is needed for the
+/// decompressed file. [`LoadCallbacks::progress`] can be used to report the
+/// decompression progress.
+pub fn load_kernel_compressed(
+    kernel: &mut CompressedKernel<'_>,
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    callbacks: LoadCallbacks<'_>,
+) -> Result<LoadedKernel, LoadKernelError> {
+    load_kernel_from_source(kernel, page_table, frame_allocator, used_entries, callbacks)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{CompressedKernel, WINDOW_SIZE};
+    use crate::{
+        level_4_entries::UsedLevel4Entries,
+        load_kernel::{
+            tests::{
+                build_elf,
+                mock::{BumpAllocator, PhysicalMemory, RecordingMapper},
+                Phdr,
+            },
+            KernelSource, Loader, LoaderOptions,
+        },
+    };
+    use bootloader_api::BootloaderConfig;
+    use std::{boxed::Box, vec::Vec};
+    use x86_64::{structures::paging::Translate, PhysAddr, VirtAddr};
+    use xmas_elf::program::{FLAG_R, FLAG_X};
+
+    /// Returns a file that doesn't repeat within the window.
+    fn file(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i % 251) as u8 ^ (i / 251) as u8)
+            .collect()
+    }
+
+    /// Returns a deflate stream that stores the data in uncompressed blocks.
+    fn stored_blocks(data: &[u8]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        let mut chunks = data.chunks(0xffff).peekable();
+        while let Some(chunk) = chunks.next() {
+            stream.push(u8::from(chunks.peek().is_none()));
+            let len = chunk.len() as u16;
+            stream.extend_from_slice(&len.to_le_bytes());
+            stream.extend_from_slice(&(!len).to_le_bytes());
+            stream.extend_from_slice(chunk);
+        }
+        stream
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        // With a file name, which has to be skipped.
+        let mut file = Vec::from([0x1f, 0x8b, 8, 1 << 3, 0, 0, 0, 0, 0, 0xff]);
+        file.extend_from_slice(b"kernel\0");
+        file.extend_from_slice(&stored_blocks(data));
+        // The CRC-32 isn't checked.
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + u32::from(byte)) % 65521;
+            b = (b + a) % 65521;
+        }
+        let mut file = Vec::from([0x78, 0x01]);
+        file.extend_from_slice(&stored_blocks(data));
+        file.extend_from_slice(&(b << 16 | a).to_be_bytes());
+        file
+    }
+
+    #[test]
+    fn random_access_reads() {
+        let data = file(3 * WINDOW_SIZE + 100);
+        let mut window = Box::new([0; WINDOW_SIZE]);
+        for compressed in [gzip(&data), zlib(&data)] {
+            let mut kernel = CompressedKernel::new(&compressed, &mut window).unwrap();
+            assert_eq!(kernel.file_size(), data.len() as u64);
+
+            // Reads within the window, across the window boundary, larger
+            // than the window and before the window.
+            let reads = [
+                (0, 64),
+                (100, 200),
+                (WINDOW_SIZE - 10, 20),
+                (WINDOW_SIZE, 2 * WINDOW_SIZE + 50),
+                (3 * WINDOW_SIZE, 100),
+                (10, 10),
+            ];
+            for (offset, len) in reads {
+                let mut buf = std::vec![0; len];
+                kernel.read_at(offset as u64, &mut buf).unwrap();
+                assert_eq!(buf, data[offset..][..len], "offset {offset:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_file() {
+        let data = file(1000);
+        let mut compressed = gzip(&data);
+        // Claim that the file is larger than it is.
+        let len = compressed.len();
+        compressed[len - 4..].copy_from_slice(&2000u32.to_le_bytes());
+
+        let mut window = Box::new([0; WINDOW_SIZE]);
+        let mut kernel = CompressedKernel::new(&compressed, &mut window).unwrap();
+        let mut buf = [0; 100];
+        assert!(kernel.read_at(1500, &mut buf).is_err());
+    }
+
+    #[test]
+    fn load_gzip_kernel() {
+        let code = file(0x100);
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x100,
+            mem_size: 0x2000,
+            align: 0x1000,
+        };
+        let mut elf = build_elf(2, 0x2000, &[segment]); // executable
+        elf[0x1000..][..code.len()].copy_from_slice(&code);
+        let compressed = gzip(&elf);
+        let mut window = Box::new([0; WINDOW_SIZE]);
+        let mut kernel = CompressedKernel::new(&compressed, &mut window).unwrap();
+
+        let memory = PhysicalMemory::new(0x10_0000);
+        let mut frame_allocator = BumpAllocator::new(PhysAddr::new(0), &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+
+        let mut loader = Loader::from_source(
+            &mut kernel,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        loader.load_segments().unwrap();
+        loader.apply_relocations().unwrap();
+
+        let read = |addr: u64, len: usize| {
+            let phys = page_table.translate_addr(VirtAddr::new(addr)).unwrap();
+            memory.read(phys, len)
+        };
+        assert_eq!(read(0x20_1000, code.len()), code);
+        assert_eq!(read(0x20_1100, 0x100), [0; 0x100]);
+        assert_eq!(read(0x20_2000, 0x100), [0; 0x100]);
+    }
+}
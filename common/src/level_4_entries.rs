@@ -8,7 +8,7 @@ use rand::{
 use rand_hc::Hc128Rng;
 use usize_conversions::IntoUsize;
 use x86_64::{
-    structures::paging::{Page, PageTableIndex, Size4KiB},
+    structures::paging::{Page, PageSize, PageTableIndex, Size4KiB},
     PhysAddr, VirtAddr,
 };
 use xmas_elf::program::ProgramHeader;
@@ -17,8 +17,8 @@ use xmas_elf::program::ProgramHeader;
 ///
 /// Useful for determining a free virtual memory block, e.g. for mapping additional data.
 pub struct UsedLevel4Entries {
-    /// Whether an entry is in use by the kernel.
-    entry_state: [bool; 512],
+    /// A bitmap of the entries in use by the kernel, see [`Self::bitmap`].
+    entry_state: [u64; 8],
     /// A random number generator that should be used to generate random addresses or
     /// `None` if aslr is disabled.
     rng: Option<Hc128Rng>,
@@ -35,11 +35,11 @@ impl UsedLevel4Entries {
         config: &BootloaderConfig,
     ) -> Self {
         let mut used = UsedLevel4Entries {
-            entry_state: [false; 512],
+            entry_state: [0; 8],
             rng: config.mappings.aslr.then(entropy::build_rng),
         };
 
-        used.entry_state[0] = true; // TODO: Can we do this dynamically?
+        used.mark_p4_index_as_used(PageTableIndex::new(0)); // TODO: Can we do this dynamically?
 
         // Mark the statically configured ranges from the config as used.
 
@@ -57,7 +57,11 @@ impl UsedLevel4Entries {
         }
 
         if let config::Mapping::FixedAddress(kernel_stack_address) = config.mappings.kernel_stack {
-            used.mark_range_as_used(kernel_stack_address, config.kernel_stack_size);
+            // The fixed address is the guard page below the stack.
+            used.mark_range_as_used(
+                kernel_stack_address,
+                Size4KiB::SIZE + config.kernel_stack_size,
+            );
         }
 
         if let config::Mapping::FixedAddress(boot_info_address) = config.mappings.boot_info {
@@ -102,13 +106,34 @@ impl UsedLevel4Entries {
 
     /// Marks all p4 entries in the range `[address..address+size)` as used.
     ///
-    /// `size` can be a `u64` or `usize`.
-    fn mark_range_as_used<S>(&mut self, address: u64, size: S)
+    /// `size` can be a `u64` or `usize`. See [`Self::mark_range`].
+    pub fn mark_range_as_used<S>(&mut self, address: u64, size: S)
     where
         VirtAddr: core::ops::Add<S, Output = VirtAddr>,
     {
         let start = VirtAddr::new(address);
-        let end_inclusive = (start + size) - 1usize;
+        self.mark_range(start, (start + size) - start);
+    }
+
+    /// Marks all p4 entries spanned by the range `[start..start+size)` as used.
+    ///
+    /// This can be used to reserve additional virtual memory regions, e.g. the boot info or
+    /// the framebuffer, so that they aren't returned by [`Self::get_free_address`]. The range
+    /// may span multiple p4 entries and end at the end of the address space. Nothing is marked
+    /// if `size` is zero.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the range doesn't consist of canonical addresses.
+    pub fn mark_range(&mut self, start: VirtAddr, size: u64) {
+        if size == 0 {
+            return;
+        }
+        let end_inclusive = start
+            .as_u64()
+            .checked_add(size - 1)
+            .and_then(|end| VirtAddr::try_new(end).ok())
+            .expect("range must consist of canonical addresses");
         let start_page = Page::<Size4KiB>::containing_address(start);
         let end_page_inclusive = Page::<Size4KiB>::containing_address(end_inclusive);
 
@@ -118,8 +143,36 @@ impl UsedLevel4Entries {
         }
     }
 
+    /// Marks the p4 entry containing the given address as used.
+    pub fn mark_address_as_used(&mut self, address: VirtAddr) {
+        self.mark_p4_index_as_used(address.p4_index());
+    }
+
     fn mark_p4_index_as_used(&mut self, p4_index: PageTableIndex) {
-        self.entry_state[usize::from(p4_index)] = true;
+        let index = usize::from(p4_index);
+        self.entry_state[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Returns whether the level 4 entry with the given index is used.
+    ///
+    /// Panics if `index` is not smaller than 512.
+    pub fn is_used(&self, index: usize) -> bool {
+        is_set(&self.entry_state, index)
+    }
+
+    /// Returns the indices of all used level 4 entries in ascending order.
+    pub fn used_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..512).filter(|&index| self.is_used(index))
+    }
+
+    /// Returns the used level 4 entries as a bitmap.
+    ///
+    /// Bit `i % 64` of word `i / 64` is set if entry `i` is used. Marking an
+    /// entry is idempotent, so the bitmap only depends on the marked ranges
+    /// and not on the order in which they were marked, e.g. the order of the
+    /// program headers of the kernel.
+    pub fn bitmap(&self) -> &[u64; 8] {
+        &self.entry_state
     }
 
     /// Marks the virtual address range of all segments as used.
@@ -129,13 +182,33 @@ impl UsedLevel4Entries {
         virtual_address_offset: VirtualAddressOffset,
     ) {
         for segment in segments.filter(|s| s.mem_size() > 0) {
-            self.mark_range_as_used(
-                virtual_address_offset + segment.virtual_addr(),
+            self.mark_range(
+                VirtAddr::new(virtual_address_offset + segment.virtual_addr()),
                 segment.mem_size(),
             );
         }
     }
 
+    /// Marks the guard pages directly below and above all segments as used.
+    pub fn mark_guard_pages<'a>(
+        &mut self,
+        segments: impl Iterator<Item = ProgramHeader<'a>>,
+        virtual_address_offset: VirtualAddressOffset,
+    ) {
+        for segment in segments.filter(|s| s.mem_size() > 0) {
+            let start = VirtAddr::new(virtual_address_offset + segment.virtual_addr());
+            let end_inclusive = start + (segment.mem_size() - 1);
+            let start_page = Page::<Size4KiB>::containing_address(start);
+            let end_page = Page::<Size4KiB>::containing_address(end_inclusive);
+            if let Some(guard_page) = Step::backward_checked(start_page, 1) {
+                self.mark_p4_index_as_used(guard_page.p4_index());
+            }
+            if let Some(guard_page) = Step::forward_checked(end_page, 1) {
+                self.mark_p4_index_as_used(guard_page.p4_index());
+            }
+        }
+    }
+
     /// Returns the first index of a `num` contiguous unused level 4 entries and marks them as
     /// used. If `CONFIG.aslr` is enabled, this will return random contiguous available entries.
     ///
@@ -143,12 +216,11 @@ impl UsedLevel4Entries {
     /// to determine multiple unused virtual memory regions.
     pub fn get_free_entries(&mut self, num: u64) -> PageTableIndex {
         // Create an iterator over all available p4 indices with `num` contiguous free entries.
-        let mut free_entries = self
-            .entry_state
-            .windows(num.into_usize())
-            .enumerate()
-            .filter(|(_, entries)| entries.iter().all(|used| !used))
-            .map(|(idx, _)| idx);
+        let num = num.into_usize();
+        let entry_state = self.entry_state;
+        let mut free_entries = (0..512).filter(|&idx| {
+            idx + num <= 512 && (idx..idx + num).all(|index| !is_set(&entry_state, index))
+        });
 
         // Choose the free entry index.
         let idx_opt = if let Some(rng) = self.rng.as_mut() {
@@ -163,8 +235,8 @@ impl UsedLevel4Entries {
         };
 
         // Mark the entries as used.
-        for i in 0..num.into_usize() {
-            self.entry_state[idx + i] = true;
+        for i in 0..num {
+            self.mark_p4_index_as_used(PageTableIndex::new((idx + i) as u16));
         }
 
         PageTableIndex::new(idx.try_into().unwrap())
@@ -197,4 +269,20 @@ impl UsedLevel4Entries {
 
         base + offset
     }
+
+    /// Returns a virtual address in one or more unused level 4 entries, preceded by an unmapped
+    /// guard page, and marks them as used.
+    ///
+    /// This is used for the kernel stack, so that a stack overflow causes a page fault instead of
+    /// overwriting other memory. The guard page is part of the reserved range, so it won't be
+    /// reused for later allocations.
+    pub fn get_free_address_with_guard_page(&mut self, size: u64, alignment: u64) -> VirtAddr {
+        let guard_size = alignment.max(Size4KiB::SIZE);
+        self.get_free_address(guard_size + size, alignment) + guard_size
+    }
+}
+
+/// Returns whether the bit for the given level 4 entry is set in the bitmap.
+fn is_set(bitmap: &[u64; 8], index: usize) -> bool {
+    bitmap[index / 64] & (1 << (index % 64)) != 0
 }
@@ -11,6 +11,7 @@ use bootloader_api::{
 use bootloader_boot_config::{BootConfig, LevelFilter};
 use core::{alloc::Layout, arch::asm, mem::MaybeUninit, slice};
 use level_4_entries::UsedLevel4Entries;
+use load_kernel::{Loader, LoaderOptions, PhysicalFootprint};
 use usize_conversions::FromUsize;
 use x86_64::{
     structures::paging::{
@@ -21,6 +22,9 @@ use x86_64::{
 };
 use xmas_elf::ElfFile;
 
+/// Provides a kernel source that decompresses gzip or zlib compressed kernels.
+#[cfg(feature = "compressed-kernel")]
+pub mod compressed_kernel;
 /// Provides a function to gather entropy and build a RNG.
 mod entropy;
 /// Provides a type that logs output as text to pixel-based framebuffers.
@@ -36,6 +40,8 @@ pub mod load_kernel;
 pub mod logger;
 /// Provides a type that logs output as text to a Serial Being port.
 pub mod serial;
+/// Provides a page table adapter for loading the kernel with 4 KiB pages only.
+pub mod small_pages;
 
 const PAGE_SIZE: u64 = 4096;
 
@@ -198,39 +204,34 @@ where
     let kernel_slice_start = PhysAddr::new(kernel.start_address as _);
     let kernel_slice_len = u64::try_from(kernel.len).unwrap();
 
-    let (kernel_image_offset, entry_point, tls_template) = load_kernel::load_kernel(
+    let mut loader = Loader::new(
         kernel,
         kernel_page_table,
         frame_allocator,
         &mut used_entries,
+        LoaderOptions::new_default(),
     )
-    .expect("no entry point");
+    .expect("failed to parse kernel");
+    let tls_template = loader.load_segments().expect("failed to load kernel");
+    loader
+        .apply_relocations()
+        .expect("failed to apply kernel relocations");
+    let entry_point = loader.entry_point();
     log::info!("Entry point at: {:#x}", entry_point.as_u64());
+
     // create a stack
-    let stack_start = {
-        // we need page-alignment because we want a guard page directly below the stack
-        let guard_page = mapping_addr_page_aligned(
-            config.mappings.kernel_stack,
-            // allocate an additional page as a guard page
-            Size4KiB::SIZE + config.kernel_stack_size,
-            &mut used_entries,
-            "kernel stack start",
-        );
-        guard_page + 1
+    let stack_start = match config.mappings.kernel_stack {
+        // the fixed address is the guard page directly below the stack
+        Mapping::FixedAddress(addr) => Some(VirtAddr::new(addr) + Size4KiB::SIZE),
+        Mapping::Dynamic => None,
     };
-    let stack_end_addr = stack_start.start_address() + config.kernel_stack_size;
+    let stack_end_addr = loader
+        .map_kernel_stack(config.kernel_stack_size, stack_start)
+        .expect("failed to map kernel stack");
 
-    let stack_end = Page::containing_address(stack_end_addr - 1u64);
-    for page in Page::range_inclusive(stack_start, stack_end) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .expect("frame allocation failed when mapping a kernel stack");
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
-            Ok(tlb) => tlb.flush(),
-            Err(err) => panic!("failed to map page {:?}: {:?}", page, err),
-        }
-    }
+    let kernel_image_offset =
+        VirtAddr::new(loader.virtual_address_offset().virtual_address_offset() as u64);
+    let kernel_footprint = *loader.physical_footprint();
 
     // identity-map context switch function, so that we don't get an immediate pagefault
     // after switching the active page table
@@ -403,6 +404,7 @@ where
         kernel_slice_start,
         kernel_slice_len,
         kernel_image_offset,
+        kernel_footprint,
 
         ramdisk_slice_start,
         ramdisk_slice_len,
@@ -433,6 +435,8 @@ pub struct Mappings {
     pub kernel_slice_len: u64,
     /// Relocation offset of the kernel image in virtual memory.
     pub kernel_image_offset: VirtAddr,
+    /// The physical frames used by the loaded kernel.
+    pub kernel_footprint: PhysicalFootprint,
     pub ramdisk_slice_start: Option<VirtAddr>,
     pub ramdisk_slice_len: u64,
 }
@@ -1,20 +1,33 @@
 use crate::{level_4_entries::UsedLevel4Entries, PAGE_SIZE};
 use bootloader_api::info::TlsTemplate;
-use core::{cmp, iter::Step, mem::size_of, ops::Add};
+use core::{
+    cmp, fmt,
+    iter::Step,
+    mem::size_of,
+    ops::{Add, Range, RangeInclusive},
+};
 
 use x86_64::{
-    align_up,
+    align_down, align_up,
     structures::paging::{
+        frame::PhysFrameRange,
         mapper::{MappedFrame, MapperAllSizes, TranslateResult},
-        FrameAllocator, Page, PageSize, PageTableFlags as Flags, PhysFrame, Size4KiB, Translate,
+        page::PageRangeInclusive,
+        FrameAllocator, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags as Flags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 use xmas_elf::{
-    dynamic, header,
-    program::{self, ProgramHeader, SegmentData, Type},
-    sections::Rela,
-    ElfFile,
+    dynamic::{self, Dynamic},
+    header,
+    program::{self, ProgramHeader, Type},
+    sections::{
+        self, Rel, Rela, SectionData, SectionHeader, SectionHeader_, ShType, SHF_ALLOC,
+        SHF_EXECINSTR, SHF_WRITE, SHN_ABS, SHN_UNDEF,
+    },
+    symbol_table::{self, DynEntry64, Entry},
+    ElfFile, P64,
 };
 
 use super::Kernel;
@@ -22,16 +35,420 @@ use super::Kernel;
 /// Used by [`Inner::make_mut`] and [`Inner::clean_copied_flag`].
 const COPIED: Flags = Flags::BIT_9;
 
-struct Loader<'a, M, F> {
+/// The number of 4 KiB frames in a 2 MiB frame.
+const HUGE_PAGE_FRAMES: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+/// The index of the OS/ABI identification in the ELF header.
+const EI_OSABI: usize = 7;
+/// The index of the ABI version in the ELF header.
+const EI_ABIVERSION: usize = 8;
+
+/// The value of the `PltRel` entry for `Rela` relocations.
+const DT_RELA: u64 = 7;
+/// The value of the `PltRel` entry for `Rel` relocations.
+const DT_REL: u64 = 17;
+/// The tag of the `RelrSize` entry. Not supported by `xmas_elf`.
+const DT_RELRSZ: u64 = 35;
+/// The tag of the `Relr` entry. Not supported by `xmas_elf`.
+const DT_RELR: u64 = 36;
+/// The tag of the `RelrEnt` entry. Not supported by `xmas_elf`.
+const DT_RELRENT: u64 = 37;
+/// The number of relocation targets that [`Loader::verify_segments`] collects
+/// per pass over the relocation tables.
+const VERIFY_RELOCATION_BATCH: usize = 256;
+/// The tag of the `RelaCount` entry, the number of `R_AMD64_RELATIVE`
+/// relocations at the start of the `Rela` table.
+const DT_RELACOUNT: u64 = 0x6fff_fff9;
+/// The type of the `GNU_STACK` program header. Not supported by `xmas_elf`.
+const PT_GNU_STACK: u32 = 0x6474_e551;
+/// The type of the `GNU_PROPERTY` program header. Not supported by `xmas_elf`.
+const PT_GNU_PROPERTY: u32 = 0x6474_e553;
+/// The note type of the `.note.gnu.property` note.
+const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+/// The property type of the x86 features that all input objects support.
+const GNU_PROPERTY_X86_FEATURE_1_AND: u32 = 0xc000_0002;
+
+/// Loads a kernel ELF file into a page table.
+///
+/// [`load_kernel`] is a convenience function that performs all loading steps
+/// at once. Use this type directly to run additional checks between the
+/// individual steps.
+pub struct Loader<'a, M, F> {
     elf_file: ElfFile<'a>,
+    layout: KernelLayout<'a>,
+    /// The source the segments are read from if the kernel isn't fully
+    /// loaded into memory, see [`Loader::from_source`].
+    source: Option<&'a mut dyn KernelSource>,
     inner: Inner<'a, M, F>,
+    used_entries: &'a mut UsedLevel4Entries,
+    entry_point: VirtAddr,
+    x86_features: X86Features,
+}
+
+/// A kernel ELF file that is read on demand, e.g. from a disk.
+///
+/// Used by [`Loader::from_source`] to load a kernel without reading the whole
+/// file into memory first.
+pub trait KernelSource {
+    /// Returns the size of the ELF file in bytes.
+    fn file_size(&self) -> u64;
+
+    /// Fills `buf` with the bytes of the ELF file starting at `offset`.
+    ///
+    /// The requested range is always within the file.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+}
+
+/// A [`KernelSource`] for an ELF file that is completely in memory, see
+/// [`LoaderOptions::copy_segments`].
+struct SliceSource<'a>(&'a [u8]);
+
+impl KernelSource for SliceSource<'_> {
+    fn file_size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        buf.copy_from_slice(&self.0[offset as usize..][..buf.len()]);
+        Ok(())
+    }
+}
+
+/// A frame allocator that calls a closure to allocate frames.
+///
+/// Allows passing a simple early allocator to the loader without
+/// implementing [`FrameAllocator`] for a dedicated type.
+pub struct FnFrameAllocator<A>(A);
+
+impl<A> FnFrameAllocator<A>
+where
+    A: FnMut() -> Option<PhysFrame>,
+{
+    /// Creates a frame allocator that returns the frames returned by
+    /// `allocate`. Returning `None` makes loading fail with
+    /// [`LoadKernelError::OutOfFrames`].
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that `allocate` only returns unused frames,
+    /// see [`FrameAllocator`].
+    pub unsafe fn new(allocate: A) -> Self {
+        Self(allocate)
+    }
+}
+
+unsafe impl<A> FrameAllocator<Size4KiB> for FnFrameAllocator<A>
+where
+    A: FnMut() -> Option<PhysFrame>,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        (self.0)()
+    }
+}
+
+/// Allows configuring the behavior of the [`Loader`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct LoaderOptions {
+    /// Whether Load segments that are both writable and executable should be
+    /// rejected.
+    ///
+    /// Such segments are usually caused by a misconfigured linker script.
+    pub enforce_write_xor_execute: bool,
+    /// An additional offset that is added to the base address of position
+    /// independent kernels, e.g. for address space layout randomization.
+    ///
+    /// Must be a multiple of 2 MiB, so that the kernel can still be mapped
+    /// using huge pages. Ignored for kernels that aren't position
+    /// independent.
+    pub kaslr_offset: u64,
+    /// Whether unmapped guard pages should be kept around the Load segments.
+    ///
+    /// Segments are mapped page by page, so the pages between two segments
+    /// are never mapped, unless the pages of the segments directly follow
+    /// each other and form a single region without a guard page. This option
+    /// marks the pages directly below and above each segment in the
+    /// [`UsedLevel4Entries`], so that no other memory is mapped there, and
+    /// reserves room for them around position independent kernels.
+    pub guard_pages: bool,
+    /// Whether non-executable memory should be mapped without the
+    /// `NO_EXECUTE` flag.
+    ///
+    /// Setting the flag causes a page fault on CPUs that don't support it or
+    /// haven't enabled it in the `EFER` register.
+    pub disable_no_execute: bool,
+    /// The virtual address at which physical memory is mapped in the current
+    /// address space.
+    ///
+    /// The loader accesses the ELF file and newly allocated frames through
+    /// this mapping. Use 0 if physical memory is identity-mapped.
+    pub physical_memory_offset: u64,
+    /// The virtual address at which position independent kernels are
+    /// loaded.
+    ///
+    /// Must be page aligned. If `None`, a free virtual address range is
+    /// chosen. Ignored for kernels that aren't position independent.
+    pub base_address: Option<u64>,
+    /// An exclusive upper limit for the physical addresses of the kernel.
+    ///
+    /// All frames of the ELF file that are mapped and all frames allocated
+    /// for the kernel must end at or below this address, e.g. because of DMA
+    /// constraints. If `None`, the physical addresses aren't limited.
+    pub max_physical_address: Option<u64>,
+    /// Whether [`Loader::apply_relocations`] should verify the loaded
+    /// segments using [`Loader::verify_segments`].
+    ///
+    /// This is expensive and only meant for debugging.
+    pub verify_segments: bool,
+    /// Whether the frame allocator only returns frames that are already
+    /// zeroed.
+    ///
+    /// If set, newly allocated frames aren't zeroed again, which speeds up
+    /// loading kernels with large bss sections. Frames copied from the ELF
+    /// file are still partially zeroed as needed.
+    pub frames_are_zeroed: bool,
+    /// Whether the memory of the kernel should be mapped with the `GLOBAL`
+    /// flag.
+    ///
+    /// Global pages stay in the TLB when switching address spaces, which is
+    /// useful because the kernel is mapped in all of them. The flag is only
+    /// honored if `CR4.PGE` is enabled. If not set, no page of the kernel is
+    /// mapped with the `GLOBAL` flag, as required for per-address-space
+    /// kernel mappings, e.g. with KPTI.
+    pub global_pages: bool,
+    /// Whether position independent kernels should be loaded at the virtual
+    /// addresses that equal the physical addresses of their segments in the
+    /// ELF file.
+    ///
+    /// This is meant for debugging early boot code. Pages that are modified
+    /// while loading, e.g. by relocations or because they contain bss
+    /// memory, are still mapped to new frames. Takes precedence over
+    /// `base_address` and `kaslr_offset`. Not supported for kernels loaded
+    /// from a [`KernelSource`].
+    pub identity_map: bool,
+    /// Whether the relocations of position independent kernels must target
+    /// writable Load segments or `PT_GNU_RELRO` segments.
+    ///
+    /// Relocations in read-only memory are applied anyway because the loader
+    /// writes to the frames directly, but they usually indicate a broken
+    /// linker script.
+    pub check_relocation_targets: bool,
+    /// Whether [`Loader::apply_relocations`] should check the mapped pages
+    /// using [`Loader::touch_pages`].
+    ///
+    /// This is only meant for debugging frame allocators and page tables.
+    pub touch_pages: bool,
+    /// Whether the file contents of the Load segments should be copied to
+    /// newly allocated frames instead of mapping the frames of the ELF file.
+    ///
+    /// The ELF file isn't part of the loaded kernel then, so its memory can
+    /// be reused after loading. Kernels loaded from a [`KernelSource`] are
+    /// always copied.
+    pub copy_segments: bool,
+    /// The OS/ABI identification (`EI_OSABI`) the kernel ELF file must have.
+    ///
+    /// If `None`, any OS/ABI is accepted, see [`Loader::os_abi`].
+    pub expected_os_abi: Option<u8>,
+    /// The ABI version (`EI_ABIVERSION`) the kernel ELF file must have.
+    ///
+    /// If `None`, any ABI version is accepted, see [`Loader::abi_version`].
+    pub expected_abi_version: Option<u8>,
+    /// Whether zeroed memory at the end of Load segments should be mapped
+    /// with 2 MiB pages where possible.
+    ///
+    /// Frames are still allocated one at a time. A 2 MiB page is used for
+    /// each 2 MiB aligned part of the zeroed memory for which the frame
+    /// allocator returns 512 consecutive frames starting at a 2 MiB aligned
+    /// frame, which is common for bump allocators. Otherwise, the frames are
+    /// mapped with 4 KiB pages.
+    pub huge_bss_pages: bool,
+    /// Resolves `R_AMD64_IRELATIVE` relocations, which are emitted for
+    /// indirect functions (ifuncs).
+    ///
+    /// The function is called with the relocated address of the resolver
+    /// function of each relocation and returns the value that is written to
+    /// the relocation target. It usually calls the resolver, which requires
+    /// the kernel to be mapped in the current address space. If `None`,
+    /// kernels containing such relocations are rejected.
+    pub ifunc_resolver: Option<fn(u64) -> u64>,
+    /// The maximum number of relocations the kernel may contain.
+    ///
+    /// Bounds the time spent on relocating an untrusted kernel. The number
+    /// of entries of each relocation table is checked before it's applied,
+    /// so a kernel exceeding the limit is rejected before most of its
+    /// relocations are processed. Entries of `Relr` tables are counted as
+    /// one relocation each. If `None`, the number of relocations is not
+    /// limited.
+    pub max_relocations: Option<u64>,
+    /// The entry point to use instead of the one declared by the kernel,
+    /// e.g. a trampoline for chain-loading.
+    ///
+    /// Like the `e_entry` field of the ELF header, the address is given as
+    /// it was linked, so the virtual address offset is added to it. For
+    /// relocatable object files, it is the offset in the loaded kernel. The
+    /// address must be part of an executable segment or section, and is
+    /// returned by [`Loader::entry_point`]. If `None`, the declared entry
+    /// point is used.
+    pub entry_point: Option<u64>,
+    /// Whether the kernel pages are guaranteed to be mapped with the
+    /// `ACCESSED` and `DIRTY` flags cleared, e.g. for a kernel that tracks
+    /// its working set from the first instruction.
+    ///
+    /// The loader writes to kernel memory through the physical memory
+    /// mapping, so it never causes the CPU to set these flags. If set, the
+    /// flags are also removed from the flags returned by the `map_flags`
+    /// callback of [`LoadCallbacks`], and
+    /// [`Loader::apply_relocations`] checks that no page of the kernel has
+    /// them set.
+    pub clear_accessed_dirty: bool,
+}
+
+impl LoaderOptions {
+    /// Creates new default options with the following values:
+    ///
+    /// - `enforce_write_xor_execute`: false
+    /// - `kaslr_offset`: 0
+    /// - `guard_pages`: false
+    /// - `disable_no_execute`: false
+    /// - `physical_memory_offset`: 0
+    /// - `base_address`: None
+    /// - `max_physical_address`: None
+    /// - `verify_segments`: false
+    /// - `frames_are_zeroed`: false
+    /// - `global_pages`: false
+    /// - `identity_map`: false
+    /// - `check_relocation_targets`: false
+    /// - `touch_pages`: false
+    /// - `copy_segments`: false
+    /// - `expected_os_abi`: None
+    /// - `expected_abi_version`: None
+    /// - `huge_bss_pages`: false
+    /// - `ifunc_resolver`: None
+    /// - `max_relocations`: None
+    /// - `entry_point`: None
+    /// - `clear_accessed_dirty`: false
+    pub const fn new_default() -> Self {
+        Self {
+            enforce_write_xor_execute: false,
+            kaslr_offset: 0,
+            guard_pages: false,
+            disable_no_execute: false,
+            physical_memory_offset: 0,
+            base_address: None,
+            max_physical_address: None,
+            verify_segments: false,
+            frames_are_zeroed: false,
+            global_pages: false,
+            identity_map: false,
+            check_relocation_targets: false,
+            touch_pages: false,
+            copy_segments: false,
+            expected_os_abi: None,
+            expected_abi_version: None,
+            huge_bss_pages: false,
+            ifunc_resolver: None,
+            max_relocations: None,
+            entry_point: None,
+            clear_accessed_dirty: false,
+        }
+    }
+}
+
+impl Default for LoaderOptions {
+    fn default() -> Self {
+        Self::new_default()
+    }
+}
+
+/// Optional callbacks for [`Loader::load_segments_with`].
+///
+/// All callbacks are `None` by default.
+#[derive(Default)]
+pub struct LoadCallbacks<'c> {
+    /// Called for every program header before any of them is handled.
+    ///
+    /// Receives the program header and the virtual address range it will be
+    /// loaded to. The range is `None` if the addresses of the header can't
+    /// be offset into the address space, which can happen for headers that
+    /// don't describe memory. Returning an error aborts loading.
+    pub inspect: Option<&'c mut InspectFn<'c>>,
+    /// Returns the page table flags to map a Load segment with, e.g.
+    /// [`range_flags`].
+    ///
+    /// Receives the program header and the flags the segment would be mapped
+    /// with otherwise. The returned flags also apply to the zeroed memory at
+    /// the end of the segment. `PRESENT` is always set, and the pages of a
+    /// `PT_GNU_RELRO` segment are still made read-only after relocation.
+    pub map_flags: Option<&'c mut MapFlagsFn<'c>>,
+    /// Called after each Load segment is mapped, e.g. to draw a progress bar
+    /// while loading from a slow [`KernelSource`].
+    ///
+    /// Receives the number of bytes loaded so far and the total number of
+    /// bytes, which are the sums of the memory sizes of the Load segments (or
+    /// allocated sections of relocatable object files).
+    pub progress: Option<&'c mut dyn FnMut(u64, u64)>,
 }
 
+/// See [`LoadCallbacks::inspect`].
+type InspectFn<'c> =
+    dyn FnMut(&ProgramHeader, Option<Range<VirtAddr>>) -> Result<(), LoadKernelError> + 'c;
+/// See [`LoadCallbacks::map_flags`].
+type MapFlagsFn<'c> = dyn FnMut(&ProgramHeader, Flags) -> Flags + 'c;
+
 struct Inner<'a, M, F> {
     kernel_offset: PhysAddr,
+    /// The frames containing the ELF file.
+    kernel_frames: PhysFrameRange,
     virtual_address_offset: VirtualAddressOffset,
     page_table: &'a mut M,
     frame_allocator: &'a mut F,
+    /// Whether non-executable memory is mapped with the `NO_EXECUTE` flag.
+    no_execute: bool,
+    /// See [`LoaderOptions::physical_memory_offset`].
+    physical_memory_offset: u64,
+    /// See [`LoaderOptions::max_physical_address`].
+    max_physical_address: Option<u64>,
+    /// See [`LoaderOptions::verify_segments`].
+    verify_segments: bool,
+    /// See [`LoaderOptions::frames_are_zeroed`].
+    frames_are_zeroed: bool,
+    /// See [`LoaderOptions::global_pages`].
+    global_pages: bool,
+    /// See [`LoaderOptions::check_relocation_targets`].
+    check_relocation_targets: bool,
+    /// The virtual address range of the `PT_GNU_RELRO` segment, which may be
+    /// relocated even if its Load segment isn't writable.
+    relro: Option<Range<u64>>,
+    /// See [`LoaderOptions::touch_pages`].
+    touch_pages: bool,
+    /// See [`LoaderOptions::copy_segments`].
+    copy_segments: bool,
+    /// See [`LoaderOptions::huge_bss_pages`].
+    huge_bss_pages: bool,
+    /// See [`LoaderOptions::ifunc_resolver`].
+    ifunc_resolver: Option<fn(u64) -> u64>,
+    /// See [`LoaderOptions::max_relocations`].
+    max_relocations: Option<u64>,
+    /// See [`LoaderOptions::clear_accessed_dirty`].
+    clear_accessed_dirty: bool,
+    /// Whether the kernel is relocated in place, see
+    /// [`Loader::load_segments_in_place`].
+    in_place: bool,
+    /// The physical frames used by the kernel so far.
+    footprint: PhysicalFootprint,
+    /// The number of frames allocated for bss memory so far.
+    bss_frames: u64,
+    /// The number of relocations applied so far.
+    relocations: u64,
+    /// The TLS template, which is needed for TLS relocations.
+    tls: Option<TlsTemplate>,
+    /// The page that was last made mutable by [`Inner::make_mut`] and its
+    /// new frame.
+    ///
+    /// Relocations are usually sorted by their offset, so consecutive
+    /// relocations mostly target the same page. Remembering it saves two
+    /// page table walks per relocation.
+    last_mut_page: Option<(Page, PhysFrame)>,
 }
 
 impl<'a, M, F> Loader<'a, M, F>
@@ -39,32 +456,298 @@ where
     M: MapperAllSizes + Translate,
     F: FrameAllocator<Size4KiB>,
 {
-    fn new(
+    /// Parses and checks the kernel ELF file and determines the virtual
+    /// address range it will be loaded to.
+    ///
+    /// The level 4 entries used by the kernel are marked as used in
+    /// `used_entries`. No memory is mapped yet.
+    pub fn new(
         kernel: Kernel<'a>,
         page_table: &'a mut M,
         frame_allocator: &'a mut F,
-        used_entries: &mut UsedLevel4Entries,
-    ) -> Result<Self, &'static str> {
+        used_entries: &'a mut UsedLevel4Entries,
+        options: LoaderOptions,
+    ) -> Result<Self, LoadKernelError> {
         log::info!("Elf file loaded at {:#p}", kernel.elf.input);
-        let kernel_offset = PhysAddr::new(&kernel.elf.input[0] as *const u8 as u64);
+        let kernel_offset = elf_phys_addr(kernel.elf.input, &options)?;
         if !kernel_offset.is_aligned(PAGE_SIZE) {
-            return Err("Loaded kernel ELF file is not sufficiently aligned");
+            return Err(LoadKernelError::MisalignedElf);
+        }
+
+        Self::from_elf(
+            kernel.elf,
+            kernel_offset,
+            None,
+            page_table,
+            frame_allocator,
+            used_entries,
+            options,
+        )
+    }
+
+    /// Like [`Loader::new`], but accepts an ELF file at any alignment, e.g.
+    /// as provided by firmware.
+    ///
+    /// Unless `bytes` is page aligned already, the ELF file is first copied
+    /// into newly allocated frames, which then take the place of the
+    /// original file. The frame allocator must return consecutive frames for
+    /// the copy, because the ELF file must be contiguous in physical memory.
+    pub fn from_unaligned(
+        bytes: &'a [u8],
+        page_table: &'a mut M,
+        frame_allocator: &'a mut F,
+        used_entries: &'a mut UsedLevel4Entries,
+        options: LoaderOptions,
+    ) -> Result<Self, LoadKernelError> {
+        let (elf_file, kernel_offset) = match elf_phys_addr(bytes, &options) {
+            Ok(addr) if addr.is_aligned(PAGE_SIZE) => (ElfFile::new(bytes)?, addr),
+            _ => {
+                let first_frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(LoadKernelError::OutOfFrames)?;
+                let frame_count = (bytes.len() as u64).div_ceil(Size4KiB::SIZE);
+                for i in 1..frame_count {
+                    let frame = frame_allocator
+                        .allocate_frame()
+                        .ok_or(LoadKernelError::OutOfFrames)?;
+                    if frame != first_frame + i {
+                        return Err(LoadKernelError::NonConsecutiveFrames(frame));
+                    }
+                }
+
+                let kernel_offset = first_frame.start_address();
+                // SAFETY: The frames were just allocated, so nothing else
+                // uses them, and they are accessible through the physical
+                // memory mapping.
+                let staged = unsafe {
+                    let ptr = phys_to_ptr(options.physical_memory_offset, kernel_offset);
+                    ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+                    core::slice::from_raw_parts(ptr, bytes.len())
+                };
+                log::info!("Copied unaligned ELF file to {kernel_offset:#x}");
+                (ElfFile::new(staged)?, kernel_offset)
+            }
+        };
+
+        Self::from_elf(
+            elf_file,
+            kernel_offset,
+            None,
+            page_table,
+            frame_allocator,
+            used_entries,
+            options,
+        )
+    }
+
+    /// Like [`Loader::new`], but reads the kernel from a [`KernelSource`]
+    /// instead of requiring the whole ELF file in memory.
+    ///
+    /// The ELF header and the program headers are read into a newly
+    /// allocated frame, so they must fit into a single page. The Load
+    /// segments are read into newly allocated frames by
+    /// [`Loader::load_segments`], which only reads the file bytes of each
+    /// segment. The relocation tables are read from the loaded segments.
+    ///
+    /// The section headers are never read, so relocatable object files are
+    /// not supported.
+    pub fn from_source(
+        source: &'a mut dyn KernelSource,
+        page_table: &'a mut M,
+        frame_allocator: &'a mut F,
+        used_entries: &'a mut UsedLevel4Entries,
+        options: LoaderOptions,
+    ) -> Result<Self, LoadKernelError> {
+        const HEADER_SIZE: usize =
+            size_of::<header::HeaderPt1>() + size_of::<header::HeaderPt2_<u64>>();
+
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(LoadKernelError::OutOfFrames)?;
+        let kernel_offset = frame.start_address();
+        // SAFETY: The frame was just allocated, so nothing else uses it, and
+        // it is accessible through the physical memory mapping.
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(
+                phys_to_ptr(options.physical_memory_offset, kernel_offset),
+                Size4KiB::SIZE as usize,
+            )
+        };
+
+        if source.file_size() < HEADER_SIZE as u64 {
+            return Err("file is too small to contain an ELF header".into());
+        }
+        source
+            .read_at(0, &mut buf[..HEADER_SIZE])
+            .map_err(LoadKernelError::ReadFailed)?;
+        let header = ElfFile::new(&buf[..HEADER_SIZE])?.header;
+        let class = header.pt1.class();
+        if class != header::Class::SixtyFour {
+            return Err(LoadKernelError::UnsupportedClass(class));
+        }
+        let data = header.pt1.data();
+        if data != header::Data::LittleEndian {
+            return Err(LoadKernelError::UnsupportedDataEncoding(data));
+        }
+        let headers_end = u64::from(header.pt2.ph_entry_size())
+            .checked_mul(u64::from(header.pt2.ph_count()))
+            .and_then(|size| size.checked_add(header.pt2.ph_offset()))
+            .filter(|&end| end <= Size4KiB::SIZE)
+            .ok_or(LoadKernelError::InvalidElf(
+                "program headers don't fit into a single page",
+            ))?;
+        let headers_end = cmp::max(headers_end as usize, HEADER_SIZE);
+        if headers_end as u64 > source.file_size() {
+            return Err("program header table out of range".into());
+        }
+        source
+            .read_at(HEADER_SIZE as u64, &mut buf[HEADER_SIZE..headers_end])
+            .map_err(LoadKernelError::ReadFailed)?;
+
+        // The section headers aren't read, so we clear `e_shoff`,
+        // `e_shentsize`, `e_shnum` and `e_shstrndx` to keep `xmas_elf` from
+        // accessing them.
+        buf[0x28..0x30].fill(0);
+        buf[0x3a..0x40].fill(0);
+
+        let elf_file = ElfFile::new(&buf[..headers_end])?;
+        if is_relocatable(&elf_file) {
+            return Err("relocatable kernels can't be loaded from a `KernelSource`".into());
+        }
+
+        Self::from_elf(
+            elf_file,
+            kernel_offset,
+            Some(source),
+            page_table,
+            frame_allocator,
+            used_entries,
+            options,
+        )
+    }
+
+    fn from_elf(
+        elf_file: ElfFile<'a>,
+        kernel_offset: PhysAddr,
+        mut source: Option<&'a mut dyn KernelSource>,
+        page_table: &'a mut M,
+        frame_allocator: &'a mut F,
+        used_entries: &'a mut UsedLevel4Entries,
+        options: LoaderOptions,
+    ) -> Result<Self, LoadKernelError> {
+        check_program_header_table(&elf_file)?;
+        // Only the sections of relocatable object files are loaded.
+        if is_relocatable(&elf_file) {
+            check_section_header_table(&elf_file)?;
+        }
+        header::sanity_check(&elf_file)?;
+
+        // We only support 64-bit x86_64 kernels. The 64-bit class is also
+        // required to interpret the program headers and relocations correctly.
+        let class = elf_file.header.pt1.class();
+        if class != header::Class::SixtyFour {
+            return Err(LoadKernelError::UnsupportedClass(class));
+        }
+        // All header fields, program headers and relocations are read in
+        // the native byte order.
+        let data = elf_file.header.pt1.data();
+        if data != header::Data::LittleEndian {
+            return Err(LoadKernelError::UnsupportedDataEncoding(data));
+        }
+        // `xmas_elf` reads `e_phentsize` bytes as a whole program header.
+        let ph_entry_size = elf_file.header.pt2.ph_entry_size();
+        if elf_file.header.pt2.ph_count() > 0
+            && usize::from(ph_entry_size) != size_of::<program::ProgramHeader64>()
+        {
+            return Err(LoadKernelError::UnsupportedProgramHeaderSize(ph_entry_size));
+        }
+        let machine = elf_file.header.pt2.machine().as_machine();
+        if machine != header::Machine::X86_64 {
+            return Err(LoadKernelError::UnsupportedMachine(machine));
+        }
+        let (os_abi, abi_version) = elf_abi(&elf_file);
+        if options
+            .expected_os_abi
+            .is_some_and(|expected| expected != os_abi)
+            || options
+                .expected_abi_version
+                .is_some_and(|expected| expected != abi_version)
+        {
+            return Err(LoadKernelError::UnexpectedAbi {
+                os_abi,
+                abi_version,
+            });
         }
 
-        let elf_file = kernel.elf;
+        let file_len = source
+            .as_ref()
+            .map_or(elf_file.input.len() as u64, |source| source.file_size());
+        let mut layout = KernelLayout::new();
         for program_header in elf_file.program_iter() {
-            program::sanity_check(program_header, &elf_file)?;
+            check_segment_bounds(program_header, file_len)?;
+            if source.is_some() {
+                check_streamed_program_header(program_header, &elf_file)?;
+            } else {
+                program::sanity_check(program_header, &elf_file)?;
+            }
+
+            // We can't provide a dynamic interpreter, so kernels that request
+            // one would crash in obscure ways.
+            if program_header.get_type()? == Type::Interp {
+                let path = interpreter_path(program_header, &elf_file, source.as_deref_mut())?;
+                return Err(LoadKernelError::DynamicInterpreter(path));
+            }
+
+            layout.add(program_header)?;
+
+            if options.enforce_write_xor_execute
+                && program_header.get_type()? == Type::Load
+                && program_header.flags().is_write()
+                && program_header.flags().is_execute()
+            {
+                return Err(LoadKernelError::WritableAndExecutable(
+                    program_header.virtual_addr(),
+                ));
+            }
         }
 
+        check_overlapping_segments(&elf_file)?;
+
+        let mut x86_features = match layout.gnu_property {
+            Some(program_header) => {
+                gnu_property_features(program_header, &elf_file, source.as_deref_mut())?
+            }
+            None => X86Features::default(),
+        };
+
         let virtual_address_offset = match elf_file.header.pt2.type_().as_type() {
-            header::Type::None => unimplemented!(),
-            header::Type::Relocatable => unimplemented!(),
+            header::Type::None => return Err("ELF file has no type".into()),
+            header::Type::Relocatable => {
+                for section in elf_file.section_iter() {
+                    sections::sanity_check(section, &elf_file)?;
+                }
+
+                // Relocatable object files don't contain program headers, so
+                // the properties are read from their section instead.
+                if let Some(section) = elf_file.find_section_by_name(".note.gnu.property") {
+                    let data = slice_at(&elf_file, section.offset(), section.size())?;
+                    x86_features = parse_gnu_property_note(data)?;
+                }
+
+                // Relocatable object files don't contain program headers, so
+                // we lay out their allocated sections ourselves.
+                let (size, align) = relocatable_layout(&elf_file)?;
+                let offset = reserve_address_range(used_entries, size, align, &options)?;
+                VirtualAddressOffset::new(i128::from(offset))
+            }
+            // Executables are not position independent, so they are loaded
+            // exactly at the virtual addresses given in their program headers.
+            // With an offset of zero, all segment mappings, the entry point,
+            // the TLS template and any relocations use the link addresses.
             header::Type::Executable => VirtualAddressOffset::zero(),
             header::Type::SharedObject => {
                 // Find the highest virtual memory address and the biggest alignment.
-                let load_program_headers = elf_file
-                    .program_iter()
-                    .filter(|h| matches!(h.get_type(), Ok(Type::Load)));
+                let load_program_headers = load_segments(&elf_file);
                 let max_addr = load_program_headers
                     .clone()
                     .map(|h| h.virtual_addr() + h.mem_size())
@@ -78,165 +761,1046 @@ where
                 let size = max_addr - min_addr;
                 let align = load_program_headers.map(|h| h.align()).max().unwrap_or(1);
 
-                let offset = used_entries.get_free_address(size, align).as_u64();
-                VirtualAddressOffset::new(i128::from(offset) - i128::from(min_addr))
+                if options.identity_map {
+                    // The segments aren't stored contiguously in memory.
+                    if source.is_some() {
+                        return Err("streamed kernels can't be identity-mapped".into());
+                    }
+                    identity_offset(&elf_file, kernel_offset)?
+                } else {
+                    let offset = reserve_address_range(used_entries, size, align, &options)?;
+                    VirtualAddressOffset::new(i128::from(offset) - i128::from(min_addr))
+                }
+            }
+            header::Type::Core => {
+                return Err("ELF is a core dump, not a loadable kernel image".into())
+            }
+            header::Type::ProcessorSpecific(_) => {
+                return Err("ELF has a processor-specific type, not a loadable kernel image".into())
             }
-            header::Type::Core => unimplemented!(),
-            header::Type::ProcessorSpecific(_) => unimplemented!(),
         };
         log::info!(
             "virtual_address_offset: {:#x}",
             virtual_address_offset.virtual_address_offset()
         );
 
-        used_entries.mark_segments(elf_file.program_iter(), virtual_address_offset);
+        // Reject segments in the non-canonical hole before any of their
+        // addresses are used, as a corrupted ELF file might contain them.
+        for program_header in load_segments(&elf_file).chain(layout.tls) {
+            segment_virt_range(&program_header, virtual_address_offset)?;
+        }
+        if let Some(program_header) = layout.relro {
+            check_relro_segment(program_header, &elf_file, virtual_address_offset)?;
+        }
+        for program_header in load_segments(&elf_file) {
+            check_segment_alignment(program_header, virtual_address_offset)?;
+        }
+
+        used_entries.mark_segments(load_segments(&elf_file), virtual_address_offset);
+        if options.guard_pages {
+            used_entries.mark_guard_pages(load_segments(&elf_file), virtual_address_offset);
+        }
+
+        // Jumping to an entry point outside of the kernel's code would fault
+        // immediately, so we reject such kernels instead.
+        let entry_point = if is_relocatable(&elf_file) {
+            match options.entry_point {
+                Some(entry_point) => {
+                    let is_in_code = relocatable_sections(&elf_file).any(|(_, section, offset)| {
+                        section.flags() & SHF_EXECINSTR != 0
+                            && entry_point.wrapping_sub(offset) < section.size()
+                    });
+                    if !is_in_code {
+                        return Err(LoadKernelError::InvalidEntryPoint(entry_point));
+                    }
+                    entry_point
+                }
+                None => relocatable_entry_point(&elf_file)?,
+            }
+        } else {
+            let entry_point = options
+                .entry_point
+                .unwrap_or(elf_file.header.pt2.entry_point());
+            let is_in_code = load_segments(&elf_file).any(|h| {
+                h.flags().is_execute() && entry_point.wrapping_sub(h.virtual_addr()) < h.mem_size()
+            });
+            if !is_in_code {
+                return Err(LoadKernelError::InvalidEntryPoint(entry_point));
+            }
+            entry_point
+        };
+        let entry_point = virtual_address_offset
+            .checked_add(entry_point)
+            .and_then(canonical_addr)
+            .ok_or(LoadKernelError::InvalidEntryPoint(entry_point))?;
+
+        let kernel_frames = PhysFrame::range(
+            PhysFrame::containing_address(kernel_offset),
+            PhysFrame::containing_address(kernel_offset + (elf_file.input.len() as u64 - 1)) + 1,
+        );
+
+        // The end was checked by `check_relro_segment`.
+        let relro = layout
+            .relro
+            .map(|h| h.virtual_addr()..h.virtual_addr() + h.mem_size());
 
-        header::sanity_check(&elf_file)?;
         let loader = Loader {
             elf_file,
+            layout,
+            source,
             inner: Inner {
                 kernel_offset,
+                kernel_frames,
                 virtual_address_offset,
                 page_table,
                 frame_allocator,
+                no_execute: !options.disable_no_execute,
+                physical_memory_offset: options.physical_memory_offset,
+                max_physical_address: options.max_physical_address,
+                verify_segments: options.verify_segments,
+                frames_are_zeroed: options.frames_are_zeroed,
+                global_pages: options.global_pages,
+                check_relocation_targets: options.check_relocation_targets,
+                relro,
+                touch_pages: options.touch_pages,
+                copy_segments: options.copy_segments,
+                huge_bss_pages: options.huge_bss_pages,
+                ifunc_resolver: options.ifunc_resolver,
+                max_relocations: options.max_relocations,
+                clear_accessed_dirty: options.clear_accessed_dirty,
+                in_place: false,
+                footprint: PhysicalFootprint::new(),
+                bss_frames: 0,
+                relocations: 0,
+                tls: None,
+                last_mut_page: None,
             },
+            used_entries,
+            entry_point,
+            x86_features,
         };
 
         Ok(loader)
     }
 
-    fn load_segments(&mut self) -> Result<Option<TlsTemplate>, &'static str> {
+    /// Maps the Load segments of the kernel into the page table.
+    ///
+    /// Returns the thread local storage template of the kernel (if any).
+    ///
+    /// The kernel isn't ready to run before [`Loader::apply_relocations`] has
+    /// been called.
+    pub fn load_segments(&mut self) -> Result<Option<TlsTemplate>, LoadKernelError> {
+        self.load_segments_with(LoadCallbacks::default())
+    }
+
+    /// Like [`Loader::load_segments`], but calls the given callbacks while
+    /// loading, see [`LoadCallbacks`].
+    pub fn load_segments_with(
+        &mut self,
+        callbacks: LoadCallbacks<'_>,
+    ) -> Result<Option<TlsTemplate>, LoadKernelError> {
+        let LoadCallbacks {
+            inspect,
+            mut map_flags,
+            mut progress,
+        } = callbacks;
+        let relocatable = is_relocatable(&self.elf_file);
+        let sections = relocatable
+            .then(|| relocatable_sections(&self.elf_file))
+            .into_iter()
+            .flatten()
+            .map(|(_, section, _)| section.size());
+        let total = load_segments(&self.elf_file)
+            .map(|h| h.mem_size())
+            .chain(sections)
+            .fold(0, u64::saturating_add);
+        let mut loaded = 0u64;
+
+        if let Some(inspect) = inspect {
+            for program_header in self.elf_file.program_iter() {
+                inspect(&program_header, self.inner.loaded_range(program_header))?;
+            }
+        }
+
         // Load the segments into virtual memory.
-        let mut tls_template = None;
-        for program_header in self.elf_file.program_iter() {
-            match program_header.get_type()? {
-                Type::Load => self.inner.handle_load_segment(program_header)?,
-                Type::Tls => {
-                    if tls_template.is_none() {
-                        tls_template = Some(self.inner.handle_tls_segment(program_header)?);
-                    } else {
-                        return Err("multiple TLS segments not supported");
-                    }
+        let input = self.elf_file.input;
+        for program_header in load_segments(&self.elf_file) {
+            let mut flags = self.inner.segment_flags(&program_header);
+            if let Some(map_flags) = map_flags.as_deref_mut() {
+                flags = map_flags(&program_header, flags);
+            }
+            // `COPIED` is reserved for the loader.
+            let mut flags = (flags | Flags::PRESENT) & !COPIED;
+            if self.inner.clear_accessed_dirty {
+                flags.remove(Flags::ACCESSED | Flags::DIRTY);
+            }
+            match self.source.as_deref_mut() {
+                Some(source) => {
+                    self.inner
+                        .handle_streamed_load_segment(program_header, flags, source)?
                 }
-                Type::Null
-                | Type::Dynamic
-                | Type::Interp
-                | Type::Note
-                | Type::ShLib
-                | Type::Phdr
-                | Type::GnuRelro
-                | Type::OsSpecific(_)
-                | Type::ProcessorSpecific(_) => {}
+                // Copying the segments works like reading them from a source.
+                None if self.inner.copy_segments => self.inner.handle_streamed_load_segment(
+                    program_header,
+                    flags,
+                    &mut SliceSource(input),
+                )?,
+                None => self.inner.handle_load_segment(program_header, flags)?,
+            }
+            loaded = loaded.saturating_add(program_header.mem_size());
+            if let Some(progress) = progress.as_deref_mut() {
+                progress(loaded, total);
             }
         }
+        let tls_template = match self.layout.tls {
+            Some(program_header) => Some(self.inner.handle_tls_segment(program_header)?),
+            None => None,
+        };
 
-        // Apply relocations in virtual memory.
-        for program_header in self.elf_file.program_iter() {
-            if let Type::Dynamic = program_header.get_type()? {
+        // Relocatable object files contain no segments, so we load their
+        // sections instead.
+        if relocatable {
+            for (_, section, offset) in relocatable_sections(&self.elf_file) {
                 self.inner
-                    .handle_dynamic_segment(program_header, &self.elf_file)?
+                    .handle_relocatable_section(section, offset, &self.elf_file)?;
+                loaded = loaded.saturating_add(section.size());
+                if let Some(progress) = progress.as_deref_mut() {
+                    progress(loaded, total);
+                }
             }
         }
 
-        // Mark some memory regions as read-only after relocations have been
-        // applied.
-        for program_header in self.elf_file.program_iter() {
-            if let Type::GnuRelro = program_header.get_type()? {
-                self.inner.handle_relro_segment(program_header);
+        Ok(tls_template)
+    }
+
+    /// Prepares relocating a kernel that is already mapped in the page table,
+    /// e.g. by the firmware, instead of loading its segments.
+    ///
+    /// Nothing is mapped or allocated. [`Loader::apply_relocations`] then
+    /// writes the relocations directly to the frames the Load segments are
+    /// mapped to and leaves the page table unchanged, so `PT_GNU_RELRO`
+    /// segments stay writable. The virtual address offset must match the
+    /// existing mapping, e.g. by setting [`LoaderOptions::base_address`] or
+    /// [`LoaderOptions::identity_map`].
+    ///
+    /// Must be called instead of [`Loader::load_segments`]. Returns the
+    /// thread local storage template of the kernel (if any). Relocatable
+    /// object files and kernels loaded from a [`KernelSource`] are not
+    /// supported. Returns [`LoadKernelError::InconsistentMapping`] if a page
+    /// of a Load segment isn't mapped.
+    pub fn load_segments_in_place(&mut self) -> Result<Option<TlsTemplate>, LoadKernelError> {
+        if self.source.is_some() {
+            return Err("streamed kernels can't be relocated in place".into());
+        }
+        if is_relocatable(&self.elf_file) {
+            return Err("relocatable kernels can't be relocated in place".into());
+        }
+        for program_header in load_segments(&self.elf_file) {
+            if program_header.mem_size() > 0 {
+                self.inner
+                    .check_mapped_in(program_header.virtual_addr(), program_header.mem_size())?;
             }
         }
-
-        self.inner.remove_copied_flags(&self.elf_file).unwrap();
-
-        Ok(tls_template)
+        self.inner.in_place = true;
+        match self.layout.tls {
+            Some(program_header) => Ok(Some(self.inner.handle_tls_segment(program_header)?)),
+            None => Ok(None),
+        }
     }
 
-    fn entry_point(&self) -> VirtAddr {
-        VirtAddr::new(self.inner.virtual_address_offset + self.elf_file.header.pt2.entry_point())
+    /// Applies the relocations of the kernel and marks memory regions that
+    /// should only be writable during relocation as read-only.
+    ///
+    /// Must be called after [`Loader::load_segments`], even if the kernel
+    /// contains no relocations. Logs a summary of the loaded kernel
+    /// afterwards.
+    pub fn apply_relocations(&mut self) -> Result<(), LoadKernelError> {
+        self.apply_relocations_with(|_, _| {})
     }
-}
 
-impl<'a, M, F> Inner<'a, M, F>
-where
-    M: MapperAllSizes + Translate,
-    F: FrameAllocator<Size4KiB>,
-{
-    fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
-        log::info!("Handling Segment: {:x?}", segment);
+    /// Like [`Loader::apply_relocations`], but calls `sink` with the
+    /// destination address and the written value of every applied
+    /// relocation, e.g. to measure them.
+    ///
+    /// Values of relocations that write fewer than 8 bytes are
+    /// zero-extended.
+    pub fn apply_relocations_with<S>(&mut self, mut sink: S) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        // Apply relocations in virtual memory.
+        if let Some(program_header) = self.layout.dynamic {
+            self.inner
+                .handle_dynamic_segment(program_header, &self.elf_file, &mut sink)?
+        }
 
-        let phys_start_addr = self.kernel_offset + segment.offset();
-        let start_frame: PhysFrame = PhysFrame::containing_address(phys_start_addr);
-        let end_frame: PhysFrame =
-            PhysFrame::containing_address(phys_start_addr + segment.file_size() - 1u64);
+        if is_relocatable(&self.elf_file) {
+            for section in self.elf_file.section_iter() {
+                match section.get_type()? {
+                    ShType::Rela => self.inner.handle_relocatable_relocations(
+                        section,
+                        &self.elf_file,
+                        &mut sink,
+                    )?,
+                    ShType::Rel => {
+                        return Err("Rel sections are not supported in relocatable kernels".into())
+                    }
+                    _ => {}
+                }
+            }
+        }
 
-        let virt_start_addr = VirtAddr::new(self.virtual_address_offset + segment.virtual_addr());
-        let start_page: Page = Page::containing_address(virt_start_addr);
+        // The page table of a kernel relocated in place isn't ours to modify.
+        if !self.inner.in_place {
+            // Mark some memory regions as read-only after relocations have
+            // been applied.
+            if let Some(program_header) = self.layout.relro {
+                self.inner.handle_relro_segment(program_header)?;
+            }
 
-        let mut segment_flags = Flags::PRESENT;
-        if !segment.flags().is_execute() {
-            segment_flags |= Flags::NO_EXECUTE;
-        }
-        if segment.flags().is_write() {
-            segment_flags |= Flags::WRITABLE;
+            self.inner.remove_copied_flags(&self.elf_file);
         }
 
-        // map all frames of the segment at the desired virtual address
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            let offset = frame - start_frame;
-            let page = start_page + offset;
-            let flusher = unsafe {
-                self.page_table
-                    .map_to(page, frame, segment_flags, self.frame_allocator)
-                    .map_err(|_err| "map_to failed")?
-            };
-            // we operate on an inactive page table, so there's no need to flush anything
-            flusher.ignore();
+        if self.inner.verify_segments {
+            self.verify_segments()?;
         }
-
-        // Handle .bss section (mem_size > file_size)
-        if segment.mem_size() > segment.file_size() {
-            // .bss section (or similar), which needs to be mapped and zeroed
-            self.handle_bss_section(&segment, segment_flags)?;
+        if self.inner.touch_pages {
+            self.touch_pages()?;
+        }
+        if self.inner.clear_accessed_dirty {
+            self.check_accessed_dirty()?;
         }
 
+        let load_segments = load_segments(&self.elf_file);
+        log::info!(
+            "Loaded kernel: {} Load segments, {:#x} bytes mapped, {} bss frames, \
+             {} relocations applied, TLS template: {}",
+            load_segments.clone().count(),
+            load_segments.map(|h| h.mem_size()).sum::<u64>(),
+            self.inner.bss_frames,
+            self.inner.relocations,
+            if self.inner.tls.is_some() {
+                "yes"
+            } else {
+                "no"
+            },
+        );
+
         Ok(())
     }
 
-    fn handle_bss_section(
-        &mut self,
-        segment: &ProgramHeader,
-        segment_flags: Flags,
-    ) -> Result<(), &'static str> {
-        log::info!("Mapping bss section");
+    /// Checks that no page of the kernel is mapped with the `ACCESSED` or
+    /// `DIRTY` flag, see [`LoaderOptions::clear_accessed_dirty`].
+    fn check_accessed_dirty(&self) -> Result<(), LoadKernelError> {
+        let segments = load_segments(&self.elf_file).map(|h| (h.virtual_addr(), h.mem_size()));
+        let sections = is_relocatable(&self.elf_file)
+            .then(|| relocatable_sections(&self.elf_file))
+            .into_iter()
+            .flatten()
+            .map(|(_, section, offset)| (offset, section.size()));
+        for (virtual_addr, size) in segments.chain(sections).filter(|&(_, size)| size > 0) {
+            let start = VirtAddr::new(self.inner.virtual_address_offset + virtual_addr);
+            let end = start + (size - 1);
+            for page in Page::<Size4KiB>::range_inclusive(
+                Page::containing_address(start),
+                Page::containing_address(end),
+            ) {
+                if let TranslateResult::Mapped { flags, .. } =
+                    self.inner.page_table.translate(page.start_address())
+                {
+                    if flags.intersects(Flags::ACCESSED | Flags::DIRTY) {
+                        return Err(LoadKernelError::AccessedOrDirty(
+                            page.start_address().as_u64(),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let virt_start_addr = VirtAddr::new(self.virtual_address_offset + segment.virtual_addr());
-        let mem_size = segment.mem_size();
-        let file_size = segment.file_size();
+    /// Checks that the file contents of all Load segments are mapped
+    /// correctly by reading them back through the page table.
+    ///
+    /// Bytes that are modified by relocations are skipped, so this should be
+    /// called after [`Loader::apply_relocations`]. Kernels loaded from a
+    /// [`KernelSource`] can't be verified.
+    pub fn verify_segments(&self) -> Result<(), LoadKernelError> {
+        if self.source.is_some() {
+            return Err("kernels loaded from a `KernelSource` can't be verified".into());
+        }
 
-        // calculate virtual memory region that must be zeroed
-        let zero_start = virt_start_addr + file_size;
-        let zero_end = virt_start_addr + mem_size;
+        let mut targets = [0; VERIFY_RELOCATION_BATCH];
+        for program_header in load_segments(&self.elf_file) {
+            if program_header.file_size() == 0 {
+                continue;
+            }
+            let data = slice_at(
+                &self.elf_file,
+                program_header.offset(),
+                program_header.file_size(),
+            )?;
+            let (start, _) = self.inner.segment_virt_range(&program_header)?;
+            let segment_start = program_header.virtual_addr();
+            let segment_end = segment_start + program_header.file_size();
 
-        // a type alias that helps in efficiently clearing a page
-        type PageArray = [u64; Size4KiB::SIZE as usize / 8];
-        const ZERO_ARRAY: PageArray = [0; Size4KiB::SIZE as usize / 8];
+            // The relocation targets are collected in sorted batches, so that
+            // each batch needs only one pass over the relocation tables.
+            let mut pos = segment_start;
+            while pos < segment_end {
+                let len =
+                    collect_relocation_targets(&self.elf_file, pos..segment_end, &mut targets)?;
+                let targets = &targets[..len];
+                // Targets after a full batch weren't collected, so only the
+                // bytes before its last target can be checked. If that
+                // target doesn't come after `pos`, all targets of the batch
+                // cover the byte at `pos`.
+                let batch_end = match targets {
+                    [.., last] if len == VERIFY_RELOCATION_BATCH => cmp::max(*last, pos + 1),
+                    _ => segment_end,
+                };
+                let offset = pos - segment_start;
+                self.verify_range(
+                    &data[offset as usize..(batch_end - segment_start) as usize],
+                    start + offset,
+                    pos,
+                    targets,
+                )?;
+                pos = batch_end;
+            }
+        }
+        Ok(())
+    }
 
-        // In some cases, `zero_start` might not be page-aligned. This requires some
-        // special treatment because we can't safely zero a frame of the original file.
-        let data_bytes_before_zero = zero_start.as_u64() & 0xfff;
-        if data_bytes_before_zero != 0 {
-            // The last non-bss frame of the segment consists partly of data and partly of bss
-            // memory, which must be zeroed. Unfortunately, the file representation might have
-            // reused the part of the frame that should be zeroed to store the next segment. This
-            // means that we can't simply overwrite that part with zeroes, as we might overwrite
-            // other data this way.
-            //
-            // Example:
-            //
-            //   XXXXXXXXXXXXXXX000000YYYYYYY000ZZZZZZZZZZZ     virtual memory (XYZ are data)
-            //   |·············|     /·····/   /·········/
-            //   |·············| ___/·····/   /·········/
-            //   |·············|/·····/‾‾‾   /·········/
+    /// Compares the loaded bytes starting at `addr` with `expected`, the file
+    /// contents at the virtual offset `virt_offset`.
+    ///
+    /// Bytes that are covered by one of the sorted relocation `targets` are
+    /// skipped.
+    fn verify_range(
+        &self,
+        expected: &[u8],
+        addr: VirtAddr,
+        virt_offset: u64,
+        targets: &[u64],
+    ) -> Result<(), LoadKernelError> {
+        let mut buf = [0; 64];
+        for (chunk_idx, expected) in expected.chunks(buf.len()).enumerate() {
+            let chunk_offset = (chunk_idx * buf.len()) as u64;
+            let actual = &mut buf[..expected.len()];
+            self.inner.copy_from(addr + chunk_offset, actual);
+            for (idx, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+                let offset = chunk_offset + idx as u64;
+                let byte = virt_offset + offset;
+                // The first target that might cover the byte.
+                let first = targets.partition_point(|&target| {
+                    target.saturating_add(size_of::<u64>() as u64) <= byte
+                });
+                let relocated = targets.get(first).is_some_and(|&target| target <= byte);
+                if actual != expected && !relocated {
+                    return Err(LoadKernelError::LoadedDataMismatch(addr.as_u64() + offset));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every page of the kernel is mapped to a frame that was
+    /// recorded in the [`PhysicalFootprint`] and reads a byte of each frame
+    /// through the physical memory mapping.
+    ///
+    /// This catches broken mappings while loading instead of when the
+    /// kernel first accesses the page. Should be called after
+    /// [`Loader::apply_relocations`], which may remap pages.
+    pub fn touch_pages(&self) -> Result<(), LoadKernelError> {
+        for program_header in load_segments(&self.elf_file) {
+            if program_header.mem_size() > 0 {
+                self.inner
+                    .touch_pages_in(program_header.virtual_addr(), program_header.mem_size())?;
+            }
+        }
+        if is_relocatable(&self.elf_file) {
+            for (_, section, offset) in relocatable_sections(&self.elf_file) {
+                if section.size() > 0 {
+                    self.inner.touch_pages_in(offset, section.size())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates and maps a zeroed kernel stack of the given size.
+    ///
+    /// The stack is mapped writable and non-executable starting at `start`,
+    /// or at a free virtual address if `start` is `None`. The page directly
+    /// below the stack is left unmapped as a guard page, so that a stack
+    /// overflow causes a page fault. The stack and its guard page are marked
+    /// as used in the [`UsedLevel4Entries`].
+    ///
+    /// Returns the (exclusive) end address of the stack.
+    pub fn map_kernel_stack(
+        &mut self,
+        size: u64,
+        start: Option<VirtAddr>,
+    ) -> Result<VirtAddr, LoadKernelError> {
+        let start = match start {
+            Some(start) => {
+                let guard_page = Page::<Size4KiB>::from_start_address(start)
+                    .ok()
+                    .and_then(|page| Step::backward_checked(page, 1))
+                    .ok_or(LoadKernelError::MisalignedStack(start.as_u64()))?;
+                self.used_entries
+                    .mark_range_as_used(guard_page.start_address().as_u64(), Size4KiB::SIZE + size);
+                start
+            }
+            None => self
+                .used_entries
+                .get_free_address_with_guard_page(size, Size4KiB::SIZE),
+        };
+        let end = start + size;
+
+        let mut flags = Flags::PRESENT | Flags::WRITABLE;
+        if self.inner.no_execute {
+            flags |= Flags::NO_EXECUTE;
+        }
+        let start_page: Page = Page::containing_address(start);
+        let end_page = Page::containing_address(end - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            // allocate a new unused frame
+            let frame = self.inner.allocate_frame()?;
+
+            // zero frame, utilizing the physical memory mapping
+            if !self.inner.frames_are_zeroed {
+                let frame_ptr = self.inner.phys_to_ptr(frame.start_address());
+                unsafe { zero_volatile(frame_ptr, Size4KiB::SIZE as usize) };
+            }
+
+            // map frame
+            let flusher = unsafe {
+                self.inner
+                    .page_table
+                    .map_to(page, frame, flags, self.inner.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapStackFailed(page))?
+            };
+            // we operate on an inactive page table, so we don't need to flush our changes
+            flusher.ignore();
+        }
+
+        Ok(end)
+    }
+
+    /// Maps a module that isn't part of the kernel, e.g. an initial ramdisk,
+    /// into the kernel address space.
+    ///
+    /// `data` must be accessible through the physical memory mapping, see
+    /// [`LoaderOptions::physical_memory_offset`], and stay in memory while the
+    /// kernel uses it. Its frames are mapped read-only and non-executable
+    /// starting at the page-aligned address `start`, or at a free virtual
+    /// address if `start` is `None`. The mapped range is marked as used in
+    /// the [`UsedLevel4Entries`].
+    ///
+    /// Returns the virtual address of the first byte of `data`, which has
+    /// the same offset within its page as the physical address.
+    pub fn map_ramdisk(
+        &mut self,
+        data: &[u8],
+        start: Option<VirtAddr>,
+    ) -> Result<VirtAddr, LoadKernelError> {
+        if data.is_empty() {
+            return Err(LoadKernelError::InvalidRamdisk("ramdisk is empty"));
+        }
+        let phys_start = (data.as_ptr() as u64)
+            .checked_sub(self.inner.physical_memory_offset)
+            .and_then(|addr| PhysAddr::try_new(addr).ok())
+            .ok_or(LoadKernelError::InvalidRamdisk(
+                "ramdisk isn't part of the physical memory mapping",
+            ))?;
+        let offset_in_page = phys_start.as_u64() % Size4KiB::SIZE;
+        let size = align_up(offset_in_page + data.len() as u64, Size4KiB::SIZE);
+
+        let start = match start {
+            Some(start) => {
+                if !start.is_aligned(Size4KiB::SIZE) {
+                    return Err(LoadKernelError::MisalignedRamdisk(start.as_u64()));
+                }
+                self.used_entries.mark_range_as_used(start.as_u64(), size);
+                start
+            }
+            None => self.used_entries.get_free_address(size, Size4KiB::SIZE),
+        };
+
+        let mut flags = Flags::PRESENT;
+        if self.inner.no_execute {
+            flags |= Flags::NO_EXECUTE;
+        }
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(phys_start);
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        for idx in 0..size / Size4KiB::SIZE {
+            let page = start_page + idx;
+            let flusher = unsafe {
+                self.inner
+                    .page_table
+                    .map_to(page, start_frame + idx, flags, self.inner.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapRamdiskFailed(page))?
+            };
+            // we operate on an inactive page table, so we don't need to flush our changes
+            flusher.ignore();
+        }
+
+        Ok(start + offset_in_page)
+    }
+
+    /// Returns the address of the kernel entry point, or of the entry point
+    /// given by [`LoaderOptions::entry_point`].
+    ///
+    /// The entry point is determined by [`Loader::new`], so it can be checked
+    /// before any memory is mapped.
+    pub fn entry_point(&self) -> VirtAddr {
+        self.entry_point
+    }
+
+    /// Returns whether the kernel is position independent, i.e. a shared
+    /// object or a relocatable object file whose addresses are moved by the
+    /// virtual address offset and which is relocated.
+    ///
+    /// Executables are loaded at the fixed addresses they were linked at.
+    pub fn is_position_independent(&self) -> bool {
+        self.elf_file.header.pt2.type_().as_type() != header::Type::Executable
+    }
+
+    /// Returns the OS/ABI identification (`EI_OSABI`) of the kernel ELF file.
+    ///
+    /// Zero means System V, which is used by most kernels.
+    pub fn os_abi(&self) -> u8 {
+        elf_abi(&self.elf_file).0
+    }
+
+    /// Returns the ABI version (`EI_ABIVERSION`) of the kernel ELF file.
+    pub fn abi_version(&self) -> u8 {
+        elf_abi(&self.elf_file).1
+    }
+
+    /// Returns the x86 features requested by the `.note.gnu.property` note of
+    /// the kernel, e.g. Intel CET.
+    ///
+    /// The loader doesn't enable any of the features, this is up to the
+    /// caller before jumping to the entry point. Returns no features if the
+    /// kernel contains no such note.
+    pub fn x86_features(&self) -> X86Features {
+        self.x86_features
+    }
+
+    /// Returns whether the kernel was linked expecting an executable stack,
+    /// as indicated by the flags of its `GNU_STACK` program header.
+    ///
+    /// Returns `None` if the kernel contains no `GNU_STACK` program header.
+    pub fn executable_stack(&self) -> Option<bool> {
+        self.layout.gnu_stack.map(|h| h.flags().is_execute())
+    }
+
+    /// Returns the offset that is added to the virtual addresses of the
+    /// kernel, i.e. the base address of position independent kernels.
+    ///
+    /// The offset is zero for kernels that aren't position independent. Like
+    /// the entry point, it is determined by [`Loader::new`].
+    pub fn virtual_address_offset(&self) -> VirtualAddressOffset {
+        self.inner.virtual_address_offset
+    }
+
+    /// Returns the virtual address range spanned by the kernel, from the
+    /// lowest start address to the highest end address of its memory.
+    ///
+    /// The range includes the zeroed memory at the end of the Load segments,
+    /// so memory after it is not used by the kernel. The range is inclusive,
+    /// so that it can also end at the end of a canonical half of the address
+    /// space. Returns `None` if the kernel contains no memory. Like the entry
+    /// point, it is determined by [`Loader::new`].
+    pub fn virtual_range(&self) -> Option<RangeInclusive<VirtAddr>> {
+        let segments = load_segments(&self.elf_file).map(|h| (h.virtual_addr(), h.mem_size()));
+        let sections = is_relocatable(&self.elf_file)
+            .then(|| relocatable_sections(&self.elf_file))
+            .into_iter()
+            .flatten()
+            .map(|(_, section, offset)| (offset, section.size()));
+        let (start, end_inclusive) = segments
+            .chain(sections)
+            .filter(|&(_, size)| size > 0)
+            .map(|(addr, size)| {
+                let start = self.inner.virtual_address_offset + addr;
+                (start, start + (size - 1))
+            })
+            .reduce(|(start, end), (other_start, other_end)| {
+                (cmp::min(start, other_start), cmp::max(end, other_end))
+            })?;
+        Some(VirtAddr::try_new(start).ok()?..=VirtAddr::try_new(end_inclusive).ok()?)
+    }
+
+    /// Returns the offset in the ELF file of the contents of the kernel at
+    /// the given virtual address.
+    ///
+    /// The address is relocated, i.e. it includes the virtual address offset.
+    /// Returns `None` if the address isn't part of the kernel or if it is
+    /// part of zeroed memory, which isn't stored in the file.
+    pub fn virt_to_file_offset(&self, virt: VirtAddr) -> Option<u64> {
+        let offset = self.inner.virtual_address_offset.virtual_address_offset();
+        let addr = u64::try_from(i128::from(virt.as_u64()) - offset).ok()?;
+        let segments =
+            load_segments(&self.elf_file).map(|h| (h.virtual_addr(), h.file_size(), h.offset()));
+        let sections = is_relocatable(&self.elf_file)
+            .then(|| relocatable_sections(&self.elf_file))
+            .into_iter()
+            .flatten()
+            .filter(|(_, section, _)| section.get_type() != Ok(ShType::NoBits))
+            .map(|(_, section, offset)| (offset, section.size(), section.offset()));
+        segments
+            .chain(sections)
+            .find(|&(start, file_size, _)| addr.wrapping_sub(start) < file_size)
+            .map(|(start, _, file_offset)| file_offset + (addr - start))
+    }
+
+    /// Reads a `u64` at the given virtual address of the kernel, e.g. to
+    /// inspect a kernel global before jumping to the entry point.
+    ///
+    /// The value is read through the kernel's page table, so it includes
+    /// applied relocations. Returns `None` if the address isn't mapped, e.g.
+    /// because it was read before [`Loader::load_segments`].
+    pub fn read_u64_at_virt(&self, virt: VirtAddr) -> Option<u64> {
+        let last = VirtAddr::try_new(virt.as_u64().checked_add(7)?).ok()?;
+        self.inner.page_table.translate_addr(virt)?;
+        self.inner.page_table.translate_addr(last)?;
+        let mut buf = [0; 8];
+        self.inner.copy_from(virt, &mut buf);
+        Some(u64::from_ne_bytes(buf))
+    }
+
+    /// Returns the virtual memory regions of the kernel and the flags they
+    /// are mapped with.
+    ///
+    /// Must be called after [`Loader::apply_relocations`], so that the final
+    /// flags are reported.
+    pub fn memory_map(&self) -> Result<KernelMemoryMap, LoadKernelError> {
+        let mut memory_map = KernelMemoryMap::new();
+        for program_header in load_segments(&self.elf_file) {
+            if program_header.mem_size() > 0 {
+                self.inner.add_to_memory_map(
+                    &mut memory_map,
+                    program_header.virtual_addr(),
+                    program_header.mem_size(),
+                )?;
+            }
+        }
+        if is_relocatable(&self.elf_file) {
+            for (_, section, offset) in relocatable_sections(&self.elf_file) {
+                if section.size() > 0 {
+                    self.inner
+                        .add_to_memory_map(&mut memory_map, offset, section.size())?;
+                }
+            }
+        }
+        Ok(memory_map)
+    }
+
+    /// Returns the physical frames used by the kernel, i.e. the frames of
+    /// the ELF file that are mapped and all frames allocated for the kernel.
+    ///
+    /// The frames allocated for page tables are not included.
+    pub fn physical_footprint(&self) -> &PhysicalFootprint {
+        &self.inner.footprint
+    }
+
+    /// Collects the information about the loaded kernel.
+    ///
+    /// Must be called after [`Loader::apply_relocations`].
+    fn loaded_kernel(
+        &self,
+        tls_template: Option<TlsTemplate>,
+    ) -> Result<LoadedKernel, LoadKernelError> {
+        let virtual_range = self.virtual_range().ok_or("kernel memory is empty")?;
+        Ok(LoadedKernel {
+            virtual_base_offset: VirtAddr::new(
+                self.virtual_address_offset().virtual_address_offset() as u64,
+            ),
+            entry_point: self.entry_point(),
+            position_independent: self.is_position_independent(),
+            x86_features: self.x86_features,
+            virtual_start: *virtual_range.start(),
+            virtual_end_inclusive: *virtual_range.end(),
+            tls_template,
+            physical_footprint: self.inner.footprint,
+            memory_map: self.memory_map()?,
+        })
+    }
+
+    /// Returns the level 4 entries that are in use, including the ones used
+    /// by the kernel.
+    pub fn used_level_4_entries(&self) -> &UsedLevel4Entries {
+        self.used_entries
+    }
+
+    /// Returns the level 4 entries that are in use, so that additional
+    /// regions can be marked as used.
+    pub fn used_level_4_entries_mut(&mut self) -> &mut UsedLevel4Entries {
+        self.used_entries
+    }
+}
+
+impl<'a, M, F> Inner<'a, M, F>
+where
+    M: MapperAllSizes + Translate,
+    F: FrameAllocator<Size4KiB>,
+{
+    /// Returns the start address and the inclusive end address of the
+    /// virtual memory of a segment, see [`segment_virt_range`].
+    fn segment_virt_range(
+        &self,
+        segment: &ProgramHeader,
+    ) -> Result<(VirtAddr, VirtAddr), LoadKernelError> {
+        segment_virt_range(segment, self.virtual_address_offset)
+    }
+
+    /// Allocates a new frame for the kernel and records it in the footprint.
+    ///
+    /// Fails if the frame allocator returns a frame of the ELF file, which
+    /// would corrupt the data that is still needed for loading.
+    fn allocate_frame(&mut self) -> Result<PhysFrame, LoadKernelError> {
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(LoadKernelError::OutOfFrames)?;
+        if self.kernel_frames.start <= frame && frame < self.kernel_frames.end {
+            return Err(LoadKernelError::FrameInKernelFile(frame));
+        }
+        self.check_physical_limit(frame)?;
+        self.footprint.add(PhysFrame::range(frame, frame + 1))?;
+        Ok(frame)
+    }
+
+    /// Checks that the given frame ends below the configured
+    /// [`LoaderOptions::max_physical_address`].
+    fn check_physical_limit(&self, frame: PhysFrame) -> Result<(), LoadKernelError> {
+        match self.max_physical_address {
+            Some(limit) if frame.start_address().as_u64() + frame.size() > limit => Err(
+                LoadKernelError::FrameAboveLimit(frame.start_address().as_u64()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a pointer to the given physical address, see [`phys_to_ptr`].
+    fn phys_to_ptr(&self, addr: PhysAddr) -> *mut u8 {
+        phys_to_ptr(self.physical_memory_offset, addr)
+    }
+
+    /// Returns the virtual address range a program header is loaded to.
+    fn loaded_range(&self, program_header: ProgramHeader) -> Option<Range<VirtAddr>> {
+        let start = program_header.virtual_addr();
+        let end = start.checked_add(program_header.mem_size())?;
+        let start = self.virtual_address_offset.checked_add(start)?;
+        let end = self.virtual_address_offset.checked_add(end)?;
+        Some(VirtAddr::try_new(start).ok()?..VirtAddr::try_new(end).ok()?)
+    }
+
+    /// Returns the page table flags for the memory of a Load segment.
+    fn segment_flags(&self, segment: &ProgramHeader) -> Flags {
+        let mut flags = segment_page_flags(segment.flags(), self.no_execute);
+        if self.global_pages {
+            flags |= Flags::GLOBAL;
+        }
+        flags
+    }
+
+    fn handle_load_segment(
+        &mut self,
+        segment: ProgramHeader,
+        segment_flags: Flags,
+    ) -> Result<(), LoadKernelError> {
+        log::info!("Handling Segment: {:x?}", segment);
+
+        // Segments without file contents (e.g. a dedicated bss segment) are
+        // not backed by any frames of the ELF file.
+        if segment.file_size() == 0 {
+            if segment.mem_size() > 0 {
+                self.handle_bss_section(&segment, segment_flags)?;
+            }
+            return Ok(());
+        }
+
+        let out_of_bounds = LoadKernelError::SegmentOutOfBounds(segment.virtual_addr());
+        let phys_start_addr = self
+            .kernel_offset
+            .as_u64()
+            .checked_add(segment.offset())
+            .ok_or(out_of_bounds)?;
+        let phys_end_addr = phys_start_addr
+            .checked_add(segment.file_size() - 1)
+            .and_then(|addr| PhysAddr::try_new(addr).ok())
+            .ok_or(out_of_bounds)?;
+        let start_frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(phys_start_addr));
+        let end_frame: PhysFrame = PhysFrame::containing_address(phys_end_addr);
+        // The page of each frame is calculated from its distance to
+        // `start_frame`, which must not be negative. This can't happen for a
+        // non-empty segment, but the guard keeps the arithmetic well-defined.
+        if end_frame < start_frame {
+            return Err(out_of_bounds);
+        }
+        self.check_physical_limit(end_frame)?;
+
+        self.footprint
+            .add(PhysFrame::range(start_frame, end_frame + 1))?;
+
+        let (virt_start_addr, _) = self.segment_virt_range(&segment)?;
+        let start_page: Page = Page::containing_address(virt_start_addr);
+
+        // Use 2 MiB pages where possible to reduce the number of page table
+        // entries. Writable segments are likely to be modified while applying
+        // relocations, which requires 4 KiB pages, so we don't bother for them.
+        let huge_frames = if segment.flags().is_write() {
+            None
+        } else {
+            huge_frame_range(start_page, start_frame, end_frame)
+        };
+        let is_huge = |frame: PhysFrame| {
+            huge_frames.is_some_and(|range| {
+                range.start.start_address() <= frame.start_address()
+                    && frame.start_address() < range.end.start_address()
+            })
+        };
+
+        // map all frames of the segment at the desired virtual address
+        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+            if is_huge(frame) {
+                continue;
+            }
+            let offset = frame - start_frame;
+            let page = start_page + offset;
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(page, frame, segment_flags, self.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapSegmentFailed(page))?
+            };
+            // we operate on an inactive page table, so there's no need to flush anything
+            flusher.ignore();
+        }
+        for frame in huge_frames.into_iter().flatten() {
+            let offset = frame.start_address() - start_frame.start_address();
+            let page = Page::<Size2MiB>::containing_address(start_page.start_address() + offset);
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(page, frame, segment_flags, self.frame_allocator)
+                    .map_err(|_err| {
+                        LoadKernelError::MapSegmentFailed(Page::containing_address(
+                            page.start_address(),
+                        ))
+                    })?
+            };
+            // we operate on an inactive page table, so there's no need to flush anything
+            flusher.ignore();
+        }
+
+        // Handle .bss section (mem_size > file_size)
+        if segment.mem_size() > segment.file_size() {
+            // .bss section (or similar), which needs to be mapped and zeroed
+            self.handle_bss_section(&segment, segment_flags)?;
+        }
+
+        Ok(())
+    }
+
+    /// Maps a Load segment of a kernel that is read from a [`KernelSource`]
+    /// or copied because of [`LoaderOptions::copy_segments`].
+    ///
+    /// Every page of the segment is backed by a newly allocated frame. Only
+    /// the bytes of the segment that are stored in the file are read, the
+    /// rest of each frame is zeroed.
+    fn handle_streamed_load_segment(
+        &mut self,
+        segment: ProgramHeader,
+        segment_flags: Flags,
+        source: &mut dyn KernelSource,
+    ) -> Result<(), LoadKernelError> {
+        log::info!("Handling Segment: {:x?}", segment);
+
+        if segment.mem_size() == 0 {
+            return Ok(());
+        }
+
+        // The frames don't belong to the ELF file, so they can be modified
+        // in place while applying relocations.
+        let segment_flags = segment_flags | COPIED;
+
+        let (virt_start_addr, virt_end_addr) = self.segment_virt_range(&segment)?;
+        let file_end_addr = virt_start_addr.as_u64() + segment.file_size();
+        let start_page: Page = Page::containing_address(virt_start_addr);
+        let end_page = Page::containing_address(virt_end_addr);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = self.allocate_frame()?;
+
+            // zero frame, utilizing the physical memory mapping
+            let frame_ptr = self.phys_to_ptr(frame.start_address());
+            if !self.frames_are_zeroed {
+                unsafe { zero_volatile(frame_ptr, Size4KiB::SIZE as usize) };
+            }
+
+            // read the part of the page that is stored in the file
+            let copy_start = cmp::max(page.start_address(), virt_start_addr);
+            let copy_end = cmp::min(
+                page.start_address().as_u64() + Size4KiB::SIZE,
+                file_end_addr,
+            );
+            if copy_start.as_u64() < copy_end {
+                let buf = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        frame_ptr.add((copy_start - page.start_address()) as usize),
+                        (copy_end - copy_start.as_u64()) as usize,
+                    )
+                };
+                source
+                    .read_at(segment.offset() + (copy_start - virt_start_addr), buf)
+                    .map_err(LoadKernelError::ReadFailed)?;
+            }
+
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(page, frame, segment_flags, self.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapSegmentFailed(page))?
+            };
+            // we operate on an inactive page table, so there's no need to flush anything
+            flusher.ignore();
+        }
+
+        Ok(())
+    }
+
+    fn handle_bss_section(
+        &mut self,
+        segment: &ProgramHeader,
+        segment_flags: Flags,
+    ) -> Result<(), LoadKernelError> {
+        log::info!("Mapping bss section");
+
+        let (virt_start_addr, virt_end_addr) = self.segment_virt_range(segment)?;
+        let file_size = segment.file_size();
+
+        // calculate virtual memory region that must be zeroed, which is
+        // within the checked segment range
+        let zero_start = virt_start_addr + file_size;
+
+        // In some cases, `zero_start` might not be page-aligned. This requires some
+        // special treatment because we can't safely zero a frame of the original file.
+        let data_bytes_before_zero = zero_start.as_u64() % Size4KiB::SIZE;
+        if data_bytes_before_zero != 0 && file_size != 0 {
+            // The last non-bss frame of the segment consists partly of data and partly of bss
+            // memory, which must be zeroed. Unfortunately, the file representation might have
+            // reused the part of the frame that should be zeroed to store the next segment. This
+            // means that we can't simply overwrite that part with zeroes, as we might overwrite
+            // other data this way.
+            //
+            // Example:
+            //
+            //   XXXXXXXXXXXXXXX000000YYYYYYY000ZZZZZZZZZZZ     virtual memory (XYZ are data)
+            //   |·············|     /·····/   /·········/
+            //   |·············| ___/·····/   /·········/
+            //   |·············|/·····/‾‾‾   /·········/
             //   |·············||·····|/·̅·̅·̅·̅·̅·····/‾‾‾‾
             //   XXXXXXXXXXXXXXXYYYYYYYZZZZZZZZZZZ              file memory (zeros are not saved)
             //   '       '       '       '        '
@@ -254,40 +1818,113 @@ where
             // the remaining part of the frame since the frame is no longer shared with other
             // segments now.
 
-            let last_page = Page::containing_address(virt_start_addr + file_size - 1u64);
-            let new_frame = unsafe { self.make_mut(last_page) };
-            let new_bytes_ptr = new_frame.start_address().as_u64() as *mut u8;
+            let last_page = Page::containing_address(zero_start - 1u64);
+            // `make_mut` copies the original frame with a single
+            // `copy_nonoverlapping`, which preserves the data prefix. Only the
+            // bss tail is zeroed afterwards.
+            let new_frame = unsafe { self.make_mut(last_page)? };
+            let new_bytes_ptr = self.phys_to_ptr(new_frame.start_address());
             unsafe {
-                core::ptr::write_bytes(
+                zero_volatile(
                     new_bytes_ptr.add(data_bytes_before_zero as usize),
-                    0,
                     (Size4KiB::SIZE - data_bytes_before_zero) as usize,
                 );
             }
         }
 
         // map additional frames for `.bss` memory that is not present in source file
-        let start_page: Page =
-            Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)));
-        let end_page = Page::containing_address(zero_end - 1u64);
-        for page in Page::range_inclusive(start_page, end_page) {
-            // allocate a new unused frame
-            let frame = self.frame_allocator.allocate_frame().unwrap();
+        // Without file contents, the first page isn't mapped yet either.
+        let first_new_addr = if file_size == 0 {
+            zero_start.align_down(Size4KiB::SIZE)
+        } else {
+            zero_start
+        };
+        let Some(pages) = bss_pages(first_new_addr, virt_end_addr) else {
+            return Ok(());
+        };
+        let mut page = pages.start;
+        'pages: while page <= pages.end {
+            let frame = self.allocate_bss_frame()?;
+            let mapped = if self.huge_bss_pages
+                && page.start_address().is_aligned(Size2MiB::SIZE)
+                && frame.start_address().is_aligned(Size2MiB::SIZE)
+                && pages.end - page >= HUGE_PAGE_FRAMES - 1
+            {
+                // Use a 2 MiB page if the allocator returns the following
+                // frames, which are mapped with 4 KiB pages otherwise.
+                for count in 1..HUGE_PAGE_FRAMES {
+                    let next = self.allocate_bss_frame()?;
+                    if next != frame + count {
+                        self.map_bss_pages(page, frame, count, segment_flags)?;
+                        self.map_bss_pages(page + count, next, 1, segment_flags)?;
+                        match Step::forward_checked(page, count as usize + 1) {
+                            Some(next_page) => page = next_page,
+                            None => break 'pages,
+                        }
+                        continue 'pages;
+                    }
+                }
+                let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+                let huge_frame = PhysFrame::<Size2MiB>::containing_address(frame.start_address());
+                let flusher = unsafe {
+                    self.page_table
+                        .map_to(huge_page, huge_frame, segment_flags, self.frame_allocator)
+                        .map_err(|_err| LoadKernelError::MapBssFailed(page))?
+                };
+                // we operate on an inactive page table, so we don't need to flush our changes
+                flusher.ignore();
+                HUGE_PAGE_FRAMES
+            } else {
+                self.map_bss_pages(page, frame, 1, segment_flags)?;
+                1
+            };
+            match Step::forward_checked(page, mapped as usize) {
+                Some(next_page) => page = next_page,
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
 
-            // zero frame, utilizing identity-mapping
-            let frame_ptr = frame.start_address().as_u64() as *mut PageArray;
-            unsafe { frame_ptr.write(ZERO_ARRAY) };
+    /// Allocates and zeroes a new frame for bss memory.
+    fn allocate_bss_frame(&mut self) -> Result<PhysFrame, LoadKernelError> {
+        // allocate a new unused frame
+        let frame = self.allocate_frame()?;
+        self.bss_frames += 1;
 
-            // map frame
+        // zero frame, utilizing the physical memory mapping
+        if !self.frames_are_zeroed {
+            let frame_ptr = self.phys_to_ptr(frame.start_address());
+            unsafe { zero_volatile(frame_ptr, Size4KiB::SIZE as usize) };
+        }
+        Ok(frame)
+    }
+
+    /// Maps `count` consecutive bss pages starting at `start_page` to the
+    /// consecutive frames starting at `start_frame`.
+    fn map_bss_pages(
+        &mut self,
+        start_page: Page,
+        start_frame: PhysFrame,
+        count: u64,
+        segment_flags: Flags,
+    ) -> Result<(), LoadKernelError> {
+        for offset in 0..count {
+            let page = start_page + offset;
             let flusher = unsafe {
                 self.page_table
-                    .map_to(page, frame, segment_flags, self.frame_allocator)
-                    .map_err(|_err| "Failed to map new frame for bss memory")?
+                    .map_to(
+                        page,
+                        start_frame + offset,
+                        segment_flags,
+                        self.frame_allocator,
+                    )
+                    .map_err(|_err| LoadKernelError::MapBssFailed(page))?
             };
             // we operate on an inactive page table, so we don't need to flush our changes
             flusher.ignore();
         }
-
         Ok(())
     }
 
@@ -295,7 +1932,9 @@ where
     ///
     /// ## Panics
     ///
-    /// Panics if a page is not mapped in `self.page_table`.
+    /// Panics if a page is not mapped in `self.page_table`. This can't happen
+    /// for addresses in a Load segment once the segments were loaded, see
+    /// [`Loader::load_segments_in_place`].
     fn copy_from(&self, addr: VirtAddr, buf: &mut [u8]) {
         // We can't know for sure that contiguous virtual address are contiguous
         // in physical memory, so we iterate of the pages spanning the
@@ -308,10 +1947,13 @@ where
 
         for page in start_page..=end_inclusive_page {
             // Translate the virtual page to the physical frame.
-            let phys_addr = self
-                .page_table
-                .translate_page(page)
-                .expect("address is not mapped to the kernel's memory space");
+            let phys_addr = match self.last_mut_page {
+                Some((last_page, frame)) if last_page == page => frame.start_address(),
+                _ => self
+                    .page_table
+                    .translate_addr(page.start_address())
+                    .expect("address is not mapped to the kernel's memory space"),
+            };
 
             // Figure out which address range we want to copy from the frame.
 
@@ -331,15 +1973,16 @@ where
             let copy_len = end_inclusive_offset_in_frame - start_offset_in_frame + 1;
 
             // Calculate the physical addresses.
-            let start_phys_addr = phys_addr.start_address() + start_offset_in_frame;
+            let start_phys_addr = phys_addr + start_offset_in_frame;
 
             // These are the offsets from the start address. These correspond
             // to the destination indices in `buf`.
             let start_offset_in_buf = Step::steps_between(&addr, &start_copy_address).unwrap();
 
             // Calculate the source slice.
-            // Utilize that frames are identity mapped.
-            let src_ptr = start_phys_addr.as_u64() as *const u8;
+            // Utilize that frames are accessible through the physical
+            // memory mapping.
+            let src_ptr = self.phys_to_ptr(start_phys_addr) as *const u8;
             let src = unsafe {
                 // SAFETY: We know that this memory is valid because we got it
                 // as a result from a translation. There are not other
@@ -363,7 +2006,7 @@ where
     /// ## Panics
     ///
     /// Panics if a page is not mapped in `self.page_table`.
-    unsafe fn copy_to(&mut self, addr: VirtAddr, buf: &[u8]) {
+    unsafe fn copy_to(&mut self, addr: VirtAddr, buf: &[u8]) -> Result<(), LoadKernelError> {
         // We can't know for sure that contiguous virtual address are contiguous
         // in physical memory, so we iterate of the pages spanning the
         // addresses, translate them to frames and copy the data.
@@ -377,7 +2020,7 @@ where
             // Translate the virtual page to the physical frame.
             let phys_addr = unsafe {
                 // SAFETY: The caller asserts that the pages are mapped by a Load segment.
-                self.make_mut(page)
+                self.make_mut(page)?
             };
 
             // Figure out which address range we want to copy from the frame.
@@ -405,8 +2048,9 @@ where
             let start_offset_in_buf = Step::steps_between(&addr, &start_copy_address).unwrap();
 
             // Calculate the source slice.
-            // Utilize that frames are identity mapped.
-            let dest_ptr = start_phys_addr.as_u64() as *mut u8;
+            // Utilize that frames are accessible through the physical
+            // memory mapping.
+            let dest_ptr = self.phys_to_ptr(start_phys_addr);
             let dest = unsafe {
                 // SAFETY: We know that this memory is valid because we got it
                 // as a result from a translation. There are not other
@@ -417,353 +2061,6784 @@ where
             // Calculate the destination pointer.
             let src = &buf[start_offset_in_buf..][..copy_len];
 
-            // Do the actual copy.
-            dest.copy_from_slice(src);
-        }
+            // Do the actual copy.
+            dest.copy_from_slice(src);
+        }
+
+        Ok(())
+    }
+
+    /// This method is intended for making the memory loaded by a Load segment mutable.
+    ///
+    /// All memory from a Load segment starts out by mapped to the same frames that
+    /// contain the elf file. Thus writing to memory in that state will cause aliasing issues.
+    /// To avoid that, we allocate a new frame, copy all bytes from the old frame to the new frame,
+    /// and remap the page to the new frame. At this point the page no longer aliases the elf file
+    /// and we can write to it.
+    ///
+    /// When we map the new frame we also set [`COPIED`] flag in the page table flags, so that
+    /// we can detect if the frame has already been copied when we try to modify the page again.
+    ///
+    /// ## Safety
+    /// - `page` should be a page mapped by a Load segment.
+    ///
+    /// Returns [`LoadKernelError::InconsistentMapping`] if the page isn't
+    /// mapped.
+    unsafe fn make_mut(&mut self, page: Page) -> Result<PhysFrame, LoadKernelError> {
+        if let Some((last_page, frame)) = self.last_mut_page {
+            if last_page == page {
+                return Ok(frame);
+            }
+        }
+
+        let (frame, flags) = match self.page_table.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame,
+                offset: _,
+                flags,
+            } => (frame, flags),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                return Err(LoadKernelError::InconsistentMapping(
+                    page.start_address().as_u64(),
+                ))
+            }
+        };
+        if self.in_place {
+            // The kernel is already resident, so its frames are modified
+            // directly without touching the mapping.
+            let offset = page.start_address().as_u64() % frame.size();
+            let frame = PhysFrame::containing_address(frame.start_address() + offset);
+            self.last_mut_page = Some((page, frame));
+            return Ok(frame);
+        }
+        let (frame, flags) = match frame {
+            MappedFrame::Size4KiB(frame) => (frame, flags),
+            MappedFrame::Size2MiB(huge_frame) => {
+                // Only complete 4k pages can be remapped.
+                let huge_page = Page::containing_address(page.start_address());
+                self.split_huge_page(huge_page, huge_frame, flags)?;
+                let offset = page.start_address() - huge_page.start_address();
+                let frame = PhysFrame::containing_address(huge_frame.start_address() + offset);
+                (frame, flags & !Flags::HUGE_PAGE)
+            }
+            // We never map 1 GiB pages.
+            MappedFrame::Size1GiB(_) => unreachable!(),
+        };
+
+        if flags.contains(COPIED) {
+            // The frame was already copied, we are free to modify it.
+            self.last_mut_page = Some((page, frame));
+            return Ok(frame);
+        }
+
+        // Allocate a new frame and copy the memory, utilizing the physical memory mapping.
+        let new_frame = self.allocate_frame()?;
+        let frame_ptr = self.phys_to_ptr(frame.start_address()) as *const u8;
+        let new_frame_ptr = self.phys_to_ptr(new_frame.start_address());
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame_ptr, new_frame_ptr, Size4KiB::SIZE as usize);
+        }
+
+        // Replace the underlying frame and update the flags.
+        self.page_table
+            .unmap(page)
+            .map_err(|_err| LoadKernelError::UnmapFailed(page))?
+            .1
+            .ignore();
+        let new_flags = flags | COPIED;
+        unsafe {
+            self.page_table
+                .map_to(page, new_frame, new_flags, self.frame_allocator)
+                .unwrap()
+                .ignore();
+        }
+        self.last_mut_page = Some((page, new_frame));
+
+        Ok(new_frame)
+    }
+
+    /// Replaces the mapping of a 2 MiB page with 4 KiB pages mapped to the
+    /// same memory.
+    fn split_huge_page(
+        &mut self,
+        huge_page: Page<Size2MiB>,
+        huge_frame: PhysFrame<Size2MiB>,
+        flags: Flags,
+    ) -> Result<(), LoadKernelError> {
+        let start_page = Page::<Size4KiB>::containing_address(huge_page.start_address());
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(huge_frame.start_address());
+
+        self.page_table
+            .unmap(huge_page)
+            .map_err(|_err| LoadKernelError::UnmapFailed(start_page))?
+            .1
+            .ignore();
+
+        let flags = flags & !Flags::HUGE_PAGE;
+        for offset in 0..Size2MiB::SIZE / Size4KiB::SIZE {
+            let page = start_page + offset;
+            let frame = start_frame + offset;
+            unsafe {
+                self.page_table
+                    .map_to(page, frame, flags, self.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapSegmentFailed(page))?
+                    .ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cleans up the custom flags set by [`Inner::make_mut`].
+    fn remove_copied_flags(&mut self, elf_file: &ElfFile) {
+        // Without the flag, the page would be copied again by `make_mut`.
+        self.last_mut_page = None;
+        for program_header in load_segments(elf_file) {
+            if program_header.mem_size() > 0 {
+                self.remove_copied_flags_in(
+                    program_header.virtual_addr(),
+                    program_header.mem_size(),
+                );
+            }
+        }
+        if is_relocatable(elf_file) {
+            for (_, section, offset) in relocatable_sections(elf_file) {
+                if section.size() > 0 {
+                    self.remove_copied_flags_in(offset, section.size());
+                }
+            }
+        }
+    }
+
+    /// Adds the mapped pages in the given range, which is offset by the
+    /// virtual address offset, to the memory map.
+    fn add_to_memory_map(
+        &self,
+        memory_map: &mut KernelMemoryMap,
+        virtual_addr: u64,
+        size: u64,
+    ) -> Result<(), LoadKernelError> {
+        let overflow = LoadKernelError::AddressOverflow(virtual_addr);
+        let start = self
+            .virtual_address_offset
+            .checked_add(virtual_addr)
+            .ok_or(overflow)?;
+        let end_inclusive = start.checked_add(size - 1).ok_or(overflow)?;
+
+        let mut addr = align_down(start, Size4KiB::SIZE);
+        while addr <= end_inclusive {
+            let (page_size, flags) = match self.page_table.translate(VirtAddr::new(addr)) {
+                TranslateResult::Mapped { frame, flags, .. } => (frame.size(), flags),
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                    unreachable!("has the elf file not been mapped correctly?")
+                }
+            };
+            let page_start = align_down(addr, page_size);
+            memory_map.add(KernelRegion {
+                start: VirtAddr::new(page_start),
+                size: page_size,
+                flags: flags & !(Flags::HUGE_PAGE | Flags::ACCESSED | Flags::DIRTY),
+            })?;
+            // Stop at the end of the address space.
+            match page_start
+                .checked_add(page_size)
+                .filter(|&next| VirtAddr::try_new(next).is_ok())
+            {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the pages in the given range, which is offset by the
+    /// virtual address offset, are mapped, see
+    /// [`Loader::load_segments_in_place`].
+    fn check_mapped_in(&self, virtual_addr: u64, size: u64) -> Result<(), LoadKernelError> {
+        let start = VirtAddr::new(self.virtual_address_offset + virtual_addr);
+        let end = start + (size - 1);
+        for page in Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        ) {
+            if let TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) =
+                self.page_table.translate(page.start_address())
+            {
+                return Err(LoadKernelError::InconsistentMapping(
+                    page.start_address().as_u64(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the pages in the given range, which is offset by the virtual
+    /// address offset, see [`Loader::touch_pages`].
+    fn touch_pages_in(&self, virtual_addr: u64, size: u64) -> Result<(), LoadKernelError> {
+        let start = VirtAddr::new(self.virtual_address_offset + virtual_addr);
+        let end = start + (size - 1);
+        for page in Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        ) {
+            let inconsistent = LoadKernelError::InconsistentMapping(page.start_address().as_u64());
+            let addr = match self.page_table.translate(page.start_address()) {
+                TranslateResult::Mapped { frame, offset, .. } => frame.start_address() + offset,
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                    return Err(inconsistent)
+                }
+            };
+            if !self.footprint.contains(PhysFrame::containing_address(addr)) {
+                return Err(inconsistent);
+            }
+            unsafe {
+                // SAFETY: The frame belongs to the kernel, so it is backed by
+                // memory that is accessible through the physical memory
+                // mapping.
+                core::ptr::read_volatile(self.phys_to_ptr(addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Cleans up the custom flags set by [`Inner::make_mut`] for the pages
+    /// in the given range, which is offset by the virtual address offset.
+    fn remove_copied_flags_in(&mut self, virtual_addr: u64, size: u64) {
+        let start = VirtAddr::new(self.virtual_address_offset + virtual_addr);
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(start + (size - 1));
+        for page in Page::<Size4KiB>::range_inclusive(start_page, end_page) {
+            // Translate the page and get the flags.
+            let res = self.page_table.translate(page.start_address());
+            let flags = match res {
+                TranslateResult::Mapped {
+                    frame: _,
+                    offset: _,
+                    flags,
+                } => flags,
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                    unreachable!("has the elf file not been mapped correctly?")
+                }
+            };
+
+            if flags.contains(COPIED) {
+                // Remove the flag.
+                unsafe {
+                    self.page_table
+                        .update_flags(page, flags & !COPIED)
+                        .unwrap()
+                        .ignore();
+                }
+            }
+        }
+    }
+
+    /// Maps an allocated section of a relocatable object file to newly
+    /// allocated frames.
+    ///
+    /// The pages are mapped with the [`COPIED`] flag because the frames don't
+    /// alias the ELF file, so relocations can be applied to them directly.
+    fn handle_relocatable_section(
+        &mut self,
+        section: SectionHeader,
+        offset: u64,
+        elf_file: &ElfFile,
+    ) -> Result<(), LoadKernelError> {
+        log::info!("Handling Section: {:x?}", section);
+
+        if section.size() == 0 {
+            return Ok(());
+        }
+        let data = match section.get_type()? {
+            ShType::NoBits => &[],
+            _ => section.raw_data(elf_file),
+        };
+
+        let mut section_flags = Flags::PRESENT | COPIED;
+        if self.no_execute && section.flags() & SHF_EXECINSTR == 0 {
+            section_flags |= Flags::NO_EXECUTE;
+        }
+        if self.global_pages {
+            section_flags |= Flags::GLOBAL;
+        }
+        if section.flags() & SHF_WRITE != 0 {
+            section_flags |= Flags::WRITABLE;
+        }
+
+        let virt_start_addr = VirtAddr::new(self.virtual_address_offset + offset);
+        let start_page: Page = Page::containing_address(virt_start_addr);
+        let end_page = Page::containing_address(virt_start_addr + section.size() - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            // allocate a new unused frame
+            let frame = self.allocate_frame()?;
+
+            // copy the section data into the frame and zero the rest,
+            // utilizing the physical memory mapping
+            let offset_in_section = (page.start_address() - virt_start_addr) as usize;
+            let chunk = data.get(offset_in_section..).unwrap_or_default();
+            let chunk = &chunk[..cmp::min(chunk.len(), Size4KiB::SIZE as usize)];
+            let frame_ptr = self.phys_to_ptr(frame.start_address());
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), frame_ptr, chunk.len());
+                zero_volatile(
+                    frame_ptr.add(chunk.len()),
+                    Size4KiB::SIZE as usize - chunk.len(),
+                );
+            }
+
+            // map frame
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(page, frame, section_flags, self.frame_allocator)
+                    .map_err(|_err| LoadKernelError::MapSegmentFailed(page))?
+            };
+            // we operate on an inactive page table, so we don't need to flush our changes
+            flusher.ignore();
+        }
+
+        Ok(())
+    }
+
+    /// Applies the relocations of a `Rela` section of a relocatable object
+    /// file.
+    fn handle_relocatable_relocations<S>(
+        &mut self,
+        section: SectionHeader,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        // Relocations for sections that aren't loaded (e.g. debug info) are
+        // not needed at runtime.
+        let target = section.info() as u16;
+        let Some(target_offset) = relocatable_section_offset(elf_file, target) else {
+            return Ok(());
+        };
+        let target_size = elf_file.section_header(target)?.size();
+
+        let symbols = match elf_file
+            .section_header(section.link() as u16)?
+            .get_data(elf_file)?
+        {
+            SectionData::SymbolTable64(symbols) => symbols,
+            _ => return Err("relocation section doesn't reference a symbol table".into()),
+        };
+        let relocations = match section.get_data(elf_file)? {
+            SectionData::Rela64(relocations) => relocations,
+            _ => return Err("expected Rela64 section".into()),
+        };
+        self.check_relocation_count(relocations.len() as u64)?;
+
+        for rela in relocations {
+            let offset_in_section = rela.get_offset();
+            let ty = rela.get_type();
+            let (size, pc_relative) = match ty {
+                // R_AMD64_64
+                1 => (8, false),
+                // R_AMD64_PC32 | R_AMD64_PLT32
+                2 | 4 => (4, true),
+                // R_AMD64_32 | R_AMD64_32S
+                10 | 11 => (4, false),
+                // R_AMD64_PC64
+                24 => (8, true),
+                ty => {
+                    return Err(LoadKernelError::UnsupportedRelocationType {
+                        ty,
+                        offset: offset_in_section,
+                    })
+                }
+            };
+
+            // Make sure that the relocation happens inside the target section.
+            if offset_in_section
+                .checked_add(size)
+                .is_none_or(|end| end > target_size)
+            {
+                return Err(LoadKernelError::NotInLoadSegment(offset_in_section));
+            }
+            let offset = target_offset + offset_in_section;
+            let addr = VirtAddr::new(self.virtual_address_offset + offset);
+
+            // Calculate the relocated value.
+            let symbol_value =
+                self.resolve_relocatable_symbol(symbols, rela.get_symbol_table_index(), elf_file)?;
+            let mut value = i128::from(symbol_value) + i128::from(rela.get_addend() as i64);
+            if pc_relative {
+                value -= i128::from(addr.as_u64());
+            }
+            let overflow = LoadKernelError::RelocationOverflow(offset);
+            let bytes = match ty {
+                1 => u64::try_from(value).map_err(|_| overflow)?.to_ne_bytes(),
+                24 => i64::try_from(value).map_err(|_| overflow)?.to_ne_bytes(),
+                10 => u64::from(u32::try_from(value).map_err(|_| overflow)?).to_ne_bytes(),
+                _ => i64::from(i32::try_from(value).map_err(|_| overflow)?).to_ne_bytes(),
+            };
+
+            // Write the relocated value to memory.
+            unsafe {
+                // SAFETY: We just verified that the address is in a loaded
+                // section.
+                self.copy_to(addr, &bytes[..size as usize])?;
+            }
+            self.relocations += 1;
+            let mut written = [0; 8];
+            written[..size as usize].copy_from_slice(&bytes[..size as usize]);
+            sink(addr, u64::from_ne_bytes(written));
+        }
+
+        Ok(())
+    }
+
+    /// Calculates the address of a symbol of a relocatable object file.
+    fn resolve_relocatable_symbol(
+        &self,
+        symbols: &[symbol_table::Entry64],
+        idx: u32,
+        elf_file: &ElfFile,
+    ) -> Result<u64, LoadKernelError> {
+        // Relocations without a symbol use zero as the symbol value.
+        if idx == 0 {
+            return Ok(0);
+        }
+        let symbol = symbols
+            .get(idx as usize)
+            .ok_or("symbol index is out of bounds")?;
+        match symbol.shndx() {
+            SHN_UNDEF if is_weak(symbol) => Ok(0),
+            SHN_UNDEF => Err(LoadKernelError::UndefinedSymbol {
+                idx,
+                name: symbol.get_name(elf_file).ok().map(SymbolName::from),
+            }),
+            // Absolute symbols are not affected by relocation.
+            SHN_ABS => Ok(symbol.value()),
+            shndx => {
+                let offset = relocatable_section_offset(elf_file, shndx)
+                    .ok_or("symbol is defined in a section that isn't loaded")?;
+                Ok(self.virtual_address_offset + (offset + symbol.value()))
+            }
+        }
+    }
+
+    fn handle_tls_segment(
+        &mut self,
+        segment: ProgramHeader,
+    ) -> Result<TlsTemplate, LoadKernelError> {
+        // The template is part of a Load segment, so it's moved by the same
+        // offset, including any KASLR offset or base address.
+        let (start_addr, _) = self.segment_virt_range(&segment)?;
+        let tls = TlsTemplate {
+            start_addr: start_addr.as_u64(),
+            mem_size: segment.mem_size(),
+            file_size: segment.file_size(),
+            align: match segment.align() {
+                0 => 8,
+                align => align,
+            },
+        };
+        self.tls = Some(tls);
+        Ok(tls)
+    }
+
+    fn handle_dynamic_segment<S>(
+        &mut self,
+        segment: ProgramHeader,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        // The dynamic entries are read from the loaded segments, so that
+        // they are also available for kernels loaded from a `KernelSource`.
+        check_table_in_load(elf_file, segment.virtual_addr(), segment.file_size())?;
+        let num_entries = segment.file_size() / size_of::<Dynamic<u64>>() as u64;
+
+        // Find the `Rela`, `RelaSize`, `RelaEnt`, `Rel`, `RelSize`, `RelEnt`,
+        // `Relr`, `RelrSize`, `RelrEnt`, `JmpRel`, `PltRelSize`, `PltRel`,
+        // `SymTab`, `SymEnt`, `StrTab` and `StrSize` entries.
+        let mut rela = None;
+        let mut rela_size = None;
+        let mut rela_ent = None;
+        let mut rela_count = None;
+        let mut rel = None;
+        let mut rel_size = None;
+        let mut rel_ent = None;
+        let mut relr = None;
+        let mut relr_size = None;
+        let mut relr_ent = None;
+        let mut jmp_rel = None;
+        let mut plt_rel_size = None;
+        let mut plt_rel = None;
+        let mut symbol_table = None;
+        let mut symbol_ent = None;
+        let mut string_table = None;
+        let mut string_size = None;
+        for idx in 0..num_entries {
+            let entry = &self.read_dynamic_entry(segment.virtual_addr(), idx);
+            let tag = match entry.get_tag() {
+                Ok(tag) => tag,
+                Err(err) => {
+                    // `xmas_elf` doesn't know about the `Relr` entries, so we
+                    // have to decode them ourselves.
+                    let (tag, val) = raw_dynamic_entry(entry);
+                    let (name, slot) = match tag {
+                        DT_RELR => ("Relr", &mut relr),
+                        DT_RELRSZ => ("RelrSize", &mut relr_size),
+                        DT_RELRENT => ("RelrEnt", &mut relr_ent),
+                        _ => return Err(err.into()),
+                    };
+                    let prev = slot.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry(name));
+                    }
+                    continue;
+                }
+            };
+            match tag {
+                dynamic::Tag::Rela => {
+                    let ptr = entry.get_ptr()?;
+                    let prev = rela.replace(ptr);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("Rela"));
+                    }
+                }
+                dynamic::Tag::RelaSize => {
+                    let val = entry.get_val()?;
+                    let prev = rela_size.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("RelaSize"));
+                    }
+                }
+                dynamic::Tag::RelaEnt => {
+                    let val = entry.get_val()?;
+                    let prev = rela_ent.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("RelaEnt"));
+                    }
+                }
+                dynamic::Tag::OsSpecific(DT_RELACOUNT) => {
+                    let val = entry.get_val()?;
+                    let prev = rela_count.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("RelaCount"));
+                    }
+                }
+                dynamic::Tag::Rel => {
+                    let ptr = entry.get_ptr()?;
+                    let prev = rel.replace(ptr);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("Rel"));
+                    }
+                }
+                dynamic::Tag::RelSize => {
+                    let val = entry.get_val()?;
+                    let prev = rel_size.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("RelSize"));
+                    }
+                }
+                dynamic::Tag::RelEnt => {
+                    let val = entry.get_val()?;
+                    let prev = rel_ent.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("RelEnt"));
+                    }
+                }
+                dynamic::Tag::JmpRel => {
+                    let ptr = entry.get_ptr()?;
+                    let prev = jmp_rel.replace(ptr);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("JmpRel"));
+                    }
+                }
+                dynamic::Tag::PltRelSize => {
+                    let val = entry.get_val()?;
+                    let prev = plt_rel_size.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("PltRelSize"));
+                    }
+                }
+                dynamic::Tag::PltRel => {
+                    let val = entry.get_val()?;
+                    let prev = plt_rel.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("PltRel"));
+                    }
+                }
+                dynamic::Tag::SymTab => {
+                    let ptr = entry.get_ptr()?;
+                    let prev = symbol_table.replace(ptr);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("SymTab"));
+                    }
+                }
+                dynamic::Tag::SymEnt => {
+                    let val = entry.get_val()?;
+                    let prev = symbol_ent.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("SymEnt"));
+                    }
+                }
+                dynamic::Tag::StrTab => {
+                    let ptr = entry.get_ptr()?;
+                    let prev = string_table.replace(ptr);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("StrTab"));
+                    }
+                }
+                dynamic::Tag::StrSize => {
+                    let val = entry.get_val()?;
+                    let prev = string_size.replace(val);
+                    if prev.is_some() {
+                        return Err(LoadKernelError::DuplicateDynamicEntry("StrSize"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(entry_size) = symbol_ent {
+            // Make sure that the reported size matches our `DynEntry64`.
+            check_entry_size("SymEnt", entry_size, size_of::<DynEntry64>())?;
+        }
+        let string_table = match (string_table, string_size) {
+            (Some(offset), Some(size)) => {
+                check_table_in_load(elf_file, offset, size)?;
+                Some((offset, size))
+            }
+            (Some(_), None) => return Err(LoadKernelError::MissingDynamicEntry("StrSize")),
+            (None, Some(_)) => return Err(LoadKernelError::UnexpectedDynamicEntries("StrTab")),
+            (None, None) => None,
+        };
+        let symbols = DynamicSymbols {
+            symbol_table,
+            string_table,
+        };
+
+        if let Some(offset) = rela {
+            let total_size = rela_size.ok_or(LoadKernelError::MissingDynamicEntry("RelaSize"))?;
+            let entry_size = rela_ent.ok_or(LoadKernelError::MissingDynamicEntry("RelaEnt"))?;
+
+            // Make sure that the reported size matches our `Rela<u64>`.
+            check_entry_size("RelaEnt", entry_size, size_of::<Rela<u64>>())?;
+
+            check_table_in_load(elf_file, offset, total_size)?;
+            let relative_count = rela_count.unwrap_or(0);
+            self.apply_rela_table(offset, total_size, relative_count, symbols, elf_file, sink)?;
+        } else if rela_size.is_some() || rela_ent.is_some() || rela_count.is_some() {
+            return Err(LoadKernelError::UnexpectedDynamicEntries("Rela"));
+        }
+
+        if let Some(offset) = rel {
+            let total_size = rel_size.ok_or(LoadKernelError::MissingDynamicEntry("RelSize"))?;
+            let entry_size = rel_ent.ok_or(LoadKernelError::MissingDynamicEntry("RelEnt"))?;
+
+            // Make sure that the reported size matches our `Rel<u64>`.
+            check_entry_size("RelEnt", entry_size, size_of::<Rel<u64>>())?;
+
+            check_table_in_load(elf_file, offset, total_size)?;
+            self.apply_rel_table(offset, total_size, symbols, elf_file, sink)?;
+        } else if rel_size.is_some() || rel_ent.is_some() {
+            return Err(LoadKernelError::UnexpectedDynamicEntries("Rel"));
+        }
+
+        if let Some(offset) = relr {
+            let total_size = relr_size.ok_or(LoadKernelError::MissingDynamicEntry("RelrSize"))?;
+            let entry_size = relr_ent.ok_or(LoadKernelError::MissingDynamicEntry("RelrEnt"))?;
+
+            // Make sure that the reported size matches our `u64` entries.
+            check_entry_size("RelrEnt", entry_size, size_of::<u64>())?;
+
+            check_table_in_load(elf_file, offset, total_size)?;
+            self.apply_relr_table(offset, total_size, elf_file, sink)?;
+        } else if relr_size.is_some() || relr_ent.is_some() {
+            return Err(LoadKernelError::UnexpectedDynamicEntries("Relr"));
+        }
+
+        // The relocations for the PLT (usually `R_AMD64_JUMP_SLOT`) are stored
+        // in a separate table.
+        if let Some(offset) = jmp_rel {
+            let total_size =
+                plt_rel_size.ok_or(LoadKernelError::MissingDynamicEntry("PltRelSize"))?;
+            check_table_in_load(elf_file, offset, total_size)?;
+            match plt_rel {
+                Some(DT_RELA) => {
+                    self.apply_rela_table(offset, total_size, 0, symbols, elf_file, sink)?
+                }
+                Some(DT_REL) => {
+                    self.apply_rel_table(offset, total_size, symbols, elf_file, sink)?
+                }
+                Some(value) => return Err(LoadKernelError::InvalidPltRel(value)),
+                None => return Err(LoadKernelError::MissingDynamicEntry("PltRel")),
+            }
+        } else if plt_rel_size.is_some() {
+            return Err(LoadKernelError::UnexpectedDynamicEntries("JmpRel"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `count` more relocations can be applied without exceeding
+    /// [`LoaderOptions::max_relocations`].
+    fn check_relocation_count(&self, count: u64) -> Result<(), LoadKernelError> {
+        let count = self.relocations.saturating_add(count);
+        match self.max_relocations {
+            Some(max) if count > max => Err(LoadKernelError::TooManyRelocations { count, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies all relocations in a table of `Rela<u64>` entries.
+    ///
+    /// The first `relative_count` entries must be `R_AMD64_RELATIVE`
+    /// relocations, as announced by the `RelaCount` entry, which are applied
+    /// without resolving their type.
+    fn apply_rela_table<S>(
+        &mut self,
+        relocation_table: u64,
+        total_size: u64,
+        relative_count: u64,
+        symbols: DynamicSymbols,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        let num_entries = total_size / size_of::<Rela<u64>>() as u64;
+        self.check_relocation_count(num_entries)?;
+        if relative_count > num_entries {
+            return Err(LoadKernelError::InvalidRelaCount(relative_count));
+        }
+        for idx in 0..relative_count {
+            let rela = self.read_relocation(relocation_table, idx);
+            // R_AMD64_RELATIVE
+            if rela.get_type() != 8 || rela.get_symbol_table_index() != 0 {
+                return Err(LoadKernelError::InvalidRelaCount(relative_count));
+            }
+            let value = self.virtual_address_offset + rela.get_addend();
+            self.write_relocation(rela.get_offset(), value, elf_file, sink)?;
+        }
+        for idx in relative_count..num_entries {
+            let rela = self.read_relocation(relocation_table, idx);
+            self.apply_relocation(rela, symbols, elf_file, sink)?;
+        }
+        Ok(())
+    }
+
+    /// Applies all relocations in a table of `Rel<u64>` entries.
+    fn apply_rel_table<S>(
+        &mut self,
+        relocation_table: u64,
+        total_size: u64,
+        symbols: DynamicSymbols,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        let num_entries = total_size / size_of::<Rel<u64>>() as u64;
+        self.check_relocation_count(num_entries)?;
+        for idx in 0..num_entries {
+            let rela = self.read_implicit_relocation(relocation_table, idx, elf_file)?;
+            self.apply_relocation(rela, symbols, elf_file, sink)?;
+        }
+        Ok(())
+    }
+
+    /// Applies all relocations in a compressed table of relative relocations.
+    fn apply_relr_table<S>(
+        &mut self,
+        relocation_table: u64,
+        total_size: u64,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        let mut decoder = RelrDecoder::default();
+        let num_entries = total_size / size_of::<u64>() as u64;
+        self.check_relocation_count(num_entries)?;
+        for idx in 0..num_entries {
+            // Calculate the address of the entry in the relocation table.
+            let offset = relocation_table + size_of::<u64>() as u64 * idx;
+            let value = self.virtual_address_offset + offset;
+            let addr =
+                VirtAddr::try_new(value).expect("relocation table is outside the address space");
+
+            // Read the entry from the kernel address space.
+            let mut buf = [0; 8];
+            self.copy_from(addr, &mut buf);
+            let entry = u64::from_ne_bytes(buf);
+
+            for offset in decoder.decode(entry) {
+                self.apply_relative_relocation(offset, elf_file, sink)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a relative relocation whose addend is stored at the
+    /// destination.
+    fn apply_relative_relocation<S>(
+        &mut self,
+        offset: u64,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        // Make sure that the relocation happens in memory mapped
+        // by a Load segment.
+        self.check_relocation_target(elf_file, offset)?;
+
+        // Calculate the destination of the relocation.
+        let addr = self.virtual_address_offset + offset;
+        let addr = VirtAddr::new(addr);
+
+        // Read the addend and calculate the relocated value.
+        let mut buf = [0; 8];
+        self.copy_from(addr, &mut buf);
+        let value = self.virtual_address_offset + u64::from_ne_bytes(buf);
+
+        // Write the relocated value to memory.
+        unsafe {
+            // SAFETY: We just verified that the address is in a Load segment.
+            self.copy_to(addr, &value.to_ne_bytes())?;
+        }
+        self.relocations += 1;
+        sink(addr, value);
+
+        Ok(())
+    }
+
+    /// Checks that the relocation at the given offset targets a Load segment
+    /// and, if enabled by [`LoaderOptions::check_relocation_targets`], memory
+    /// that is meant to be written.
+    fn check_relocation_target(
+        &self,
+        elf_file: &ElfFile,
+        virt_offset: u64,
+    ) -> Result<(), LoadKernelError> {
+        let segment = find_load_segment(elf_file, virt_offset)?
+            .ok_or(LoadKernelError::NotInLoadSegment(virt_offset))?;
+        if !self.check_relocation_targets
+            || segment.flags().is_write()
+            || self
+                .relro
+                .as_ref()
+                .is_some_and(|relro| relro.contains(&virt_offset))
+        {
+            return Ok(());
+        }
+        Err(LoadKernelError::ReadOnlyRelocationTarget(virt_offset))
+    }
+
+    /// Reads an entry of the dynamic section.
+    fn read_dynamic_entry(&self, dynamic_section: u64, idx: u64) -> Dynamic<u64> {
+        // Calculate the address of the entry in the dynamic section.
+        let offset = dynamic_section + size_of::<Dynamic<u64>>() as u64 * idx;
+        let value = self.virtual_address_offset + offset;
+        let addr = VirtAddr::try_new(value).expect("dynamic section is outside the address space");
+
+        // Read the entry from the kernel address space.
+        let mut buf = [0; 16];
+        self.copy_from(addr, &mut buf);
+
+        // Convert the bytes we read into a `Dynamic<u64>`.
+        unsafe {
+            // SAFETY: Any bitpattern is valid for `Dynamic<u64>` and buf is
+            // valid for reads.
+            core::ptr::read_unaligned(&buf as *const u8 as *const Dynamic<u64>)
+        }
+    }
+
+    /// Reads a relocation from a relocation table.
+    fn read_relocation(&self, relocation_table: u64, idx: u64) -> Rela<u64> {
+        // Calculate the address of the entry in the relocation table.
+        let offset = relocation_table + size_of::<Rela<u64>>() as u64 * idx;
+        let value = self.virtual_address_offset + offset;
+        let addr = VirtAddr::try_new(value).expect("relocation table is outside the address space");
+
+        // Read the Rela from the kernel address space.
+        let mut buf = [0; 24];
+        self.copy_from(addr, &mut buf);
+
+        // Convert the bytes we read into a `Rela<u64>`.
+        unsafe {
+            // SAFETY: Any bitpattern is valid for `Rela<u64>` and buf is
+            // valid for reads.
+            core::ptr::read_unaligned(&buf as *const u8 as *const Rela<u64>)
+        }
+    }
+
+    /// Reads a relocation from a relocation table without explicit addends.
+    ///
+    /// `Rel` entries store their addend at the destination of the relocation,
+    /// so the addend is read from there and the result is returned as a
+    /// `Rela<u64>`.
+    fn read_implicit_relocation(
+        &self,
+        relocation_table: u64,
+        idx: u64,
+        elf_file: &ElfFile,
+    ) -> Result<Rela<u64>, LoadKernelError> {
+        // Calculate the address of the entry in the relocation table.
+        let offset = relocation_table + size_of::<Rel<u64>>() as u64 * idx;
+        let value = self.virtual_address_offset + offset;
+        let addr = VirtAddr::try_new(value).expect("relocation table is outside the address space");
+
+        // Read the offset and info fields of the Rel from the kernel address
+        // space.
+        let mut buf = [0; 24];
+        self.copy_from(addr, &mut buf[..16]);
+        let rel_offset = u64::from_ne_bytes(buf[..8].try_into().unwrap());
+
+        // Read the implicit addend from the destination of the relocation.
+        check_is_in_load(elf_file, rel_offset)?;
+        let addend_addr = VirtAddr::new(self.virtual_address_offset + rel_offset);
+        self.copy_from(addend_addr, &mut buf[16..]);
+
+        // Convert the bytes we read into a `Rela<u64>`.
+        Ok(unsafe {
+            // SAFETY: Any bitpattern is valid for `Rela<u64>` and buf is
+            // valid for reads.
+            core::ptr::read_unaligned(&buf as *const u8 as *const Rela<u64>)
+        })
+    }
+
+    /// Reads a symbol from the dynamic symbol table.
+    fn read_symbol(
+        &self,
+        elf_file: &ElfFile,
+        symbol_table: u64,
+        idx: u32,
+    ) -> Result<DynEntry64, LoadKernelError> {
+        // There is no dynamic entry for the size of the symbol table, so we
+        // check that the table up to the requested entry is loaded.
+        let entry_size = size_of::<DynEntry64>() as u64;
+        let out_of_bounds = LoadKernelError::TableOutOfBounds(symbol_table);
+        let offset_in_table = entry_size
+            .checked_mul(u64::from(idx))
+            .ok_or(out_of_bounds)?;
+        let extent = offset_in_table
+            .checked_add(entry_size)
+            .ok_or(out_of_bounds)?;
+        check_table_in_load(elf_file, symbol_table, extent)?;
+
+        // Calculate the address of the entry in the symbol table.
+        let offset = symbol_table + offset_in_table;
+        let addr = VirtAddr::try_new(self.virtual_address_offset + offset)
+            .map_err(|_| LoadKernelError::AddressOverflow(offset))?;
+
+        // Read the symbol from the kernel address space.
+        let mut buf = [0; 24];
+        self.copy_from(addr, &mut buf);
+
+        // Convert the bytes we read into a `DynEntry64`.
+        unsafe {
+            // SAFETY: Any bitpattern is valid for `DynEntry64` and buf is
+            // valid for reads.
+            Ok(core::ptr::read_unaligned(
+                &buf as *const u8 as *const DynEntry64,
+            ))
+        }
+    }
+
+    /// Reads the name of a symbol from the dynamic string table.
+    ///
+    /// Returns `None` if the kernel has no string table or the name is
+    /// outside of it.
+    fn read_symbol_name(&self, symbols: DynamicSymbols, symbol: &DynEntry64) -> Option<SymbolName> {
+        let (string_table, string_size) = symbols.string_table?;
+        let name_offset = u64::from(symbol.name());
+        if name_offset >= string_size {
+            return None;
+        }
+
+        // Read the name from the kernel address space until the terminating
+        // null byte, the end of the string table, or the maximum length.
+        let mut name = SymbolName::default();
+        let max_len = cmp::min(string_size - name_offset, SymbolName::MAX_LEN as u64);
+        for i in 0..max_len {
+            let addr =
+                VirtAddr::try_new(self.virtual_address_offset + (string_table + name_offset + i))
+                    .ok()?;
+            let mut buf = [0; 1];
+            self.copy_from(addr, &mut buf);
+            if buf[0] == 0 {
+                break;
+            }
+            name.push(buf[0]);
+        }
+        Some(name)
+    }
+
+    /// Calculates the relocated address of a symbol defined in the kernel.
+    ///
+    /// Returns an error for undefined symbols because there are no other
+    /// objects that could provide a definition. Undefined weak symbols
+    /// resolve to zero instead, like in a dynamic linker.
+    fn resolve_symbol(
+        &self,
+        elf_file: &ElfFile,
+        symbols: DynamicSymbols,
+        idx: u32,
+    ) -> Result<u64, LoadKernelError> {
+        let symbol_table = symbols
+            .symbol_table
+            .ok_or(LoadKernelError::MissingDynamicEntry("SymTab"))?;
+        let symbol = self.read_symbol(elf_file, symbol_table, idx)?;
+        match symbol.shndx() {
+            SHN_UNDEF if is_weak(&symbol) => Ok(0),
+            SHN_UNDEF => Err(LoadKernelError::UndefinedSymbol {
+                idx,
+                name: self.read_symbol_name(symbols, &symbol),
+            }),
+            // Absolute symbols are not affected by relocation.
+            SHN_ABS => Ok(symbol.value()),
+            _ => Ok(self.virtual_address_offset + symbol.value()),
+        }
+    }
+
+    /// Returns the offset of a thread local symbol in the TLS segment.
+    ///
+    /// Relocations without a symbol use an offset of zero.
+    fn resolve_tls_symbol(
+        &self,
+        elf_file: &ElfFile,
+        symbols: DynamicSymbols,
+        idx: u32,
+    ) -> Result<u64, LoadKernelError> {
+        if idx == 0 {
+            return Ok(0);
+        }
+        let symbol_table = symbols
+            .symbol_table
+            .ok_or(LoadKernelError::MissingDynamicEntry("SymTab"))?;
+        let symbol = self.read_symbol(elf_file, symbol_table, idx)?;
+        match symbol.shndx() {
+            SHN_UNDEF => Err(LoadKernelError::UndefinedSymbol {
+                idx,
+                name: self.read_symbol_name(symbols, &symbol),
+            }),
+            // The value of thread local symbols is already an offset, so it
+            // isn't affected by the virtual address offset.
+            _ => Ok(symbol.value()),
+        }
+    }
+
+    fn apply_relocation<S>(
+        &mut self,
+        rela: Rela<u64>,
+        symbols: DynamicSymbols,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        let symbol_idx = rela.get_symbol_table_index();
+
+        // Calculate the relocated value.
+        let value = match rela.get_type() {
+            // R_AMD64_64
+            1 => {
+                // Relocations without a symbol are relative to the base
+                // address.
+                if symbol_idx == 0 {
+                    self.virtual_address_offset + rela.get_addend()
+                } else {
+                    let symbol_value = self.resolve_symbol(elf_file, symbols, symbol_idx)?;
+                    symbol_value
+                        .checked_add_signed(rela.get_addend() as i64)
+                        .ok_or(LoadKernelError::RelocationOverflow(rela.get_offset()))?
+                }
+            }
+            // R_AMD64_GLOB_DAT | R_AMD64_JUMP_SLOT
+            6 | 7 => {
+                // Relocations without a symbol are treated like
+                // `R_AMD64_RELATIVE`.
+                if symbol_idx == 0 {
+                    self.virtual_address_offset + rela.get_addend()
+                } else {
+                    self.resolve_symbol(elf_file, symbols, symbol_idx)?
+                }
+            }
+            // R_AMD64_RELATIVE
+            8 => {
+                if symbol_idx != 0 {
+                    return Err(LoadKernelError::UnsupportedSymbolRelocation {
+                        ty: 8,
+                        offset: rela.get_offset(),
+                    });
+                }
+                self.virtual_address_offset + rela.get_addend()
+            }
+            // R_AMD64_DTPMOD64
+            16 => {
+                // The kernel is statically linked, so its TLS block is
+                // always the first module.
+                1
+            }
+            // R_AMD64_DTPOFF64
+            17 => self
+                .resolve_tls_symbol(elf_file, symbols, symbol_idx)?
+                .wrapping_add(rela.get_addend()),
+            // R_AMD64_TPOFF64
+            18 => {
+                // The thread pointer points to the end of the TLS block, so
+                // the offsets are negative.
+                let tls = self
+                    .tls
+                    .ok_or(LoadKernelError::MissingTlsSegment(rela.get_offset()))?;
+                if !tls.align.is_power_of_two() {
+                    return Err("TLS segment alignment is not a power of two".into());
+                }
+                let block_size = align_up(tls.mem_size, tls.align);
+                self.resolve_tls_symbol(elf_file, symbols, symbol_idx)?
+                    .wrapping_add(rela.get_addend())
+                    .wrapping_sub(block_size)
+            }
+            // R_AMD64_IRELATIVE
+            37 => {
+                if symbol_idx != 0 {
+                    return Err(LoadKernelError::UnsupportedSymbolRelocation {
+                        ty: 37,
+                        offset: rela.get_offset(),
+                    });
+                }
+                let resolver = self
+                    .ifunc_resolver
+                    .ok_or(LoadKernelError::MissingIfuncResolver(rela.get_offset()))?;
+                resolver(self.virtual_address_offset + rela.get_addend())
+            }
+            ty => {
+                return Err(LoadKernelError::UnsupportedRelocationType {
+                    ty,
+                    offset: rela.get_offset(),
+                })
+            }
+        };
+
+        self.write_relocation(rela.get_offset(), value, elf_file, sink)
+    }
+
+    /// Writes the relocated value of a relocation to the given virtual
+    /// offset.
+    fn write_relocation<S>(
+        &mut self,
+        offset: u64,
+        value: u64,
+        elf_file: &ElfFile,
+        sink: &mut S,
+    ) -> Result<(), LoadKernelError>
+    where
+        S: FnMut(VirtAddr, u64),
+    {
+        // Make sure that the relocation happens in memory mapped
+        // by a Load segment.
+        self.check_relocation_target(elf_file, offset)?;
+
+        // Calculate the destination of the relocation.
+        let addr = self.virtual_address_offset + offset;
+        let addr = VirtAddr::new(addr);
+
+        // Write the relocated value to memory.
+        unsafe {
+            // SAFETY: We just verified that the address is in a Load segment.
+            self.copy_to(addr, &value.to_ne_bytes())?;
+        }
+        self.relocations += 1;
+        sink(addr, value);
+
+        Ok(())
+    }
+
+    /// Mark a region of memory indicated by a GNU_RELRO segment as read-only.
+    ///
+    /// This is a security mitigation used to protect memory regions that
+    /// need to be writable while applying relocations, but should never be
+    /// written to after relocations have been applied.
+    ///
+    /// Only pages that are completely covered by the region are made
+    /// read-only, so the start is rounded up and the end is rounded down to
+    /// a page boundary. Partially covered pages may also contain data that
+    /// must stay writable.
+    ///
+    /// The segment was checked to be part of a Load segment by
+    /// [`check_relro_segment`].
+    fn handle_relro_segment(
+        &mut self,
+        program_header: ProgramHeader,
+    ) -> Result<(), LoadKernelError> {
+        if program_header.mem_size() == 0 {
+            return Ok(());
+        }
+        let (start, end_inclusive) =
+            segment_virt_range(&program_header, self.virtual_address_offset)?;
+        // Saturating only loses a page at the very end of the address space.
+        let start = align_down(
+            start.as_u64().saturating_add(Size4KiB::SIZE - 1),
+            Size4KiB::SIZE,
+        );
+        let end = align_down(end_inclusive.as_u64().saturating_add(1), Size4KiB::SIZE);
+        if start >= end {
+            return Ok(());
+        }
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end)),
+        );
+        for page in pages {
+            let inconsistent = LoadKernelError::InconsistentMapping(page.start_address().as_u64());
+            // Translate the page and get the flags.
+            let res = self.page_table.translate(page.start_address());
+            let (frame, mut flags) = match res {
+                TranslateResult::Mapped {
+                    frame,
+                    offset: _,
+                    flags,
+                } => (frame, flags),
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                    return Err(inconsistent)
+                }
+            };
+
+            if flags.contains(Flags::WRITABLE) {
+                // Zeroed memory may be mapped with 2 MiB pages, see
+                // `LoaderOptions::huge_bss_pages`.
+                if let MappedFrame::Size2MiB(huge_frame) = frame {
+                    let huge_page = Page::containing_address(page.start_address());
+                    self.split_huge_page(huge_page, huge_frame, flags)?;
+                    flags &= !Flags::HUGE_PAGE;
+                }
+                // Remove the WRITABLE flag.
+                unsafe {
+                    self.page_table
+                        .update_flags(page, flags & !Flags::WRITABLE)
+                        .map_err(|_err| inconsistent)?
+                        .ignore();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reserves a virtual address range for a position independent kernel.
+///
+/// The range starts at the configured [`LoaderOptions::base_address`] or at a
+/// free address. Returns the start address of the range, which is moved by
+/// the configured [`LoaderOptions::kaslr_offset`].
+fn reserve_address_range(
+    used_entries: &mut UsedLevel4Entries,
+    size: u64,
+    align: u64,
+    options: &LoaderOptions,
+) -> Result<u64, LoadKernelError> {
+    // Reserve enough space to move the kernel by the KASLR offset.
+    let kaslr_offset = options.kaslr_offset;
+    if kaslr_offset & (Size2MiB::SIZE - 1) != 0 {
+        return Err(LoadKernelError::InvalidKaslrOffset(kaslr_offset));
+    }
+    // Keep space for the guard pages below and above the kernel, without
+    // changing its alignment.
+    let padding = if options.guard_pages {
+        cmp::max(align, Size4KiB::SIZE)
+    } else {
+        0
+    };
+    let reserved_size = size
+        .checked_add(kaslr_offset)
+        .and_then(|size| size.checked_add(2 * padding))
+        .ok_or(LoadKernelError::InvalidKaslrOffset(kaslr_offset))?;
+
+    let offset = match options.base_address {
+        Some(base_address) => {
+            let start = base_address
+                .checked_sub(padding)
+                .filter(|_| base_address % Size4KiB::SIZE == 0)
+                .ok_or(LoadKernelError::InvalidBaseAddress(base_address))?;
+            if reserved_size > 0 {
+                // The range must not cross the non-canonical hole.
+                let end_inclusive = start
+                    .checked_add(reserved_size - 1)
+                    .filter(|&end| {
+                        VirtAddr::try_new(start).is_ok()
+                            && VirtAddr::try_new(end).is_ok()
+                            && start >> 47 == end >> 47
+                    })
+                    .ok_or(LoadKernelError::InvalidBaseAddress(base_address))?;
+                used_entries.mark_range_as_used(start, end_inclusive - start + 1);
+            }
+            start
+        }
+        None => used_entries.get_free_address(reserved_size, align).as_u64(),
+    };
+    offset
+        .checked_add(kaslr_offset + padding)
+        .filter(|offset| VirtAddr::try_new(offset + size).is_ok())
+        .ok_or(LoadKernelError::InvalidKaslrOffset(kaslr_offset))
+}
+
+/// Returns the virtual address offset that maps the Load segments of a
+/// position independent kernel to the physical addresses of their contents,
+/// see [`LoaderOptions::identity_map`].
+///
+/// This requires the same distance between the virtual address and the file
+/// offset for all segments.
+fn identity_offset(
+    elf_file: &ElfFile,
+    kernel_offset: PhysAddr,
+) -> Result<VirtualAddressOffset, LoadKernelError> {
+    let mut offset = None;
+    for segment in load_segments(elf_file).filter(|h| h.mem_size() > 0) {
+        let impossible = LoadKernelError::IdentityMappingImpossible(segment.virtual_addr());
+        let start = kernel_offset
+            .as_u64()
+            .checked_add(segment.offset())
+            .ok_or(impossible)?;
+        // The segment must be in the lower half, like physical memory.
+        start
+            .checked_add(segment.mem_size())
+            .filter(|&end| end <= 1 << 47)
+            .ok_or(impossible)?;
+
+        let segment_offset = i128::from(start) - i128::from(segment.virtual_addr());
+        if *offset.get_or_insert(segment_offset) != segment_offset {
+            return Err(impossible);
+        }
+    }
+    Ok(VirtualAddressOffset::new(offset.unwrap_or(0)))
+}
+
+/// Returns the `len` bytes at `offset` of an ELF file that is completely in
+/// memory.
+///
+/// The offsets in program headers are usually checked by
+/// [`check_segment_bounds`] already, but reading through this function
+/// ensures that a corrupted offset results in an error instead of a panic.
+fn slice_at<'a>(
+    elf_file: &ElfFile<'a>,
+    offset: u64,
+    len: u64,
+) -> Result<&'a [u8], LoadKernelError> {
+    usize::try_from(offset)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(offset, len)| elf_file.input.get(offset..offset.checked_add(len)?))
+        .ok_or(LoadKernelError::InvalidElf("file offset out of range"))
+}
+
+/// Returns the physical address of an ELF file in the physical memory mapping.
+fn elf_phys_addr(input: &[u8], options: &LoaderOptions) -> Result<PhysAddr, LoadKernelError> {
+    (input.as_ptr() as u64)
+        .checked_sub(options.physical_memory_offset)
+        .and_then(|addr| PhysAddr::try_new(addr).ok())
+        .ok_or(LoadKernelError::InvalidElf(
+            "ELF file is not in the physical memory mapping",
+        ))
+}
+
+/// Reads the path of the dynamic interpreter requested by an `Interp` program
+/// header.
+///
+/// Paths longer than [`SymbolName::MAX_LEN`] bytes are truncated.
+fn interpreter_path(
+    program_header: ProgramHeader,
+    elf_file: &ElfFile,
+    source: Option<&mut (dyn KernelSource + '_)>,
+) -> Result<SymbolName, LoadKernelError> {
+    let len = cmp::min(program_header.file_size(), SymbolName::MAX_LEN as u64) as usize;
+    let mut buf = [0; SymbolName::MAX_LEN];
+    let bytes = match source {
+        Some(source) => {
+            source
+                .read_at(program_header.offset(), &mut buf[..len])
+                .map_err(LoadKernelError::ReadFailed)?;
+            &buf[..len]
+        }
+        None => slice_at(elf_file, program_header.offset(), len as u64)?,
+    };
+
+    let mut path = SymbolName::default();
+    for &byte in bytes.iter().take_while(|&&byte| byte != 0) {
+        path.push(byte);
+    }
+    Ok(path)
+}
+
+/// Reads the x86 features from the note of a `GNU_PROPERTY` program header.
+fn gnu_property_features(
+    program_header: ProgramHeader,
+    elf_file: &ElfFile,
+    source: Option<&mut (dyn KernelSource + '_)>,
+) -> Result<X86Features, LoadKernelError> {
+    // The note usually contains only a few properties.
+    const MAX_LEN: usize = 256;
+
+    let len = program_header.file_size();
+    if len > MAX_LEN as u64 {
+        return Err("GNU_PROPERTY segment is too large".into());
+    }
+    let len = len as usize;
+    let mut buf = [0; MAX_LEN];
+    let bytes = match source {
+        Some(source) => {
+            source
+                .read_at(program_header.offset(), &mut buf[..len])
+                .map_err(LoadKernelError::ReadFailed)?;
+            &buf[..len]
+        }
+        None => slice_at(elf_file, program_header.offset(), len as u64)?,
+    };
+    parse_gnu_property_note(bytes)
+}
+
+/// Parses the `GNU_PROPERTY_X86_FEATURE_1_AND` property of the GNU property
+/// notes in `bytes`.
+///
+/// Notes of other owners or types and other properties are skipped.
+fn parse_gnu_property_note(mut bytes: &[u8]) -> Result<X86Features, LoadKernelError> {
+    const INVALID: LoadKernelError = LoadKernelError::InvalidElf("invalid GNU property note");
+
+    fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, LoadKernelError> {
+        let bytes = bytes.get(offset..offset + 4).ok_or(INVALID)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+    // Descriptors, notes and property data are aligned to 8 bytes in 64-bit
+    // ELF files.
+    fn align(offset: usize) -> usize {
+        offset.next_multiple_of(8)
+    }
+
+    let mut features = X86Features::default();
+    while !bytes.is_empty() {
+        let name_len = read_u32(bytes, 0)?;
+        let desc_len = read_u32(bytes, 4)?;
+        let ty = read_u32(bytes, 8)?;
+        let name = bytes.get(12..12 + name_len as usize).ok_or(INVALID)?;
+        let desc_start = align(12 + name_len as usize);
+        let mut desc = bytes
+            .get(desc_start..desc_start + desc_len as usize)
+            .ok_or(INVALID)?;
+        bytes = bytes
+            .get(align(desc_start + desc_len as usize)..)
+            .unwrap_or_default();
+
+        if name != b"GNU\0" || ty != NT_GNU_PROPERTY_TYPE_0 {
+            continue;
+        }
+        while !desc.is_empty() {
+            let pr_type = read_u32(desc, 0)?;
+            let data_len = read_u32(desc, 4)?;
+            let data = desc.get(8..8 + data_len as usize).ok_or(INVALID)?;
+            if pr_type == GNU_PROPERTY_X86_FEATURE_1_AND {
+                features = X86Features(read_u32(data, 0)?);
+            }
+            desc = desc.get(align(8 + data_len as usize)..).unwrap_or_default();
+        }
+    }
+    Ok(features)
+}
+
+/// Returns a pointer to the given physical address in the current address
+/// space.
+///
+/// All accesses to physical memory go through this function, so that they
+/// respect [`LoaderOptions::physical_memory_offset`].
+fn phys_to_ptr(physical_memory_offset: u64, addr: PhysAddr) -> *mut u8 {
+    (physical_memory_offset + addr.as_u64()) as *mut u8
+}
+
+/// Zeroes `len` bytes of kernel memory starting at `ptr`.
+///
+/// The memory is only read through the kernel's page table after loading,
+/// which the compiler doesn't know about. Volatile writes guarantee that
+/// zeroing the memory is never optimized out. Aligned memory is written in
+/// whole words, so zeroing frames stays fast.
+///
+/// ## Safety
+///
+/// `ptr` must be valid for writes of `len` bytes.
+unsafe fn zero_volatile(ptr: *mut u8, len: usize) {
+    let head = cmp::min(ptr.align_offset(size_of::<u64>()), len);
+    let words = (len - head) / size_of::<u64>();
+    let tail = head + words * size_of::<u64>();
+    unsafe {
+        for i in (0..head).chain(tail..len) {
+            ptr.add(i).write_volatile(0);
+        }
+        let word_ptr = ptr.add(head).cast::<u64>();
+        for i in 0..words {
+            word_ptr.add(i).write_volatile(0);
+        }
+    }
+}
+
+/// Returns the pages of the bss region `zero_start..=zero_end` that need newly
+/// allocated frames, i.e. the pages after the page containing `zero_start`
+/// (unless `zero_start` is page aligned).
+///
+/// Returns `None` if the bss region ends within the last page that contains
+/// file data, which is zeroed by [`Inner::handle_bss_section`] directly.
+fn bss_pages(zero_start: VirtAddr, zero_end: VirtAddr) -> Option<PageRangeInclusive> {
+    let mut start_page: Page = Page::containing_address(zero_start);
+    if !zero_start.is_aligned(Size4KiB::SIZE) {
+        start_page = Step::forward_checked(start_page, 1)?;
+    }
+    let end_page = Page::containing_address(zero_end);
+    (start_page <= end_page).then(|| Page::range_inclusive(start_page, end_page))
+}
+
+/// Checks that the file contents of a segment are contained in the ELF file
+/// and that its memory size is consistent.
+///
+/// This catches truncated kernel images before any memory is mapped.
+fn check_segment_bounds(
+    program_header: ProgramHeader,
+    file_len: u64,
+) -> Result<(), LoadKernelError> {
+    let virtual_addr = program_header.virtual_addr();
+    let file_end = program_header
+        .offset()
+        .checked_add(program_header.file_size())
+        .ok_or(LoadKernelError::SegmentOutOfBounds(virtual_addr))?;
+    if file_end > file_len {
+        return Err(LoadKernelError::SegmentOutOfBounds(virtual_addr));
+    }
+    // More file contents than memory would overlap the following memory.
+    // For the TLS segment, the template wouldn't fit into the TLS block.
+    if matches!(program_header.get_type()?, Type::Load | Type::Tls)
+        && (program_header.file_size() > program_header.mem_size()
+            || virtual_addr
+                .checked_add(program_header.mem_size())
+                .is_none())
+    {
+        return Err(LoadKernelError::InvalidSegmentSize(virtual_addr));
+    }
+    Ok(())
+}
+
+/// Performs the checks of [`program::sanity_check`] that don't require the
+/// whole ELF file in memory.
+fn check_streamed_program_header(
+    program_header: ProgramHeader,
+    elf_file: &ElfFile,
+) -> Result<(), LoadKernelError> {
+    if elf_file.header.pt2.ph_entry_size() as usize != size_of::<program::ProgramHeader64>() {
+        return Err("program header size mismatch".into());
+    }
+    if program_header.get_type()? == Type::ShLib {
+        return Err("Shouldn't use ShLib".into());
+    }
+    let align = program_header.align();
+    if align > 1 && program_header.virtual_addr() % align != program_header.offset() % align {
+        return Err("Invalid combination of virtual_addr, offset, and align".into());
+    }
+    Ok(())
+}
+
+/// Returns the start address and the inclusive end address of the virtual
+/// memory of a segment.
+///
+/// Fails if the addresses overflow or if the range isn't completely
+/// contained in one canonical half of the address space, so that all
+/// addresses within the range can be computed without further checks.
+fn segment_virt_range(
+    segment: &ProgramHeader,
+    virtual_address_offset: VirtualAddressOffset,
+) -> Result<(VirtAddr, VirtAddr), LoadKernelError> {
+    let overflow = LoadKernelError::AddressOverflow(segment.virtual_addr());
+    let start = virtual_address_offset
+        .checked_add(segment.virtual_addr())
+        .ok_or(overflow)?;
+    let end = start
+        .checked_add(segment.mem_size().saturating_sub(1))
+        .ok_or(overflow)?;
+    match (canonical_addr(start), canonical_addr(end)) {
+        (Some(start), Some(end)) if start.as_u64() >> 47 == end.as_u64() >> 47 => Ok((start, end)),
+        _ => Err(overflow),
+    }
+}
+
+/// Checks that the `GNU_RELRO` segment is part of a Load segment, so that
+/// the pages it covers are mapped when they are made read-only.
+fn check_relro_segment(
+    relro: ProgramHeader,
+    elf_file: &ElfFile,
+    virtual_address_offset: VirtualAddressOffset,
+) -> Result<(), LoadKernelError> {
+    segment_virt_range(&relro, virtual_address_offset)?;
+    let start = relro.virtual_addr();
+    let end = start
+        .checked_add(relro.mem_size())
+        .ok_or(LoadKernelError::InvalidRelroSegment(start))?;
+    // The end of the Load segments was checked by `check_segment_bounds`.
+    let in_load = load_segments(elf_file)
+        .any(|h| h.virtual_addr() <= start && end <= h.virtual_addr() + h.mem_size());
+    if !in_load {
+        return Err(LoadKernelError::InvalidRelroSegment(start));
+    }
+    Ok(())
+}
+
+/// Converts the given address to a [`VirtAddr`] if it's canonical.
+///
+/// Unlike [`VirtAddr::try_new`], this doesn't sign extend addresses in the
+/// non-canonical hole whose bit 47 is set, since such an address in the ELF
+/// file is most likely corrupted.
+fn canonical_addr(addr: u64) -> Option<VirtAddr> {
+    VirtAddr::try_new(addr)
+        .ok()
+        .filter(|virt| virt.as_u64() == addr)
+}
+
+/// Returns whether the symbol has weak binding.
+///
+/// Relocations against undefined weak symbols resolve to zero.
+fn is_weak(symbol: &impl Entry) -> bool {
+    matches!(symbol.get_binding(), Ok(symbol_table::Binding::Weak))
+}
+
+/// Returns the OS/ABI identification and the ABI version of an ELF file.
+///
+/// `xmas_elf` only exposes the OS/ABI as an incomplete enum, so the bytes are
+/// read from the identification at the start of the file.
+fn elf_abi(elf_file: &ElfFile) -> (u8, u8) {
+    (elf_file.input[EI_OSABI], elf_file.input[EI_ABIVERSION])
+}
+
+/// Checks that a Load segment with an alignment larger than a page is loaded
+/// at a virtual address that satisfies the alignment.
+///
+/// Like the ELF specification requires for `p_align`, the loaded virtual
+/// address must be congruent to the file offset modulo the alignment.
+fn check_segment_alignment(
+    program_header: ProgramHeader,
+    virtual_address_offset: VirtualAddressOffset,
+) -> Result<(), LoadKernelError> {
+    let align = program_header.align();
+    if program_header.get_type()? != Type::Load || align <= PAGE_SIZE {
+        return Ok(());
+    }
+    let virtual_addr = virtual_address_offset + program_header.virtual_addr();
+    if !align.is_power_of_two() || virtual_addr % align != program_header.offset() % align {
+        return Err(LoadKernelError::MisalignedSegment {
+            addr: virtual_addr,
+            align,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that the entry size of a table matches the size of the type its
+/// entries are read as.
+///
+/// Reading the entries with a different stride would misinterpret them.
+fn check_entry_size(
+    tag: &'static str,
+    entry_size: u64,
+    expected: usize,
+) -> Result<(), LoadKernelError> {
+    if entry_size != expected as u64 {
+        return Err(LoadKernelError::UnsupportedEntrySize {
+            tag,
+            entry_size,
+            expected,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that a table of the given size at the virtual offset is contained
+/// in the file-backed part of a Load segment.
+fn check_table_in_load(
+    elf_file: &ElfFile,
+    virt_offset: u64,
+    size: u64,
+) -> Result<(), LoadKernelError> {
+    let end = virt_offset
+        .checked_add(size)
+        .ok_or(LoadKernelError::TableOutOfBounds(virt_offset))?;
+    for program_header in elf_file.program_iter() {
+        if let Type::Load = program_header.get_type()? {
+            let start_in_file = program_header.virtual_addr();
+            let end_in_file = start_in_file + program_header.file_size();
+            if start_in_file <= virt_offset && end <= end_in_file {
+                return Ok(());
+            }
+        }
+    }
+    Err(LoadKernelError::TableOutOfBounds(virt_offset))
+}
+
+/// Checks that the non-empty Load segments are sorted by their virtual
+/// address, as required by the ELF specification, and that no two of them
+/// are mapped to the same page.
+///
+/// Mapping overlapping segments would fail anyway, but with an error that
+/// doesn't mention the segments.
+fn check_overlapping_segments(elf_file: &ElfFile) -> Result<(), LoadKernelError> {
+    let mut previous: Option<ProgramHeader> = None;
+    for segment in load_segments(elf_file).filter(|h| h.mem_size() > 0) {
+        if let Some(previous) = previous {
+            let (first, second) = (previous.virtual_addr(), segment.virtual_addr());
+            if second < first {
+                return Err(LoadKernelError::UnsortedSegments { first, second });
+            }
+            let previous_end = align_up(first + previous.mem_size(), Size4KiB::SIZE);
+            if align_down(second, Size4KiB::SIZE) < previous_end {
+                return Err(LoadKernelError::OverlappingSegments { first, second });
+            }
+        }
+        previous = Some(segment);
+    }
+    Ok(())
+}
+
+/// Returns whether the ELF file is a relocatable object file.
+fn is_relocatable(elf_file: &ElfFile) -> bool {
+    matches!(
+        elf_file.header.pt2.type_().as_type(),
+        header::Type::Relocatable
+    )
+}
+
+/// Returns the allocated sections of a relocatable object file together with
+/// their indices and their offsets in the loaded kernel.
+///
+/// Each section starts on a new page, so that it can be mapped with its own
+/// flags. The iterator ends early if a section doesn't fit into the address
+/// space, which `Loader::new` rejects.
+fn relocatable_sections<'a, 'b>(
+    elf_file: &'b ElfFile<'a>,
+) -> impl Iterator<Item = (u16, SectionHeader<'a>, u64)> + 'b {
+    elf_file
+        .section_iter()
+        .enumerate()
+        .filter(|(_, section)| section.flags() & SHF_ALLOC != 0)
+        .scan(0u64, |next_offset, (index, section)| {
+            let index = index as u16;
+            let align = cmp::max(section_align(elf_file, index).ok()?, Size4KiB::SIZE);
+            let offset = next_offset.checked_next_multiple_of(align)?;
+            *next_offset = offset.checked_add(section.size())?;
+            Some((index, section, offset))
+        })
+}
+
+/// Returns the size and the alignment of the memory needed for the allocated
+/// sections of a relocatable object file.
+///
+/// Fails if a section alignment isn't a power of two or if the sections don't
+/// fit into the address space.
+fn relocatable_layout(elf_file: &ElfFile) -> Result<(u64, u64), LoadKernelError> {
+    let mut align = Size4KiB::SIZE;
+    let mut allocated = 0;
+    for (index, section) in elf_file.section_iter().enumerate() {
+        if section.flags() & SHF_ALLOC == 0 {
+            continue;
+        }
+        let section_align = section_align(elf_file, index as u16)?;
+        if section_align != 0 && !section_align.is_power_of_two() {
+            return Err("section alignment is not a power of two".into());
+        }
+        align = cmp::max(align, section_align);
+        allocated += 1;
+    }
+
+    // `relocatable_sections` stops at the first section whose end overflows,
+    // so every allocated section must be returned.
+    let mut sections = 0;
+    let mut size = 0;
+    for (_, section, offset) in relocatable_sections(elf_file) {
+        sections += 1;
+        size = offset + section.size();
+    }
+    if sections != allocated {
+        return Err("sections don't fit into the address space".into());
+    }
+    Ok((size, align))
+}
+
+/// Returns the offset of an allocated section of a relocatable object file
+/// in the loaded kernel.
+fn relocatable_section_offset(elf_file: &ElfFile, index: u16) -> Option<u64> {
+    relocatable_sections(elf_file)
+        .find(|&(i, _, _)| i == index)
+        .map(|(_, _, offset)| offset)
+}
+
+/// Returns the alignment of a section, which isn't exposed by `xmas_elf`.
+fn section_align(elf_file: &ElfFile, index: u16) -> Result<u64, LoadKernelError> {
+    // `sh_addralign` is stored at offset 48 of a 64-bit section header.
+    let header_offset = u64::from(index)
+        .checked_mul(u64::from(elf_file.header.pt2.sh_entry_size()))
+        .and_then(|offset| offset.checked_add(elf_file.header.pt2.sh_offset()))
+        .and_then(|offset| offset.checked_add(48))
+        .ok_or(LoadKernelError::SectionHeaderTableOutOfBounds)?;
+    let bytes = slice_at(elf_file, header_offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the offset of the `_start` symbol of a relocatable object file in
+/// the loaded kernel.
+fn relocatable_entry_point(elf_file: &ElfFile) -> Result<u64, LoadKernelError> {
+    for section in elf_file.section_iter() {
+        if section.get_type()? != ShType::SymTab {
+            continue;
+        }
+        let SectionData::SymbolTable64(symbols) = section.get_data(elf_file)? else {
+            continue;
+        };
+        for symbol in symbols {
+            if symbol.shndx() != SHN_UNDEF && symbol.get_name(elf_file) == Ok("_start") {
+                let offset = relocatable_section_offset(elf_file, symbol.shndx())
+                    .ok_or("_start is defined in a section that isn't loaded")?;
+                let section = elf_file.section_header(symbol.shndx())?;
+                if section.flags() & SHF_EXECINSTR == 0 {
+                    return Err(LoadKernelError::InvalidEntryPoint(offset + symbol.value()));
+                }
+                return Ok(offset + symbol.value());
+            }
+        }
+    }
+    Err("relocatable kernel doesn't define _start".into())
+}
+
+/// Returns the page table flags for a Load segment with the given flags.
+///
+/// Only writable segments are mapped with the `WRITABLE` flag. If `no_execute`
+/// is set, segments that aren't executable are mapped with the `NO_EXECUTE`
+/// flag.
+fn segment_page_flags(flags: program::Flags, no_execute: bool) -> Flags {
+    let mut segment_flags = Flags::PRESENT;
+    if no_execute && !flags.is_execute() {
+        segment_flags |= Flags::NO_EXECUTE;
+    }
+    if flags.is_write() {
+        segment_flags |= Flags::WRITABLE;
+    }
+    segment_flags
+}
+
+/// Returns the 2 MiB frames that can be used to map the frames
+/// `start_frame..=end_frame` to the pages starting at `start_page`.
+///
+/// Returns `None` if the virtual and physical addresses aren't equally aligned
+/// relative to 2 MiB or if the frames don't contain a complete 2 MiB frame.
+fn huge_frame_range(
+    start_page: Page,
+    start_frame: PhysFrame,
+    end_frame: PhysFrame,
+) -> Option<PhysFrameRange<Size2MiB>> {
+    let virt_start_addr = start_page.start_address().as_u64();
+    let phys_start_addr = start_frame.start_address().as_u64();
+    if (virt_start_addr ^ phys_start_addr) & (Size2MiB::SIZE - 1) != 0 {
+        return None;
+    }
+
+    let start = PhysFrame::containing_address(start_frame.start_address().align_up(Size2MiB::SIZE));
+    let end = PhysFrame::containing_address(end_frame.start_address() + Size4KiB::SIZE);
+    (start < end).then(|| PhysFrame::range(start, end))
+}
+
+/// Returns the raw tag and value of an entry in the dynamic section.
+///
+/// This is used for entries whose tag isn't known to `xmas_elf`.
+fn raw_dynamic_entry(entry: &Dynamic<u64>) -> (u64, u64) {
+    unsafe {
+        // SAFETY: `Dynamic<u64>` is a `repr(C)` struct consisting of the tag
+        // and the value, both of which are 64-bit integers.
+        core::ptr::read_unaligned(entry as *const Dynamic<u64> as *const (u64, u64))
+    }
+}
+
+/// The locations of the dynamic symbol and string tables.
+#[derive(Clone, Copy)]
+struct DynamicSymbols {
+    /// The value of the `SymTab` entry.
+    symbol_table: Option<u64>,
+    /// The values of the `StrTab` and `StrSize` entries.
+    string_table: Option<(u64, u64)>,
+}
+
+/// Decodes the entries of a `Relr` table into the offsets that need to be
+/// relocated.
+///
+/// An even entry is the offset of a word that needs to be relocated. An odd
+/// entry is a bitmap: Bit `n` (counting from the least significant bit after
+/// the marker bit) indicates that the `n`-th word after the last described
+/// word needs to be relocated.
+#[derive(Default)]
+struct RelrDecoder {
+    /// The offset of the word after the last word described by an entry.
+    next: u64,
+}
+
+impl RelrDecoder {
+    /// The number of words described by a bitmap entry.
+    const BITMAP_WORDS: u64 = u64::BITS as u64 - 1;
+
+    /// Returns the offsets described by the next entry of the table.
+    fn decode(&mut self, entry: u64) -> impl Iterator<Item = u64> {
+        let word_size = size_of::<u64>() as u64;
+        let (base, bitmap) = if entry & 1 == 0 {
+            self.next = entry.wrapping_add(word_size);
+            (entry, 1)
+        } else {
+            let base = self.next;
+            self.next = base.wrapping_add(Self::BITMAP_WORDS * word_size);
+            (base, entry >> 1)
+        };
+        (0..Self::BITMAP_WORDS)
+            .filter(move |i| bitmap & (1 << i) != 0)
+            .map(move |i| base.wrapping_add(i * word_size))
+    }
+}
+
+/// The program headers of the kernel that are needed for loading.
+///
+/// The program headers are parsed once by [`Loader::new`], so that all
+/// loading steps agree on the segments they handle.
+///
+/// Load segments aren't recorded, see [`load_segments`].
+struct KernelLayout<'a> {
+    tls: Option<ProgramHeader<'a>>,
+    dynamic: Option<ProgramHeader<'a>>,
+    relro: Option<ProgramHeader<'a>>,
+    gnu_stack: Option<ProgramHeader<'a>>,
+    gnu_property: Option<ProgramHeader<'a>>,
+}
+
+impl<'a> KernelLayout<'a> {
+    fn new() -> Self {
+        Self {
+            tls: None,
+            dynamic: None,
+            relro: None,
+            gnu_stack: None,
+            gnu_property: None,
+        }
+    }
+
+    /// Records a program header of the kernel.
+    ///
+    /// Program headers of other types are ignored.
+    fn add(&mut self, program_header: ProgramHeader<'a>) -> Result<(), LoadKernelError> {
+        let (header, error) = match program_header.get_type()? {
+            Type::Tls => {
+                match self.tls {
+                    // Some linkers emit the same TLS header twice, which is
+                    // harmless.
+                    Some(first) if is_same_header(first, program_header) => {}
+                    Some(first) => {
+                        return Err(LoadKernelError::MultipleTlsSegments {
+                            first_addr: first.virtual_addr(),
+                            first_size: first.mem_size(),
+                            second_addr: program_header.virtual_addr(),
+                            second_size: program_header.mem_size(),
+                        })
+                    }
+                    None => self.tls = Some(program_header),
+                }
+                return Ok(());
+            }
+            // Applying the relocations of multiple dynamic segments could
+            // apply some relocations twice, so we reject such kernels.
+            Type::Dynamic => (&mut self.dynamic, LoadKernelError::MultipleDynamicSegments),
+            Type::GnuRelro => (&mut self.relro, LoadKernelError::MultipleRelroSegments),
+            Type::OsSpecific(PT_GNU_STACK) => {
+                self.gnu_stack.get_or_insert(program_header);
+                return Ok(());
+            }
+            Type::OsSpecific(PT_GNU_PROPERTY) => {
+                self.gnu_property.get_or_insert(program_header);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if header.is_some() {
+            return Err(error);
+        }
+        *header = Some(program_header);
+        Ok(())
+    }
+}
+
+/// Returns the Load segments in the order of their program headers.
+///
+/// The types of the program headers were checked by [`KernelLayout::add`].
+fn load_segments<'a, 'b>(
+    elf_file: &'b ElfFile<'a>,
+) -> impl Iterator<Item = ProgramHeader<'a>> + Clone + 'b {
+    elf_file
+        .program_iter()
+        .filter(|h| matches!(h.get_type(), Ok(Type::Load)))
+}
+
+/// Returns whether the two program headers are identical.
+fn is_same_header(first: ProgramHeader, second: ProgramHeader) -> bool {
+    first.get_type() == second.get_type()
+        && first.flags() == second.flags()
+        && first.offset() == second.offset()
+        && first.virtual_addr() == second.virtual_addr()
+        && first.physical_addr() == second.physical_addr()
+        && first.file_size() == second.file_size()
+        && first.mem_size() == second.mem_size()
+        && first.align() == second.align()
+}
+
+/// Checks that the program header table lies within the ELF file.
+///
+/// `xmas_elf` checks this as well, but its calculation can overflow for
+/// crafted headers.
+fn check_program_header_table(elf_file: &ElfFile) -> Result<(), LoadKernelError> {
+    let pt2 = &elf_file.header.pt2;
+    let headers_end = u64::from(pt2.ph_entry_size())
+        .checked_mul(u64::from(pt2.ph_count()))
+        .and_then(|size| size.checked_add(pt2.ph_offset()));
+    match headers_end {
+        Some(end) if end <= elf_file.input.len() as u64 => Ok(()),
+        _ => Err(LoadKernelError::ProgramHeaderTableOutOfBounds),
+    }
+}
+
+/// Checks that the section header table of a relocatable object file lies
+/// within the ELF file and consists of 64-bit section headers.
+///
+/// `xmas_elf` reads `e_shentsize` bytes as a whole section header and panics
+/// if they are too short.
+fn check_section_header_table(elf_file: &ElfFile) -> Result<(), LoadKernelError> {
+    let pt2 = &elf_file.header.pt2;
+    if pt2.sh_count() == 0 {
+        return Ok(());
+    }
+    let sh_entry_size = pt2.sh_entry_size();
+    if usize::from(sh_entry_size) != size_of::<SectionHeader_<P64>>() {
+        return Err(LoadKernelError::UnsupportedSectionHeaderSize(sh_entry_size));
+    }
+    let headers_end = u64::from(sh_entry_size)
+        .checked_mul(u64::from(pt2.sh_count()))
+        .and_then(|size| size.checked_add(pt2.sh_offset()));
+    match headers_end {
+        Some(end) if end <= elf_file.input.len() as u64 => {}
+        _ => return Err(LoadKernelError::SectionHeaderTableOutOfBounds),
+    }
+    // The section names are read from this section.
+    if pt2.sh_str_index() >= pt2.sh_count() {
+        return Err("section name table index is out of bounds".into());
+    }
+    Ok(())
+}
+
+/// Check that the virtual offset belongs to a load segment.
+fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), LoadKernelError> {
+    match find_load_segment(elf_file, virt_offset)? {
+        Some(_) => Ok(()),
+        None => Err(LoadKernelError::NotInLoadSegment(virt_offset)),
+    }
+}
+
+/// Returns the Load segment that contains the given virtual offset.
+///
+/// Relocations are checked against the Load segments one by one, so this
+/// uses a binary search over the program header table instead of iterating
+/// over all program headers. This relies on the non-empty Load segments
+/// being sorted and disjoint, see [`check_overlapping_segments`].
+fn find_load_segment<'a>(
+    elf_file: &ElfFile<'a>,
+    virt_offset: u64,
+) -> Result<Option<ProgramHeader<'a>>, LoadKernelError> {
+    let ph_count = elf_file.header.pt2.ph_count();
+    // Returns the first non-empty Load segment at or after the given index
+    // of the program header table.
+    let next_segment = |mut idx: u16| -> Result<Option<ProgramHeader<'a>>, LoadKernelError> {
+        while idx < ph_count {
+            let program_header = elf_file.program_header(idx)?;
+            if program_header.get_type()? == Type::Load && program_header.mem_size() > 0 {
+                return Ok(Some(program_header));
+            }
+            idx += 1;
+        }
+        Ok(None)
+    };
+
+    // Find the first index whose next segment starts after the offset. The
+    // segment at the index before is the last one that starts at or before
+    // the offset.
+    let (mut low, mut high) = (0, ph_count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match next_segment(mid)? {
+            Some(segment) if segment.virtual_addr() <= virt_offset => low = mid + 1,
+            _ => high = mid,
+        }
+    }
+    let Some(segment) = low.checked_sub(1).map(next_segment).transpose()?.flatten() else {
+        return Ok(None);
+    };
+    Ok((virt_offset - segment.virtual_addr() < segment.mem_size()).then_some(segment))
+}
+
+/// Loads the kernel ELF file given in `bytes` in the given `page_table`.
+///
+/// The virtual memory used by the kernel is marked as used in `used_entries`,
+/// which stays usable for reserving further regions afterwards. The segments
+/// are loaded with the given `callbacks`, see [`Loader::load_segments_with`].
+pub fn load_kernel(
+    kernel: Kernel<'_>,
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    callbacks: LoadCallbacks<'_>,
+) -> Result<LoadedKernel, LoadKernelError> {
+    let mut loader = Loader::new(
+        kernel,
+        page_table,
+        frame_allocator,
+        used_entries,
+        LoaderOptions::new_default(),
+    )?;
+    let tls_template = loader.load_segments_with(callbacks)?;
+    loader.apply_relocations()?;
+    loader.loaded_kernel(tls_template)
+}
+
+/// Like [`load_kernel`], but calls `verify` with the whole kernel ELF file
+/// before it is parsed, e.g. to check a signature.
+///
+/// If `verify` returns an error, loading is aborted before anything is mapped
+/// or allocated.
+pub fn load_kernel_verified<V>(
+    kernel: Kernel<'_>,
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    callbacks: LoadCallbacks<'_>,
+    verify: V,
+) -> Result<LoadedKernel, LoadKernelError>
+where
+    V: Fn(&[u8]) -> Result<(), &'static str>,
+{
+    verify(kernel.elf.input).map_err(LoadKernelError::VerificationFailed)?;
+    load_kernel(kernel, page_table, frame_allocator, used_entries, callbacks)
+}
+
+/// Like [`load_kernel`], but reads the kernel from a [`KernelSource`], see
+/// [`Loader::from_source`].
+pub fn load_kernel_from_source(
+    source: &mut dyn KernelSource,
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    callbacks: LoadCallbacks<'_>,
+) -> Result<LoadedKernel, LoadKernelError> {
+    let mut loader = Loader::from_source(
+        source,
+        page_table,
+        frame_allocator,
+        used_entries,
+        LoaderOptions::new_default(),
+    )?;
+    let tls_template = loader.load_segments_with(callbacks)?;
+    loader.apply_relocations()?;
+    loader.loaded_kernel(tls_template)
+}
+
+/// Creates a new level 4 page table and loads the kernel into it.
+///
+/// The frame of the level 4 table is allocated from `frame_allocator` and
+/// accessed through the physical memory mapping at
+/// [`LoaderOptions::physical_memory_offset`], like all other frames of the
+/// kernel. The new page table isn't activated.
+///
+/// Returns the frame of the level 4 table, which can be loaded into the `CR3`
+/// register, together with the loaded kernel.
+///
+/// ## Safety
+///
+/// The complete physical memory must be mapped at
+/// [`LoaderOptions::physical_memory_offset`] in the current address space.
+pub unsafe fn load_kernel_into_new_page_table(
+    kernel: Kernel<'_>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    options: LoaderOptions,
+) -> Result<(PhysFrame, LoadedKernel), LoadKernelError> {
+    let level_4_frame = frame_allocator
+        .allocate_frame()
+        .ok_or(LoadKernelError::OutOfFrames)?;
+    let table = phys_to_ptr(
+        options.physical_memory_offset,
+        level_4_frame.start_address(),
+    ) as *mut PageTable;
+    let mut page_table = unsafe {
+        // SAFETY: The frame was just allocated, so it's unused, and the
+        // caller guarantees that it's accessible through the physical memory
+        // mapping.
+        table.write(PageTable::new());
+        OffsetPageTable::new(&mut *table, VirtAddr::new(options.physical_memory_offset))
+    };
+
+    let mut loader = Loader::new(
+        kernel,
+        &mut page_table,
+        frame_allocator,
+        used_entries,
+        options,
+    )?;
+    let tls_template = loader.load_segments()?;
+    loader.apply_relocations()?;
+    let kernel = loader.loaded_kernel(tls_template)?;
+    Ok((level_4_frame, kernel))
+}
+
+/// Returns a [`LoadCallbacks::map_flags`] function that adds the given page
+/// table flags to the Load segments overlapping the given virtual address
+/// ranges, e.g. to map a segment as uncacheable.
+///
+/// The ranges are compared with the virtual addresses in the ELF file, i.e.
+/// before the virtual address offset of position independent kernels is
+/// added. The flags apply to whole segments, including their zeroed memory.
+///
+/// The page table isn't active while loading, so `NO_CACHE` and
+/// `WRITE_THROUGH` only take effect once it is. The loader writes the
+/// segments through the cacheable physical memory mapping, so caches may
+/// need to be flushed before the kernel relies on uncached accesses. The
+/// `HUGE_PAGE` bit selects the PAT entry for 4 KiB pages, so it must not be
+/// set. `BIT_9` is reserved for the loader and removed again.
+pub fn range_flags(ranges: &[(Range<u64>, Flags)]) -> impl Fn(&ProgramHeader, Flags) -> Flags + '_ {
+    move |segment, mut flags| {
+        let start = segment.virtual_addr();
+        let end = start.saturating_add(segment.mem_size());
+        for (range, extra) in ranges {
+            if range.start < end && start < range.end {
+                flags |= *extra;
+            }
+        }
+        flags
+    }
+}
+
+/// Calculates how many frames [`load_kernel`] allocates for the given kernel
+/// without mapping or allocating anything.
+///
+/// This makes it possible to check that the frame allocator has enough frames
+/// before starting to load the kernel. The numbers of frames needed for
+/// relocations and page tables are upper bounds.
+pub fn required_frames(kernel: &Kernel<'_>) -> Result<RequiredFrames, LoadKernelError> {
+    let elf_file = &kernel.elf;
+    check_program_header_table(elf_file)?;
+    if is_relocatable(elf_file) {
+        check_section_header_table(elf_file)?;
+    }
+    header::sanity_check(elf_file)?;
+
+    let mut required = RequiredFrames::default();
+
+    if is_relocatable(elf_file) {
+        // Every page of an allocated section is backed by a new frame.
+        let (size, _) = relocatable_layout(elf_file)?;
+        for (_, section, _) in relocatable_sections(elf_file) {
+            required.memory += section.size().div_ceil(Size4KiB::SIZE);
+        }
+        required.page_tables = page_table_frames(size);
+        return Ok(required);
+    }
+
+    for segment in elf_file.program_iter() {
+        check_segment_bounds(segment, elf_file.input.len() as u64)?;
+        if segment.get_type()? != Type::Load || segment.mem_size() == 0 {
+            continue;
+        }
+        program::sanity_check(segment, elf_file)?;
+        required.page_tables += page_table_frames(segment.mem_size());
+
+        // See `Inner::handle_bss_section`. We count pages instead of
+        // addresses, so that segments at the end of the address space don't
+        // overflow.
+        if segment.mem_size() > segment.file_size() {
+            let virtual_addr = segment.virtual_addr();
+            let zero_start = virtual_addr + segment.file_size();
+            let zero_end = virtual_addr
+                .checked_add(segment.mem_size())
+                .ok_or(LoadKernelError::InvalidSegmentSize(virtual_addr))?;
+            let start_page = if segment.file_size() == 0 {
+                zero_start / Size4KiB::SIZE
+            } else {
+                if zero_start % Size4KiB::SIZE != 0 {
+                    // The last data frame is copied before zeroing its bss part.
+                    required.memory += 1;
+                }
+                zero_start.div_ceil(Size4KiB::SIZE)
+            };
+            let end_page = zero_end.div_ceil(Size4KiB::SIZE);
+            required.memory += end_page.saturating_sub(start_page);
+        }
+    }
+
+    // Each page that is modified by a relocation is copied once. Relocations
+    // are usually sorted by their offset, so we only count the pages in which
+    // consecutive relocations differ.
+    let mut last_page = None;
+    for_each_relocation_target(elf_file, |offset| {
+        let end = offset
+            .checked_add(size_of::<u64>() as u64 - 1)
+            .ok_or(LoadKernelError::NotInLoadSegment(offset))?;
+        for page in offset / Size4KiB::SIZE..=end / Size4KiB::SIZE {
+            if last_page != Some(page) {
+                required.relocations += 1;
+                last_page = Some(page);
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(required)
+}
+
+/// The number of frames that [`load_kernel`] allocates, as calculated by
+/// [`required_frames`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RequiredFrames {
+    /// Frames for zero-initialized memory, including copies of frames that
+    /// contain both data and zero-initialized memory. For relocatable object
+    /// files, this includes all frames of the loaded sections.
+    pub memory: u64,
+    /// Frames for copies of the pages modified by relocations.
+    pub relocations: u64,
+    /// Frames for the page tables that map the kernel.
+    pub page_tables: u64,
+}
+
+impl RequiredFrames {
+    /// Returns the total number of required frames.
+    pub fn total(&self) -> u64 {
+        self.memory + self.relocations + self.page_tables
+    }
+}
+
+/// Returns an upper bound for the number of page table frames needed to map
+/// a memory region of the given size at an arbitrary page-aligned address.
+///
+/// Level 1 tables are counted for the whole region even if parts of it are
+/// mapped with 2 MiB pages, so the bound also covers the tables allocated by
+/// [`Inner::split_huge_page`] within the region.
+fn page_table_frames(size: u64) -> u64 {
+    // Level 1, 2 and 3 tables cover 2 MiB, 1 GiB and 512 GiB respectively. The
+    // region might straddle a boundary, so we need one more table per level.
+    [
+        Size2MiB::SIZE,
+        Size2MiB::SIZE * 512,
+        Size2MiB::SIZE * 512 * 512,
+    ]
+    .into_iter()
+    .map(|table_size| size.div_ceil(table_size) + 1)
+    .sum()
+}
+
+/// Calls `f` with the offset of each relocation in the dynamic section,
+/// reading the relocation tables from the ELF file.
+///
+/// Stops at the first error returned by `f`.
+fn for_each_relocation_target(
+    elf_file: &ElfFile,
+    mut f: impl FnMut(u64) -> Result<(), LoadKernelError>,
+) -> Result<(), LoadKernelError> {
+    for program_header in elf_file.program_iter() {
+        if program_header.get_type()? != Type::Dynamic {
+            continue;
+        }
+        // The entries are validated when the relocations are applied, so we
+        // only collect the tables here.
+        let mut rela = None;
+        let mut rela_size = 0;
+        let mut rel = None;
+        let mut rel_size = 0;
+        let mut relr = None;
+        let mut relr_size = 0;
+        let mut jmp_rel = None;
+        let mut plt_rel_size = 0;
+        let mut plt_rel = None;
+        // Like `Inner::handle_dynamic_segment`, this ignores a trailing
+        // partial entry.
+        let entry_size = size_of::<Dynamic<u64>>() as u64;
+        for idx in 0..program_header.file_size() / entry_size {
+            let offset = table_entry(program_header.virtual_addr(), idx, entry_size)?;
+            let raw = [
+                read_file_u64(elf_file, offset)?,
+                read_file_u64(elf_file, table_entry(offset, 1, 8)?)?,
+            ];
+            let entry = &unsafe {
+                // SAFETY: `Dynamic<u64>` is a `repr(C)` struct consisting of
+                // the tag and the value, both of which are 64-bit integers.
+                core::ptr::read_unaligned(raw.as_ptr() as *const Dynamic<u64>)
+            };
+            match entry.get_tag() {
+                Ok(dynamic::Tag::Rela) => rela = Some(entry.get_ptr()?),
+                Ok(dynamic::Tag::RelaSize) => rela_size = entry.get_val()?,
+                Ok(dynamic::Tag::Rel) => rel = Some(entry.get_ptr()?),
+                Ok(dynamic::Tag::RelSize) => rel_size = entry.get_val()?,
+                Ok(dynamic::Tag::JmpRel) => jmp_rel = Some(entry.get_ptr()?),
+                Ok(dynamic::Tag::PltRelSize) => plt_rel_size = entry.get_val()?,
+                Ok(dynamic::Tag::PltRel) => plt_rel = Some(entry.get_val()?),
+                Ok(_) => {}
+                Err(_) => match raw_dynamic_entry(entry) {
+                    (DT_RELR, val) => relr = Some(val),
+                    (DT_RELRSZ, val) => relr_size = val,
+                    _ => {}
+                },
+            }
+        }
+
+        let plt_rel_ent = match plt_rel {
+            Some(DT_REL) => size_of::<Rel<u64>>() as u64,
+            _ => size_of::<Rela<u64>>() as u64,
+        };
+        let tables = [
+            (rela, rela_size, size_of::<Rela<u64>>() as u64),
+            (rel, rel_size, size_of::<Rel<u64>>() as u64),
+            (jmp_rel, plt_rel_size, plt_rel_ent),
+        ];
+        for (table, total_size, entry_size) in tables {
+            let Some(table) = table else { continue };
+            for idx in 0..total_size / entry_size {
+                // The offset is the first field of both `Rel` and `Rela`.
+                f(read_file_u64(
+                    elf_file,
+                    table_entry(table, idx, entry_size)?,
+                )?)?;
+            }
+        }
+
+        if let Some(table) = relr {
+            let mut decoder = RelrDecoder::default();
+            for idx in 0..relr_size / size_of::<u64>() as u64 {
+                let entry =
+                    read_file_u64(elf_file, table_entry(table, idx, size_of::<u64>() as u64)?)?;
+                decoder.decode(entry).try_for_each(&mut f)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collects the smallest relocation targets that modify a byte in the given
+/// range into `targets`, sorted in ascending order.
+///
+/// Returns the number of collected targets. If `targets` is full, there
+/// might be further targets that are at least as large as its last element.
+fn collect_relocation_targets(
+    elf_file: &ElfFile,
+    range: Range<u64>,
+    targets: &mut [u64],
+) -> Result<usize, LoadKernelError> {
+    let mut len = 0;
+    for_each_relocation_target(elf_file, |target| {
+        if target.saturating_add(size_of::<u64>() as u64) <= range.start || target >= range.end {
+            return Ok(());
+        }
+        if len == targets.len() {
+            // Relocations are usually sorted, so most targets are skipped
+            // here once the batch is full.
+            if targets.last().is_none_or(|&last| target >= last) {
+                return Ok(());
+            }
+            len -= 1;
+        }
+        let idx = targets[..len].partition_point(|&other| other <= target);
+        targets.copy_within(idx..len, idx + 1);
+        targets[idx] = target;
+        len += 1;
+        Ok(())
+    })?;
+    Ok(len)
+}
+
+/// Returns the virtual offset of the entry with the given index in a table.
+fn table_entry(table: u64, idx: u64, entry_size: u64) -> Result<u64, LoadKernelError> {
+    idx.checked_mul(entry_size)
+        .and_then(|offset| table.checked_add(offset))
+        .ok_or(LoadKernelError::TableOutOfBounds(table))
+}
+
+/// Reads a `u64` at the given virtual offset from the file contents of a
+/// Load segment.
+fn read_file_u64(elf_file: &ElfFile, virt_offset: u64) -> Result<u64, LoadKernelError> {
+    let out_of_bounds = LoadKernelError::TableOutOfBounds(virt_offset);
+    for program_header in elf_file.program_iter() {
+        if let Type::Load = program_header.get_type()? {
+            let start = program_header.virtual_addr();
+            let end = start
+                .checked_add(program_header.file_size())
+                .ok_or(LoadKernelError::InvalidSegmentSize(start))?;
+            if start <= virt_offset && virt_offset.saturating_add(8) <= end {
+                let offset = program_header
+                    .offset()
+                    .checked_add(virt_offset - start)
+                    .ok_or(out_of_bounds)?;
+                let bytes = usize::try_from(offset)
+                    .ok()
+                    .and_then(|offset| elf_file.input.get(offset..offset.checked_add(8)?))
+                    .ok_or(out_of_bounds)?;
+                return Ok(u64::from_le_bytes(bytes.try_into().unwrap()));
+            }
+        }
+    }
+    Err(out_of_bounds)
+}
+
+/// An error that can occur while loading the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadKernelError {
+    /// The kernel ELF file is malformed or uses features that aren't
+    /// supported by `xmas_elf`.
+    InvalidElf(&'static str),
+    /// The kernel ELF file isn't loaded at a page-aligned address, see
+    /// [`Loader::from_unaligned`].
+    MisalignedElf,
+    /// The kernel ELF file isn't a 64-bit ELF file.
+    UnsupportedClass(header::Class),
+    /// The kernel ELF file isn't little-endian.
+    UnsupportedDataEncoding(header::Data),
+    /// The kernel ELF file wasn't compiled for x86_64.
+    UnsupportedMachine(header::Machine),
+    /// The program header table of the kernel ELF file extends beyond the
+    /// end of the file.
+    ProgramHeaderTableOutOfBounds,
+    /// The program headers of the kernel ELF file have the given size, which
+    /// doesn't match the size of 64-bit program headers.
+    UnsupportedProgramHeaderSize(u16),
+    /// The section header table of a relocatable kernel extends beyond the
+    /// end of the file.
+    SectionHeaderTableOutOfBounds,
+    /// The section headers of a relocatable kernel have the given size, which
+    /// doesn't match the size of 64-bit section headers.
+    UnsupportedSectionHeaderSize(u16),
+    /// The kernel contains more than one TLS segment. The virtual addresses
+    /// and memory sizes of the first two distinct segments are given.
+    MultipleTlsSegments {
+        first_addr: u64,
+        first_size: u64,
+        second_addr: u64,
+        second_size: u64,
+    },
+    /// The kernel contains more than one dynamic segment.
+    MultipleDynamicSegments,
+    /// The kernel contains more than one `GNU_RELRO` segment.
+    MultipleRelroSegments,
+    /// The `GNU_RELRO` segment at the given virtual address isn't part of a
+    /// Load segment.
+    InvalidRelroSegment(u64),
+    /// The given [`LoaderOptions::kaslr_offset`] isn't 2 MiB aligned or moves
+    /// the kernel outside the address space.
+    InvalidKaslrOffset(u64),
+    /// The Load segment at the given virtual address is both writable and
+    /// executable, which was forbidden by
+    /// [`LoaderOptions::enforce_write_xor_execute`].
+    WritableAndExecutable(u64),
+    /// The entry point at the given address isn't part of an executable Load
+    /// segment or isn't a canonical address after relocation.
+    InvalidEntryPoint(u64),
+    /// The frame allocator ran out of frames.
+    OutOfFrames,
+    /// The frame allocator returned the given frame, which doesn't follow
+    /// the previous one, while copying an unaligned ELF file, see
+    /// [`Loader::from_unaligned`].
+    NonConsecutiveFrames(PhysFrame),
+    /// Mapping a page of a Load segment failed.
+    MapSegmentFailed(Page),
+    /// Mapping a new page for bss memory failed.
+    MapBssFailed(Page),
+    /// The given kernel stack start address isn't page-aligned or leaves no
+    /// room for a guard page.
+    MisalignedStack(u64),
+    /// Mapping a page of the kernel stack failed.
+    MapStackFailed(Page),
+    /// The ramdisk passed to [`Loader::map_ramdisk`] can't be mapped.
+    InvalidRamdisk(&'static str),
+    /// The given ramdisk start address isn't page-aligned.
+    MisalignedRamdisk(u64),
+    /// Mapping a page of a ramdisk failed.
+    MapRamdiskFailed(Page),
+    /// Unmapping a page of a Load segment failed.
+    UnmapFailed(Page),
+    /// The dynamic section contains an entry with the given tag more than
+    /// once.
+    DuplicateDynamicEntry(&'static str),
+    /// The dynamic section doesn't contain a required entry with the given
+    /// tag.
+    MissingDynamicEntry(&'static str),
+    /// The dynamic section doesn't contain a relocation table entry with the
+    /// given tag, but it contains the matching size entries.
+    UnexpectedDynamicEntries(&'static str),
+    /// The given `RelaCount` entry exceeds the number of leading
+    /// `R_AMD64_RELATIVE` relocations in the `Rela` table.
+    InvalidRelaCount(u64),
+    /// The entry with the given tag specifies an entry size that doesn't
+    /// match the size of the entries the loader reads.
+    UnsupportedEntrySize {
+        tag: &'static str,
+        entry_size: u64,
+        expected: usize,
+    },
+    /// The `PltRel` entry of the dynamic section has an invalid value.
+    InvalidPltRel(u64),
+    /// The relocation at the given offset has an unsupported type.
+    UnsupportedRelocationType { ty: u32, offset: u64 },
+    /// The relocation at the given offset uses the symbol table, which is not
+    /// supported for its type.
+    UnsupportedSymbolRelocation { ty: u32, offset: u64 },
+    /// A relocation references the undefined symbol with the given index and
+    /// name (if known). Undefined weak symbols resolve to zero instead.
+    UndefinedSymbol { idx: u32, name: Option<SymbolName> },
+    /// The relocated value of the relocation at the given offset is outside
+    /// the address space.
+    RelocationOverflow(u64),
+    /// The given offset isn't part of a Load segment.
+    NotInLoadSegment(u64),
+    /// The relocation at the given offset targets neither a writable Load
+    /// segment nor a `PT_GNU_RELRO` segment.
+    ReadOnlyRelocationTarget(u64),
+    /// The page at the given virtual address isn't mapped, or isn't mapped to
+    /// a frame of the kernel's [`PhysicalFootprint`].
+    InconsistentMapping(u64),
+    /// The page at the given virtual address is mapped with the `ACCESSED`
+    /// or `DIRTY` flag, which [`LoaderOptions::clear_accessed_dirty`]
+    /// forbids.
+    AccessedOrDirty(u64),
+    /// The Load segments at the given virtual addresses are mapped to the
+    /// same page.
+    OverlappingSegments { first: u64, second: u64 },
+    /// The Load segment at `second` follows the one at `first` in the program
+    /// header table, but has a lower virtual address. The ELF specification
+    /// requires Load segments to be sorted by their virtual address.
+    UnsortedSegments { first: u64, second: u64 },
+    /// The Load segment at the given virtual address doesn't satisfy its
+    /// alignment.
+    MisalignedSegment { addr: u64, align: u64 },
+    /// The physical frames used by the kernel are too fragmented to be
+    /// recorded in a [`PhysicalFootprint`].
+    FootprintTooFragmented,
+    /// The file contents of the segment at the given virtual address extend
+    /// past the end of the kernel ELF file.
+    SegmentOutOfBounds(u64),
+    /// The file size of the Load or TLS segment at the given virtual address
+    /// is larger than its memory size or its memory size is too large.
+    InvalidSegmentSize(u64),
+    /// The table at the given offset referenced by the dynamic section
+    /// isn't completely contained in the file contents of a Load segment.
+    TableOutOfBounds(u64),
+    /// Reading the kernel from a [`KernelSource`] failed.
+    ReadFailed(&'static str),
+    /// The kernel requests the dynamic interpreter at the given path, which
+    /// can't be provided. The kernel must be linked with `-static-pie` or
+    /// `-no-pie` instead.
+    DynamicInterpreter(SymbolName),
+    /// The virtual addresses of the segment at the given virtual address
+    /// overflow or leave the canonical address space, e.g. because the ELF
+    /// file is corrupted.
+    AddressOverflow(u64),
+    /// The loaded memory at the given virtual address doesn't match the ELF
+    /// file, see [`Loader::verify_segments`].
+    LoadedDataMismatch(u64),
+    /// The frame at the given physical address exceeds the configured
+    /// [`LoaderOptions::max_physical_address`].
+    FrameAboveLimit(u64),
+    /// The kernel consists of more than [`KernelMemoryMap::MAX_REGIONS`]
+    /// regions with different flags.
+    MemoryMapTooFragmented,
+    /// The frame allocator returned the given frame, which contains part of
+    /// the ELF file.
+    FrameInKernelFile(PhysFrame),
+    /// The configured base address is not page aligned or the kernel doesn't
+    /// fit into the address space at it.
+    InvalidBaseAddress(u64),
+    /// The Load segment at the given virtual address can't be mapped to the
+    /// physical address of its contents, which was required by
+    /// [`LoaderOptions::identity_map`].
+    IdentityMappingImpossible(u64),
+    /// The relocation at the given offset refers to the TLS segment, but
+    /// the kernel has none.
+    MissingTlsSegment(u64),
+    /// The kernel contains an `R_AMD64_IRELATIVE` relocation at the given
+    /// offset, but no [`LoaderOptions::ifunc_resolver`] was configured.
+    MissingIfuncResolver(u64),
+    /// The kernel declares at least `count` relocations, more than the
+    /// `max` allowed by [`LoaderOptions::max_relocations`].
+    TooManyRelocations { count: u64, max: u64 },
+    /// The kernel was rejected by the verifier passed to
+    /// [`load_kernel_verified`].
+    VerificationFailed(&'static str),
+    /// The kernel ELF file has the given OS/ABI identification and ABI
+    /// version, which don't match [`LoaderOptions::expected_os_abi`] or
+    /// [`LoaderOptions::expected_abi_version`].
+    UnexpectedAbi { os_abi: u8, abi_version: u8 },
+}
+
+impl From<&'static str> for LoadKernelError {
+    fn from(err: &'static str) -> Self {
+        Self::InvalidElf(err)
+    }
+}
+
+impl fmt::Display for LoadKernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidElf(err) => write!(f, "invalid kernel ELF file: {err}"),
+            Self::MisalignedElf => write!(f, "Loaded kernel ELF file is not sufficiently aligned"),
+            Self::UnsupportedClass(class) => {
+                write!(f, "kernel ELF file has unsupported class {class:?}")
+            }
+            Self::UnsupportedDataEncoding(data) => {
+                write!(f, "kernel ELF file has unsupported data encoding {data:?}")
+            }
+            Self::UnsupportedMachine(machine) => write!(
+                f,
+                "kernel ELF file has unsupported machine type {machine:?}, expected x86_64"
+            ),
+            Self::ProgramHeaderTableOutOfBounds => {
+                write!(f, "program header table is out of bounds")
+            }
+            Self::UnsupportedProgramHeaderSize(size) => write!(
+                f,
+                "program headers have a size of {size} bytes, expected {}",
+                size_of::<program::ProgramHeader64>()
+            ),
+            Self::SectionHeaderTableOutOfBounds => {
+                write!(f, "section header table is out of bounds")
+            }
+            Self::UnsupportedSectionHeaderSize(size) => write!(
+                f,
+                "section headers have a size of {size} bytes, expected {}",
+                size_of::<SectionHeader_<P64>>()
+            ),
+            Self::MultipleTlsSegments {
+                first_addr,
+                first_size,
+                second_addr,
+                second_size,
+            } => write!(
+                f,
+                "multiple TLS segments not supported: {first_size:#x} bytes at \
+                 {first_addr:#x} and {second_size:#x} bytes at {second_addr:#x}"
+            ),
+            Self::MultipleDynamicSegments => write!(f, "multiple dynamic segments not supported"),
+            Self::MultipleRelroSegments => write!(f, "multiple GNU_RELRO segments not supported"),
+            Self::InvalidRelroSegment(addr) => write!(
+                f,
+                "GNU_RELRO segment at {addr:#x} is not part of a Load segment"
+            ),
+            Self::InvalidKaslrOffset(offset) => write!(f, "invalid KASLR offset: {offset:#x}"),
+            Self::WritableAndExecutable(addr) => write!(
+                f,
+                "Load segment at {addr:#x} is both writable and executable"
+            ),
+            Self::InvalidEntryPoint(addr) => write!(
+                f,
+                "entry point {addr:#x} is not in an executable Load segment"
+            ),
+            Self::OutOfFrames => write!(f, "out of frames while mapping kernel memory"),
+            Self::NonConsecutiveFrames(frame) => write!(
+                f,
+                "frame {:#x} doesn't follow the previous frame of the copied ELF file",
+                frame.start_address()
+            ),
+            Self::MapSegmentFailed(page) => write!(f, "map_to failed for {page:?}"),
+            Self::MapBssFailed(page) => {
+                write!(f, "Failed to map new frame for bss memory at {page:?}")
+            }
+            Self::MisalignedStack(addr) => write!(
+                f,
+                "kernel stack start address must be page-aligned (is `{addr:#x}`)"
+            ),
+            Self::MapStackFailed(page) => {
+                write!(
+                    f,
+                    "Failed to map new frame for the kernel stack at {page:?}"
+                )
+            }
+            Self::InvalidRamdisk(err) => write!(f, "invalid ramdisk: {err}"),
+            Self::MisalignedRamdisk(addr) => write!(
+                f,
+                "ramdisk start address must be page-aligned (is `{addr:#x}`)"
+            ),
+            Self::MapRamdiskFailed(page) => write!(f, "Failed to map the ramdisk at {page:?}"),
+            Self::UnmapFailed(page) => write!(f, "unmap failed for {page:?}"),
+            Self::DuplicateDynamicEntry(tag) => {
+                write!(f, "Dynamic section contains more than one {tag} entry")
+            }
+            Self::MissingDynamicEntry(tag) => write!(f, "{tag} entry is missing"),
+            Self::UnsupportedEntrySize {
+                tag,
+                entry_size,
+                expected,
+            } => write!(
+                f,
+                "{tag} entry specifies an entry size of {entry_size} bytes, expected {expected}"
+            ),
+            Self::UnexpectedDynamicEntries(tag) => write!(
+                f,
+                "{tag} entry is missing but the matching size entries have been provided"
+            ),
+            Self::InvalidRelaCount(count) => write!(
+                f,
+                "RelaCount entry {count} doesn't match the number of relative relocations"
+            ),
+            Self::InvalidPltRel(value) => write!(f, "PltRel entry has an invalid value: {value}"),
+            Self::UnsupportedRelocationType { ty, offset } => write!(
+                f,
+                "relocation type {ty:#x} at offset {offset:#x} not supported"
+            ),
+            Self::UnsupportedSymbolRelocation { ty, offset } => write!(
+                f,
+                "relocation type {ty:#x} at offset {offset:#x} using the symbol table not supported"
+            ),
+            Self::UndefinedSymbol {
+                idx,
+                name: Some(name),
+            } => write!(
+                f,
+                "relocation references the undefined symbol {idx} (`{name}`)"
+            ),
+            Self::UndefinedSymbol { idx, name: None } => {
+                write!(f, "relocation references the undefined symbol {idx}")
+            }
+            Self::RelocationOverflow(offset) => write!(
+                f,
+                "relocated value at offset {offset:#x} is outside the address space"
+            ),
+            Self::NotInLoadSegment(offset) => {
+                write!(f, "offset {offset:#x} is not in load segment")
+            }
+            Self::ReadOnlyRelocationTarget(offset) => {
+                write!(f, "relocation at {offset:#x} targets read-only memory")
+            }
+            Self::InconsistentMapping(addr) => write!(
+                f,
+                "page at {addr:#x} isn't mapped to a frame used by the kernel"
+            ),
+            Self::AccessedOrDirty(addr) => {
+                write!(f, "page at {addr:#x} is mapped as accessed or dirty")
+            }
+            Self::OverlappingSegments { first, second } => write!(
+                f,
+                "Load segments at {first:#x} and {second:#x} overlap in virtual memory"
+            ),
+            Self::UnsortedSegments { first, second } => write!(
+                f,
+                "Load segment at {second:#x} follows the one at {first:#x} in the program headers"
+            ),
+            Self::MisalignedSegment { addr, align } => write!(
+                f,
+                "Load segment at {addr:#x} doesn't satisfy its alignment of {align:#x}"
+            ),
+            Self::FootprintTooFragmented => write!(
+                f,
+                "physical frames used by the kernel span more than {} ranges",
+                PhysicalFootprint::MAX_RANGES
+            ),
+            Self::SegmentOutOfBounds(addr) => write!(
+                f,
+                "segment at {addr:#x} extends past the end of the kernel ELF file"
+            ),
+            Self::InvalidSegmentSize(addr) => {
+                write!(f, "segment at {addr:#x} has an invalid size")
+            }
+            Self::TableOutOfBounds(offset) => write!(
+                f,
+                "table at offset {offset:#x} is not contained in a Load segment"
+            ),
+            Self::ReadFailed(err) => write!(f, "failed to read the kernel: {err}"),
+            Self::DynamicInterpreter(path) => write!(
+                f,
+                "kernel expects the dynamic interpreter `{path}`, which is not supported; \
+                 link it with `-static-pie` or `-no-pie`"
+            ),
+            Self::AddressOverflow(addr) => write!(
+                f,
+                "addresses of the segment at {addr:#x} overflow the address space \
+                 or are non-canonical"
+            ),
+            Self::LoadedDataMismatch(addr) => {
+                write!(f, "loaded memory at {addr:#x} doesn't match the ELF file")
+            }
+            Self::FrameAboveLimit(addr) => {
+                write!(f, "frame at {addr:#x} exceeds the maximum physical address")
+            }
+            Self::MemoryMapTooFragmented => write!(
+                f,
+                "kernel memory map consists of more than {} regions",
+                KernelMemoryMap::MAX_REGIONS
+            ),
+            Self::FrameInKernelFile(frame) => write!(
+                f,
+                "frame allocator returned the frame at {:#x}, which belongs to the kernel ELF file",
+                frame.start_address()
+            ),
+            Self::InvalidBaseAddress(addr) => {
+                write!(f, "kernel can't be loaded at the base address {addr:#x}")
+            }
+            Self::IdentityMappingImpossible(addr) => write!(
+                f,
+                "Load segment at {addr:#x} can't be mapped at its physical address"
+            ),
+            Self::MissingTlsSegment(offset) => write!(
+                f,
+                "TLS relocation at offset {offset:#x} requires a TLS segment"
+            ),
+            Self::MissingIfuncResolver(offset) => write!(
+                f,
+                "IRELATIVE relocation at offset {offset:#x} requires an ifunc resolver"
+            ),
+            Self::TooManyRelocations { count, max } => write!(
+                f,
+                "kernel declares {count} relocations, but at most {max} are allowed"
+            ),
+            Self::VerificationFailed(err) => write!(f, "kernel verification failed: {err}"),
+            Self::UnexpectedAbi {
+                os_abi,
+                abi_version,
+            } => write!(
+                f,
+                "kernel ELF file has unexpected OS/ABI {os_abi:#x} with ABI version {abi_version}"
+            ),
+        }
+    }
+}
+
+/// Information about a kernel loaded by [`load_kernel`] and its variants.
+///
+/// The virtual memory used by the kernel is recorded in the
+/// [`UsedLevel4Entries`] passed to the loader instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LoadedKernel {
+    /// The offset that was added to the virtual addresses of the kernel,
+    /// which is zero for kernels that aren't position independent.
+    pub virtual_base_offset: VirtAddr,
+    /// The address of the kernel entry point.
+    pub entry_point: VirtAddr,
+    /// Whether the kernel is position independent and was relocated, see
+    /// [`Loader::is_position_independent`].
+    pub position_independent: bool,
+    /// The x86 features requested by the kernel, see
+    /// [`Loader::x86_features`].
+    pub x86_features: X86Features,
+    /// The lowest virtual address used by the kernel, see
+    /// [`Loader::virtual_range`].
+    pub virtual_start: VirtAddr,
+    /// The highest virtual address used by the kernel, including its zeroed
+    /// memory, see [`Loader::virtual_range`].
+    pub virtual_end_inclusive: VirtAddr,
+    /// The thread local storage template of the kernel (if any).
+    pub tls_template: Option<TlsTemplate>,
+    /// The physical frames used by the kernel.
+    pub physical_footprint: PhysicalFootprint,
+    /// The virtual memory regions of the kernel.
+    pub memory_map: KernelMemoryMap,
+}
+
+/// The x86 features requested by the `GNU_PROPERTY_X86_FEATURE_1_AND`
+/// property of a kernel, see [`Loader::x86_features`].
+///
+/// The linker only sets a feature if all input objects support it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct X86Features(u32);
+
+impl X86Features {
+    /// The bit of indirect branch tracking (`GNU_PROPERTY_X86_FEATURE_1_IBT`).
+    pub const IBT: u32 = 1 << 0;
+    /// The bit of shadow stacks (`GNU_PROPERTY_X86_FEATURE_1_SHSTK`).
+    pub const SHSTK: u32 = 1 << 1;
+
+    /// Returns the raw property bits, including unknown features.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether the kernel supports indirect branch tracking, i.e.
+    /// all indirect branch targets start with `endbr64`.
+    pub fn ibt(self) -> bool {
+        self.0 & Self::IBT != 0
+    }
+
+    /// Returns whether the kernel supports running with shadow stacks.
+    pub fn shadow_stack(self) -> bool {
+        self.0 & Self::SHSTK != 0
+    }
+}
+
+/// The physical frames used by the loaded kernel.
+///
+/// Adjacent and overlapping frame ranges are merged, so that the frames fit
+/// into a small number of ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalFootprint {
+    ranges: [PhysFrameRange; PhysicalFootprint::MAX_RANGES],
+    len: usize,
+}
+
+impl PhysicalFootprint {
+    /// The maximum number of distinct frame ranges.
+    pub const MAX_RANGES: usize = 32;
+
+    fn new() -> Self {
+        let frame = PhysFrame::containing_address(PhysAddr::zero());
+        Self {
+            ranges: [PhysFrame::range(frame, frame); Self::MAX_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Returns the recorded frame ranges.
+    pub fn ranges(&self) -> &[PhysFrameRange] {
+        &self.ranges[..self.len]
+    }
+
+    /// Returns whether the given frame is part of the footprint.
+    fn contains(&self, frame: PhysFrame) -> bool {
+        self.ranges()
+            .iter()
+            .any(|range| range.start <= frame && frame < range.end)
+    }
+
+    /// Adds the given frames, merging them with an existing range if they
+    /// overlap or are adjacent.
+    fn add(&mut self, range: PhysFrameRange) -> Result<(), LoadKernelError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        for existing in &mut self.ranges[..self.len] {
+            if range.start <= existing.end && existing.start <= range.end {
+                existing.start = cmp::min(existing.start, range.start);
+                existing.end = cmp::max(existing.end, range.end);
+                return Ok(());
+            }
+        }
+        let slot = self
+            .ranges
+            .get_mut(self.len)
+            .ok_or(LoadKernelError::FootprintTooFragmented)?;
+        *slot = range;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// A virtual memory region of the loaded kernel whose pages are mapped with
+/// the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelRegion {
+    /// The page aligned start address of the region.
+    pub start: VirtAddr,
+    /// The size of the region in bytes, a multiple of the page size.
+    pub size: u64,
+    /// The page table flags the region is mapped with.
+    ///
+    /// The `HUGE_PAGE`, `ACCESSED` and `DIRTY` flags are not included.
+    pub flags: Flags,
+}
+
+/// The virtual memory regions of the loaded kernel.
+///
+/// The regions are read from the page table after the relocations have been
+/// applied, so they reflect the bss mappings and the RELRO protection.
+/// Adjacent regions with the same flags are merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelMemoryMap {
+    regions: [KernelRegion; KernelMemoryMap::MAX_REGIONS],
+    len: usize,
+}
+
+impl KernelMemoryMap {
+    /// The maximum number of distinct regions.
+    pub const MAX_REGIONS: usize = 32;
+
+    fn new() -> Self {
+        let region = KernelRegion {
+            start: VirtAddr::zero(),
+            size: 0,
+            flags: Flags::empty(),
+        };
+        Self {
+            regions: [region; Self::MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Returns the recorded regions.
+    pub fn regions(&self) -> &[KernelRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Adds the given region, merging it with the last region if it directly
+    /// follows it and has the same flags.
+    fn add(&mut self, region: KernelRegion) -> Result<(), LoadKernelError> {
+        if let Some(last) = self.regions[..self.len].last_mut() {
+            if last.flags == region.flags
+                && last.start.as_u64().checked_add(last.size) == Some(region.start.as_u64())
+            {
+                last.size += region.size;
+                return Ok(());
+            }
+        }
+        let slot = self
+            .regions
+            .get_mut(self.len)
+            .ok_or(LoadKernelError::MemoryMapTooFragmented)?;
+        *slot = region;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// The name of a symbol or file referenced in a [`LoadKernelError`].
+///
+/// Names longer than [`SymbolName::MAX_LEN`] bytes are truncated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SymbolName {
+    bytes: [u8; SymbolName::MAX_LEN],
+    len: usize,
+}
+
+impl SymbolName {
+    /// The maximum number of bytes that are stored.
+    pub const MAX_LEN: usize = 64;
+
+    /// Returns the (possibly truncated) name.
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(name) => name,
+            // The name may have been truncated in the middle of a character.
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < Self::MAX_LEN {
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+    }
+}
+
+impl Default for SymbolName {
+    fn default() -> Self {
+        Self {
+            bytes: [0; Self::MAX_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl From<&str> for SymbolName {
+    fn from(name: &str) -> Self {
+        let mut symbol_name = Self::default();
+        for &byte in name.as_bytes() {
+            symbol_name.push(byte);
+        }
+        symbol_name
+    }
+}
+
+impl fmt::Debug for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A helper type used to offset virtual addresses for position independent
+/// executables.
+#[derive(Clone, Copy)]
+pub struct VirtualAddressOffset {
+    virtual_address_offset: i128,
+}
+
+impl VirtualAddressOffset {
+    pub fn zero() -> Self {
+        Self::new(0)
+    }
+
+    pub fn new(virtual_address_offset: i128) -> Self {
+        Self {
+            virtual_address_offset,
+        }
+    }
+
+    pub fn virtual_address_offset(&self) -> i128 {
+        self.virtual_address_offset
+    }
+
+    /// Adds the offset to the given address, returning `None` if the result
+    /// doesn't fit into a `u64`.
+    pub fn checked_add(self, offset: u64) -> Option<u64> {
+        u64::try_from(
+            self.virtual_address_offset
+                .checked_add(i128::from(offset))?,
+        )
+        .ok()
+    }
+}
+
+impl Add<u64> for VirtualAddressOffset {
+    type Output = u64;
+
+    fn add(self, offset: u64) -> Self::Output {
+        u64::try_from(
+            self.virtual_address_offset
+                .checked_add(i128::from(offset))
+                .unwrap(),
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use self::mock::{BumpAllocator, Call, PhysicalMemory, RecordingMapper};
+    use super::{
+        bss_pages, find_load_segment, huge_frame_range, load_kernel_into_new_page_table,
+        load_kernel_verified, range_flags, required_frames, segment_page_flags, slice_at,
+        zero_volatile, FnFrameAllocator, KernelMemoryMap, KernelRegion, LoadCallbacks,
+        LoadKernelError, Loader, LoaderOptions, PhysicalFootprint, RelrDecoder, RequiredFrames,
+        SymbolName, VirtualAddressOffset, X86Features, COPIED, PT_GNU_PROPERTY,
+    };
+    use crate::{level_4_entries::UsedLevel4Entries, small_pages::SmallPageMapper, Kernel};
+    use bootloader_api::{info::TlsTemplate, BootloaderConfig};
+    use std::{vec, vec::Vec};
+    use x86_64::{
+        structures::paging::{
+            mapper::TranslateResult, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize,
+            PageTable, PageTableFlags as Flags, PhysFrame, Size2MiB, Size4KiB, Translate,
+        },
+        PhysAddr, VirtAddr,
+    };
+    use xmas_elf::{
+        header,
+        program::{self, ProgramHeader, FLAG_R, FLAG_W, FLAG_X},
+        ElfFile,
+    };
+
+    /// A mock page table and frame allocator that operate on fake physical
+    /// memory on the host.
+    pub(crate) mod mock {
+        extern crate std;
+
+        use std::{vec, vec::Vec};
+        use x86_64::{
+            structures::paging::{
+                mapper::{
+                    FlagUpdateError, MapToError, MapperFlush, MapperFlushAll, TranslateError,
+                    TranslateResult, UnmapError,
+                },
+                FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+                PageTableFlags as Flags, PhysFrame, Size4KiB, Translate,
+            },
+            PhysAddr, VirtAddr,
+        };
+
+        #[derive(Clone, Copy)]
+        #[repr(C, align(4096))]
+        struct RawFrame([u8; Size4KiB::SIZE as usize]);
+
+        /// Fake physical memory starting at physical address 0.
+        ///
+        /// The memory is accessible at [`PhysicalMemory::offset`], which is
+        /// passed to the loader as the physical memory offset.
+        pub struct PhysicalMemory {
+            frames: Vec<RawFrame>,
+        }
+
+        impl PhysicalMemory {
+            pub fn new(size: u64) -> Self {
+                let num_frames = size.div_ceil(Size4KiB::SIZE) as usize;
+                Self {
+                    frames: vec![RawFrame([0; Size4KiB::SIZE as usize]); num_frames],
+                }
+            }
+
+            pub fn size(&self) -> u64 {
+                self.frames.len() as u64 * Size4KiB::SIZE
+            }
+
+            pub fn offset(&self) -> u64 {
+                self.frames.as_ptr() as u64
+            }
+
+            /// Returns a pointer to the given physical address.
+            pub fn ptr(&self, addr: PhysAddr) -> *mut u8 {
+                assert!(addr.as_u64() < self.size());
+                (self.offset() + addr.as_u64()) as *mut u8
+            }
+
+            pub fn write(&mut self, addr: PhysAddr, bytes: &[u8]) {
+                assert!(addr.as_u64() + bytes.len() as u64 <= self.size());
+                unsafe {
+                    core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr(addr), bytes.len())
+                };
+            }
+
+            pub fn read(&self, addr: PhysAddr, len: usize) -> Vec<u8> {
+                assert!(addr.as_u64() + len as u64 <= self.size());
+                unsafe { core::slice::from_raw_parts(self.ptr(addr), len) }.to_vec()
+            }
+        }
+
+        /// Hands out the frames of a [`PhysicalMemory`] in ascending order.
+        pub struct BumpAllocator {
+            next: PhysFrame,
+            end: PhysFrame,
+        }
+
+        impl BumpAllocator {
+            pub fn new(start: PhysAddr, memory: &PhysicalMemory) -> Self {
+                Self {
+                    next: PhysFrame::containing_address(start),
+                    end: PhysFrame::containing_address(PhysAddr::new(memory.size())),
+                }
+            }
+        }
+
+        unsafe impl FrameAllocator<Size4KiB> for BumpAllocator {
+            fn allocate_frame(&mut self) -> Option<PhysFrame> {
+                if self.next >= self.end {
+                    return None;
+                }
+                let frame = self.next;
+                self.next += 1;
+                Some(frame)
+            }
+        }
+
+        /// A call to [`RecordingMapper`] that modified a mapping.
+        #[derive(Debug, PartialEq, Eq)]
+        pub enum Call {
+            Map {
+                page: u64,
+                frame: u64,
+                size: u64,
+                flags: Flags,
+            },
+            Unmap {
+                page: u64,
+                size: u64,
+            },
+        }
+
+        /// An [`OffsetPageTable`] in a [`PhysicalMemory`] that records all
+        /// `map_to` and `unmap` calls.
+        pub struct RecordingMapper<'a> {
+            inner: OffsetPageTable<'a>,
+            pub calls: Vec<Call>,
+        }
+
+        impl RecordingMapper<'_> {
+            pub fn new(memory: &PhysicalMemory, frame_allocator: &mut BumpAllocator) -> Self {
+                let frame = frame_allocator.allocate_frame().unwrap();
+                let table = memory.ptr(frame.start_address()) as *mut PageTable;
+                let inner = unsafe {
+                    table.write(PageTable::new());
+                    OffsetPageTable::new(&mut *table, VirtAddr::new(memory.offset()))
+                };
+                Self {
+                    inner,
+                    calls: Vec::new(),
+                }
+            }
+        }
+
+        impl<'a, S: PageSize> Mapper<S> for RecordingMapper<'a>
+        where
+            OffsetPageTable<'a>: Mapper<S>,
+        {
+            unsafe fn map_to_with_table_flags<A>(
+                &mut self,
+                page: Page<S>,
+                frame: PhysFrame<S>,
+                flags: Flags,
+                parent_table_flags: Flags,
+                frame_allocator: &mut A,
+            ) -> Result<MapperFlush<S>, MapToError<S>>
+            where
+                Self: Sized,
+                A: FrameAllocator<Size4KiB> + ?Sized,
+            {
+                self.calls.push(Call::Map {
+                    page: page.start_address().as_u64(),
+                    frame: frame.start_address().as_u64(),
+                    size: S::SIZE,
+                    flags,
+                });
+                unsafe {
+                    self.inner.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        parent_table_flags,
+                        frame_allocator,
+                    )
+                }
+            }
+
+            fn unmap(
+                &mut self,
+                page: Page<S>,
+            ) -> Result<(PhysFrame<S>, MapperFlush<S>), UnmapError> {
+                self.calls.push(Call::Unmap {
+                    page: page.start_address().as_u64(),
+                    size: S::SIZE,
+                });
+                self.inner.unmap(page)
+            }
+
+            unsafe fn update_flags(
+                &mut self,
+                page: Page<S>,
+                flags: Flags,
+            ) -> Result<MapperFlush<S>, FlagUpdateError> {
+                unsafe { self.inner.update_flags(page, flags) }
+            }
+
+            unsafe fn set_flags_p4_entry(
+                &mut self,
+                page: Page<S>,
+                flags: Flags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.inner.set_flags_p4_entry(page, flags) }
+            }
+
+            unsafe fn set_flags_p3_entry(
+                &mut self,
+                page: Page<S>,
+                flags: Flags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.inner.set_flags_p3_entry(page, flags) }
+            }
+
+            unsafe fn set_flags_p2_entry(
+                &mut self,
+                page: Page<S>,
+                flags: Flags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.inner.set_flags_p2_entry(page, flags) }
+            }
+
+            fn translate_page(&self, page: Page<S>) -> Result<PhysFrame<S>, TranslateError> {
+                self.inner.translate_page(page)
+            }
+        }
+
+        impl Translate for RecordingMapper<'_> {
+            fn translate(&self, addr: VirtAddr) -> TranslateResult {
+                self.inner.translate(addr)
+            }
+        }
+    }
+
+    /// A program header for [`build_elf`].
+    #[derive(Clone, Copy)]
+    pub(crate) struct Phdr {
+        pub ty: u32,
+        pub flags: u32,
+        pub offset: u64,
+        pub vaddr: u64,
+        pub file_size: u64,
+        pub mem_size: u64,
+        pub align: u64,
+    }
+
+    /// Builds a 64-bit x86_64 ELF file of the given type and size that only
+    /// contains the given program headers.
+    ///
+    /// The entry point is the start of the first executable Load segment.
+    pub(crate) fn build_elf(ty: u16, len: usize, headers: &[Phdr]) -> Vec<u8> {
+        let mut elf = vec![0; len];
+        elf[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf[16..18].copy_from_slice(&ty.to_le_bytes());
+        elf[18..20].copy_from_slice(&62u16.to_le_bytes()); // x86_64
+        elf[20..24].copy_from_slice(&1u32.to_le_bytes()); // version
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf[52..54].copy_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf[56..58].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+        if let Some(header) = headers
+            .iter()
+            .find(|header| header.ty == 1 && header.flags & FLAG_X != 0)
+        {
+            elf[24..32].copy_from_slice(&header.vaddr.to_le_bytes()); // e_entry
+        }
+        for (i, header) in headers.iter().enumerate() {
+            let fields = [
+                u64::from(header.ty) | u64::from(header.flags) << 32,
+                header.offset,
+                header.vaddr,
+                header.vaddr,
+                header.file_size,
+                header.mem_size,
+                header.align,
+            ];
+            for (j, field) in fields.iter().enumerate() {
+                let start = 64 + i * 56 + j * 8;
+                elf[start..start + 8].copy_from_slice(&field.to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    /// Writes the given values to the ELF file as consecutive `u64`s.
+    fn write_u64s(elf: &mut [u8], offset: usize, values: &[u64]) {
+        for (i, value) in values.iter().enumerate() {
+            elf[offset + i * 8..][..8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// The Dynamic segment of [`dynamic_kernel`], with room for one more
+    /// entry than the default ones.
+    const DYNAMIC: Phdr = Phdr {
+        ty: 2, // Dynamic
+        flags: FLAG_R | FLAG_W,
+        offset: 0x1000,
+        vaddr: 0x1000,
+        file_size: 0x50,
+        mem_size: 0x50,
+        align: 8,
+    };
+
+    /// Builds a shared object with a RWX Load segment at 0x1000 that starts
+    /// with [`DYNAMIC`].
+    ///
+    /// The dynamic section contains `Rela`, `RelaSize`, `RelaEnt` and `Null`
+    /// entries for the given `Rela` table, which is written to 0x1100.
+    fn dynamic_kernel(relocations: &[u64]) -> Vec<u8> {
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        dynamic_kernel_with(0x3000, &[segment, DYNAMIC], relocations)
+    }
+
+    /// Like [`dynamic_kernel`], but with the given size and program headers.
+    fn dynamic_kernel_with(len: usize, headers: &[Phdr], relocations: &[u64]) -> Vec<u8> {
+        let mut elf = build_elf(3, len, headers); // shared object
+        let rela_size = 8 * relocations.len() as u64;
+        // `Rela`, `RelaSize`, `RelaEnt` and `Null` entries.
+        write_u64s(&mut elf, 0x1000, &[7, 0x1100, 8, rela_size, 9, 24, 0, 0]);
+        write_u64s(&mut elf, 0x1100, relocations);
+        elf
+    }
+
+    /// A kernel loaded by [`load`].
+    struct Loaded {
+        // Declared before `memory`, so that it is dropped first.
+        page_table: RecordingMapper<'static>,
+        memory: PhysicalMemory,
+        virtual_address_offset: u64,
+        memory_map: KernelMemoryMap,
+        tls: Option<TlsTemplate>,
+        /// The destination and value of every applied relocation.
+        relocations: Vec<(u64, u64)>,
+        x86_features: X86Features,
+    }
+
+    impl Loaded {
+        /// Returns the frame and flags the given virtual address is mapped to.
+        fn translate(&self, addr: u64) -> (PhysAddr, Flags) {
+            match self.page_table.translate(VirtAddr::new(addr)) {
+                TranslateResult::Mapped {
+                    frame,
+                    offset,
+                    flags,
+                } => (frame.start_address() + offset, flags),
+                _ => panic!("{addr:#x} is not mapped"),
+            }
+        }
+
+        /// Reads kernel memory within a single page.
+        fn read(&self, addr: u64, len: usize) -> Vec<u8> {
+            assert_eq!(
+                addr / Size4KiB::SIZE,
+                (addr + len as u64 - 1) / Size4KiB::SIZE
+            );
+            self.memory.read(self.translate(addr).0, len)
+        }
+    }
+
+    /// Loads the given ELF file at `elf_addr` in a fresh [`PhysicalMemory`].
+    fn load(memory_size: u64, elf_addr: u64, elf: &[u8]) -> Loaded {
+        load_with_options(memory_size, elf_addr, elf, LoaderOptions::new_default())
+    }
+
+    /// Like [`load`], but with the given options.
+    ///
+    /// The physical memory offset is set automatically.
+    fn load_with_options(
+        memory_size: u64,
+        elf_addr: u64,
+        elf: &[u8],
+        options: LoaderOptions,
+    ) -> Loaded {
+        try_load_with_options(memory_size, elf_addr, elf, options).unwrap()
+    }
+
+    /// Like [`load_with_options`], but returns loading errors.
+    fn try_load_with_options(
+        memory_size: u64,
+        elf_addr: u64,
+        elf: &[u8],
+        options: LoaderOptions,
+    ) -> Result<Loaded, LoadKernelError> {
+        try_load_with_flags(memory_size, elf_addr, elf, options, |_, flags| flags)
+    }
+
+    /// Like [`try_load_with_options`], but maps the segments with the flags
+    /// returned by `map_flags`.
+    fn try_load_with_flags(
+        memory_size: u64,
+        elf_addr: u64,
+        elf: &[u8],
+        mut options: LoaderOptions,
+        mut map_flags: impl FnMut(&ProgramHeader, Flags) -> Flags,
+    ) -> Result<Loaded, LoadKernelError> {
+        let elf_addr = PhysAddr::new(elf_addr);
+        let mut memory = PhysicalMemory::new(memory_size);
+        memory.write(elf_addr, elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let kernel = Kernel {
+            elf: ElfFile::new(input).unwrap(),
+            config,
+            start_address: input.as_ptr(),
+            len: input.len(),
+        };
+        options.physical_memory_offset = memory.offset();
+
+        let mut loader = Loader::new(
+            kernel,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )?;
+        let tls = loader.load_segments_with(LoadCallbacks {
+            map_flags: Some(&mut map_flags),
+            ..LoadCallbacks::default()
+        })?;
+        let mut relocations = Vec::new();
+        loader.apply_relocations_with(|addr, value| relocations.push((addr.as_u64(), value)))?;
+        let virtual_address_offset = loader.virtual_address_offset().virtual_address_offset();
+        let memory_map = loader.memory_map()?;
+        let x86_features = loader.x86_features();
+
+        Ok(Loaded {
+            page_table,
+            memory,
+            virtual_address_offset: virtual_address_offset as u64,
+            memory_map,
+            tls,
+            relocations,
+            x86_features,
+        })
+    }
+
+    /// Returns the error of [`Loader::new`] for the given ELF file.
+    fn loader_error(elf: &[u8]) -> LoadKernelError {
+        let elf_addr = PhysAddr::new(0x1000);
+        let mut memory = PhysicalMemory::new(0x10_0000);
+        memory.write(elf_addr, elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let kernel = Kernel {
+            elf: ElfFile::new(input).unwrap(),
+            config,
+            start_address: input.as_ptr(),
+            len: input.len(),
+        };
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+
+        let result = Loader::new(
+            kernel,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        );
+        match result {
+            Ok(_) => panic!("the kernel was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn relr_decoding() {
+        let entries = [
+            // A single address.
+            0x1000,
+            // The first and third word after the address.
+            0b101 << 1 | 1,
+            // The first and last word of the next 63 words.
+            (1 << 62 | 1) << 1 | 1,
+            // A new address, followed by an empty bitmap.
+            0x3000,
+            1,
+            // The second word after the empty bitmap.
+            0b10 << 1 | 1,
+        ];
+
+        let mut decoder = RelrDecoder::default();
+        let offsets: Vec<u64> = entries
+            .into_iter()
+            .flat_map(|entry| decoder.decode(entry).collect::<Vec<_>>())
+            .collect();
+
+        assert_eq!(
+            offsets,
+            [
+                0x1000,
+                0x1008,
+                0x1018,
+                0x1200,
+                0x13f0,
+                0x3000,
+                0x3000 + 64 * 8 + 8
+            ]
+        );
+    }
+
+    #[test]
+    fn segment_flags() {
+        let flags = |flags| segment_page_flags(program::Flags(flags), true);
+
+        // Read-only data must never be writable.
+        assert_eq!(flags(FLAG_R), Flags::PRESENT | Flags::NO_EXECUTE);
+        assert_eq!(flags(FLAG_R | FLAG_X), Flags::PRESENT);
+        assert_eq!(
+            flags(FLAG_R | FLAG_W),
+            Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE
+        );
+        assert_eq!(
+            flags(FLAG_R | FLAG_W | FLAG_X),
+            Flags::PRESENT | Flags::WRITABLE
+        );
+
+        // Without support for the NO_EXECUTE flag, it is never set.
+        assert_eq!(
+            segment_page_flags(program::Flags(FLAG_R), false),
+            Flags::PRESENT
+        );
+    }
+
+    #[test]
+    fn load_segment_lookup() {
+        let header = |ty, vaddr, mem_size| Phdr {
+            ty,
+            flags: FLAG_R,
+            offset: 0x1000,
+            vaddr,
+            file_size: 0,
+            mem_size,
+            align: 0x1000,
+        };
+        // Load segments interleaved with notes and an empty Load segment.
+        let headers = [
+            header(4, 0x9000, 0x1000),
+            header(1, 0x1000, 0x1800),
+            header(1, 0x5000, 0x1000),
+            header(4, 0x3000, 0x1000),
+            header(1, 0x6000, 0),
+            header(1, 0x8000, 0x800),
+            header(4, 0x8000, 0x1000),
+        ];
+        let elf = build_elf(2, 0x2000, &headers); // executable
+        let elf_file = ElfFile::new(&elf).unwrap();
+        for offset in (0..0xa000).step_by(0x100) {
+            let expected = headers.iter().position(|header| {
+                header.ty == 1 && header.vaddr <= offset && offset - header.vaddr < header.mem_size
+            });
+            let segment = find_load_segment(&elf_file, offset).unwrap();
+            assert_eq!(
+                segment.map(|segment| segment.virtual_addr()),
+                expected.map(|idx| headers[idx].vaddr),
+                "offset {offset:#x}"
+            );
+        }
+
+        // The lookup relies on the segments being sorted.
+        let elf = build_elf(
+            2, // executable
+            0x2000,
+            &[header(1, 0x20_2000, 0x1000), header(1, 0x20_0000, 0x1000)],
+        );
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::UnsortedSegments {
+                first: 0x20_2000,
+                second: 0x20_0000,
+            }
+        );
+    }
+
+    #[test]
+    fn footprint_merges_adjacent_frames() {
+        let frame = |addr| PhysFrame::containing_address(PhysAddr::new(addr));
+        let mut footprint = PhysicalFootprint::new();
+        footprint
+            .add(PhysFrame::range(frame(0x1000), frame(0x3000)))
+            .unwrap();
+        footprint
+            .add(PhysFrame::range(frame(0x3000), frame(0x4000)))
+            .unwrap();
+        footprint
+            .add(PhysFrame::range(frame(0x8000), frame(0x9000)))
+            .unwrap();
+        footprint
+            .add(PhysFrame::range(frame(0x2000), frame(0x3000)))
+            .unwrap();
+        assert_eq!(
+            footprint.ranges(),
+            [
+                PhysFrame::range(frame(0x1000), frame(0x4000)),
+                PhysFrame::range(frame(0x8000), frame(0x9000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn symbol_name_truncation() {
+        assert_eq!(SymbolName::from("_start").as_str(), "_start");
+
+        let long_name = "x".repeat(SymbolName::MAX_LEN + 10);
+        assert_eq!(
+            SymbolName::from(long_name.as_str()).as_str(),
+            &long_name[..SymbolName::MAX_LEN]
+        );
+
+        // Truncation must not split a multi-byte character.
+        let mut name = "x".repeat(SymbolName::MAX_LEN - 1);
+        name.push('ä');
+        assert_eq!(
+            SymbolName::from(name.as_str()).as_str(),
+            &name[..SymbolName::MAX_LEN - 1]
+        );
+    }
+
+    #[test]
+    fn huge_frames_for_aligned_segment() {
+        // A 4 MiB segment that is 2 MiB aligned both virtually and physically.
+        let start_page = Page::containing_address(VirtAddr::new(0xffff_8000_0020_0000));
+        let start_frame = PhysFrame::containing_address(PhysAddr::new(0x40_0000));
+        let end_frame = PhysFrame::containing_address(PhysAddr::new(0x7f_ffff));
+
+        let huge_frames = huge_frame_range(start_page, start_frame, end_frame).unwrap();
+        let huge_frames: Vec<_> = huge_frames.map(|f| f.start_address().as_u64()).collect();
+        assert_eq!(huge_frames, [0x40_0000, 0x60_0000]);
+    }
+
+    #[test]
+    fn huge_frames_for_unaligned_segment() {
+        // Only the middle 2 MiB of the segment can be mapped with a huge page.
+        let start_page = Page::containing_address(VirtAddr::new(0x20_1000));
+        let start_frame = PhysFrame::containing_address(PhysAddr::new(0x40_1000));
+        let end_frame = PhysFrame::containing_address(PhysAddr::new(0x80_0fff));
+        let huge_frames = huge_frame_range(start_page, start_frame, end_frame).unwrap();
+        let huge_frames: Vec<_> = huge_frames.map(|f| f.start_address().as_u64()).collect();
+        assert_eq!(huge_frames, [0x60_0000]);
+
+        // The virtual and physical addresses are aligned differently.
+        let start_page = Page::containing_address(VirtAddr::new(0x30_0000));
+        let start_frame = PhysFrame::containing_address(PhysAddr::new(0x40_0000));
+        let end_frame = PhysFrame::containing_address(PhysAddr::new(0x7f_ffff));
+        assert!(huge_frame_range(start_page, start_frame, end_frame).is_none());
+    }
+
+    #[test]
+    fn bss_pages_at_page_boundary() {
+        // Takes an exclusive end address for readability.
+        let pages = |start: u64, end: u64| {
+            bss_pages(VirtAddr::new(start), VirtAddr::new(end - 1))
+                .into_iter()
+                .flatten()
+                .map(|page| page.start_address().as_u64())
+                .collect::<Vec<_>>()
+        };
+
+        // The bss ends within or exactly at the end of the last data page.
+        assert_eq!(pages(0x1800, 0x1801), []);
+        assert_eq!(pages(0x1800, 0x2000), []);
+        // Spilling a single byte into the next page requires a new frame.
+        assert_eq!(pages(0x1800, 0x2001), [0x2000]);
+        // A page-aligned bss doesn't share a page with file data.
+        assert_eq!(pages(0x2000, 0x2001), [0x2000]);
+        assert_eq!(pages(0x2000, 0x4000), [0x2000, 0x3000]);
+        // The end of the lower half of the address space.
+        assert_eq!(pages(0x7fff_ffff_f800, 0x8000_0000_0000), []);
+    }
+
+    #[test]
+    fn load_segment_with_bss() {
+        const ELF_ADDR: u64 = 0x10_0000;
+        let mut elf = build_elf(
+            2, // executable
+            0x2000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_W | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x800,
+                mem_size: 0x2800,
+                align: 0x1000,
+            }],
+        );
+        elf[0x1000..0x1800].fill(0xab);
+        // The rest of the frame must not end up in the bss.
+        elf[0x1800..0x2000].fill(0xcd);
+
+        let loaded = load(0x40_0000, ELF_ADDR, &elf);
+
+        let flags = Flags::PRESENT | Flags::WRITABLE;
+        let calls = &loaded.page_table.calls;
+        assert_eq!(calls.len(), 5);
+        // The frame of the ELF file is mapped first, then replaced by a copy
+        // because it is partially zeroed.
+        assert_eq!(
+            calls[..2],
+            [
+                Call::Map {
+                    page: 0x20_1000,
+                    frame: ELF_ADDR + 0x1000,
+                    size: Size4KiB::SIZE,
+                    flags,
+                },
+                Call::Unmap {
+                    page: 0x20_1000,
+                    size: Size4KiB::SIZE,
+                },
+            ]
+        );
+        let pages: Vec<_> = calls[2..]
+            .iter()
+            .map(|call| match *call {
+                Call::Map {
+                    page, size, flags, ..
+                } => (page, size, flags),
+                Call::Unmap { .. } => panic!("unexpected unmap"),
+            })
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                (0x20_1000, Size4KiB::SIZE, flags | COPIED),
+                (0x20_2000, Size4KiB::SIZE, flags),
+                (0x20_3000, Size4KiB::SIZE, flags),
+            ]
+        );
+
+        // The `COPIED` flag is removed after loading.
+        assert_eq!(loaded.translate(0x20_1000).1, flags);
+        assert_eq!(loaded.read(0x20_1000, 0x800), [0xab; 0x800]);
+        assert_eq!(loaded.read(0x20_1800, 0x800), [0; 0x800]);
+        assert_eq!(loaded.read(0x20_2000, 0x1000), [0; 0x1000]);
+        assert_eq!(loaded.read(0x20_3000, 0x800), [0; 0x800]);
+
+        assert_eq!(
+            loaded.memory_map.regions(),
+            [KernelRegion {
+                start: VirtAddr::new(0x20_1000),
+                size: 0x3000,
+                flags,
+            }]
+        );
+    }
+
+    #[test]
+    fn small_page_mapper() {
+        let memory = PhysicalMemory::new(0x40_0000);
+        let mut frame_allocator = BumpAllocator::new(PhysAddr::new(0x1000), &memory);
+        let mut mapper = SmallPageMapper(RecordingMapper::new(&memory, &mut frame_allocator));
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        unsafe { mapper.map_to(page, frame, Flags::PRESENT, &mut frame_allocator) }
+            .unwrap()
+            .ignore();
+
+        // The huge page is mapped as 4 KiB pages.
+        assert_eq!(mapper.0.calls.len(), 512);
+        assert_eq!(
+            mapper.0.calls[1],
+            Call::Map {
+                page: 0x20_1000,
+                frame: 0x20_1000,
+                size: Size4KiB::SIZE,
+                flags: Flags::PRESENT,
+            }
+        );
+        match mapper.translate(VirtAddr::new(0x3f_f123)) {
+            TranslateResult::Mapped { frame, offset, .. } => {
+                assert_eq!(frame.size(), Size4KiB::SIZE);
+                assert_eq!(frame.start_address() + offset, PhysAddr::new(0x3f_f123));
+            }
+            result => panic!("unexpected translation {result:?}"),
+        }
+    }
+
+    #[test]
+    fn huge_page_for_read_only_segment() {
+        const ELF_ADDR: u64 = 0x20_0000;
+        let elf = build_elf(
+            2, // executable
+            0x40_1000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x20_0000,
+                vaddr: 0x40_0000,
+                file_size: 0x20_0000,
+                mem_size: 0x20_0000,
+                align: 0x20_0000,
+            }],
+        );
+
+        let loaded = load(0x80_0000, ELF_ADDR, &elf);
+
+        assert_eq!(
+            loaded.page_table.calls,
+            [Call::Map {
+                page: 0x40_0000,
+                frame: ELF_ADDR + 0x20_0000,
+                size: Size2MiB::SIZE,
+                flags: Flags::PRESENT,
+            }]
+        );
+        assert_eq!(
+            loaded.memory_map.regions(),
+            [KernelRegion {
+                start: VirtAddr::new(0x40_0000),
+                size: Size2MiB::SIZE,
+                flags: Flags::PRESENT,
+            }]
+        );
+    }
+
+    #[test]
+    fn relative_relocation_in_position_independent_kernel() {
+        // A `R_AMD64_RELATIVE` relocation.
+        let elf = dynamic_kernel(&[0x1200, 8, 0x1234]);
+
+        // The relocated word must be skipped by the verification.
+        let mut options = LoaderOptions::new_default();
+        options.verify_segments = true;
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        let base = loaded.virtual_address_offset;
+        assert_ne!(base, 0);
+        let value = loaded.read(base + 0x1200, 8);
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), base + 0x1234);
+        assert_eq!(loaded.relocations, [(base + 0x1200, base + 0x1234)]);
+    }
+
+    #[test]
+    fn verify_segments_with_many_relocations() {
+        // More `R_AMD64_RELATIVE` relocations than fit into one batch of
+        // targets, in descending order.
+        let relocations: Vec<u64> = (0..300u64)
+            .rev()
+            .flat_map(|idx| [0x3000 + 8 * idx, 8, idx])
+            .collect();
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x4000,
+            mem_size: 0x4000,
+            align: 0x1000,
+        };
+        let elf = dynamic_kernel_with(0x6000, &[segment, DYNAMIC], &relocations);
+
+        let mut options = LoaderOptions::new_default();
+        options.verify_segments = true;
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        assert_eq!(loaded.relocations.len(), 300);
+    }
+
+    #[test]
+    fn max_relocations() {
+        // Three `R_AMD64_RELATIVE` relocations.
+        let elf = dynamic_kernel(&[0x1200, 8, 0x10, 0x1208, 8, 0x20, 0x1210, 8, 0x30]);
+
+        let mut options = LoaderOptions::new_default();
+        options.max_relocations = Some(3);
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        assert_eq!(loaded.relocations.len(), 3);
+
+        // The table is rejected before any relocation is applied.
+        options.max_relocations = Some(2);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::TooManyRelocations { count: 3, max: 2 })
+        ));
+    }
+
+    #[test]
+    fn irelative_relocation() {
+        // A relocation whose resolver is at 0x1234.
+        let elf = |info: u64| dynamic_kernel(&[0x1200, info, 0x1234]);
+
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf(37), LoaderOptions::new_default()),
+            Err(LoadKernelError::MissingIfuncResolver(0x1200))
+        ));
+
+        let mut options = LoaderOptions::new_default();
+        options.ifunc_resolver = Some(|resolver| resolver + 0x10);
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf(37), options);
+        let base = loaded.virtual_address_offset;
+        let value = loaded.read(base + 0x1200, 8);
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), base + 0x1244);
+        assert_eq!(loaded.relocations, [(base + 0x1200, base + 0x1244)]);
+
+        // The resolver is given by the addend, not by a symbol.
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf(1 << 32 | 37), options),
+            Err(LoadKernelError::UnsupportedSymbolRelocation {
+                ty: 37,
+                offset: 0x1200
+            })
+        ));
+    }
+
+    #[test]
+    fn rela_count() {
+        let elf = |rela_count: u64| {
+            // `R_AMD64_RELATIVE` and `R_AMD64_64` relocations.
+            let mut elf = dynamic_kernel(&[0x1200, 8, 0x1234, 0x1208, 1, 0x5678]);
+            // `Rela`, `RelaSize`, `RelaEnt`, `RelaCount` and `Null` entries.
+            write_u64s(
+                &mut elf,
+                0x1000,
+                &[7, 0x1100, 8, 2 * 24, 9, 24, 0x6fff_fff9, rela_count, 0, 0],
+            );
+            elf
+        };
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf(1));
+        let base = loaded.virtual_address_offset;
+        assert_eq!(
+            loaded.relocations,
+            [
+                (base + 0x1200, base + 0x1234),
+                (base + 0x1208, base + 0x5678)
+            ]
+        );
+
+        // The second relocation isn't relative.
+        for rela_count in [2, 3] {
+            assert!(matches!(
+                try_load_with_options(0x40_0000, 0x10_0000, &elf(rela_count), Default::default()),
+                Err(LoadKernelError::InvalidRelaCount(count)) if count == rela_count
+            ));
+        }
+    }
+
+    #[test]
+    fn mark_range_as_used() {
+        let config = BootloaderConfig::new_default();
+        let mut used_entries = UsedLevel4Entries::new(PhysAddr::new(0x10_0000), 0, None, &config);
+        assert!(!used_entries.is_used(7) && !used_entries.is_used(8));
+
+        // Empty ranges don't mark anything.
+        used_entries.mark_range(VirtAddr::new(0x3f0_0000_0000), 0);
+        assert!(!used_entries.is_used(7));
+
+        // The range crosses the boundary between two level 4 entries.
+        used_entries.mark_range(VirtAddr::new(0x3ff_ffff_f000), 0x2000);
+        assert!(used_entries.is_used(7) && used_entries.is_used(8));
+        assert!(!used_entries.is_used(9));
+
+        // The range ends at the end of the address space.
+        used_entries.mark_range(VirtAddr::new(0xffff_ff80_0000_0000), 0x80_0000_0000);
+        assert!(used_entries.is_used(511));
+    }
+
+    #[test]
+    fn used_entries_are_independent_of_segment_order() {
+        let segment = |vaddr| Phdr {
+            ty: 1, // Load
+            flags: FLAG_R,
+            offset: 0,
+            vaddr,
+            file_size: 0,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        // Two segments share level 4 entry 7, one uses entry 300.
+        let segments = [
+            segment(0x380_0000_0000),
+            segment(0xffff_9600_0000_0000),
+            segment(0x3ff_ffff_f000),
+        ];
+        let mut reordered = segments;
+        reordered.reverse();
+
+        let used_entries = |segments: &[Phdr]| {
+            let elf = build_elf(2, 0x1000, segments); // executable
+            let elf_file = ElfFile::new(&elf).unwrap();
+            let config = BootloaderConfig::new_default();
+            let mut used_entries =
+                UsedLevel4Entries::new(PhysAddr::new(0x10_0000), 0, None, &config);
+            used_entries.mark_segments(elf_file.program_iter(), VirtualAddressOffset::zero());
+            *used_entries.bitmap()
+        };
+
+        let bitmap = used_entries(&segments);
+        assert_eq!(bitmap, used_entries(&reordered));
+        assert_eq!(bitmap[0], 1 | 1 << 7);
+        assert_eq!(bitmap[300 / 64], 1 << (300 % 64));
+    }
+
+    #[test]
+    fn guard_pages() {
+        let segment = |flags, offset, vaddr, mem_size| Phdr {
+            ty: 1, // Load
+            flags,
+            offset,
+            vaddr,
+            file_size: 0x10,
+            mem_size,
+            align: 0x1000,
+        };
+        // The usual layout of lld, where the pages of the segments directly
+        // follow each other, and a segment after a gap of two pages.
+        let segments = [
+            segment(FLAG_R | FLAG_X, 0x1000, 0x20_1000, 0x234),
+            segment(FLAG_R | FLAG_W, 0x1234, 0x20_2234, 0x100),
+            segment(FLAG_R, 0x2000, 0x20_5000, 0x1000),
+        ];
+        let elf = build_elf(2, 0x3000, &segments); // executable
+        let mut options = LoaderOptions::new_default();
+        options.guard_pages = true;
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        for addr in [0x20_1000, 0x20_2000, 0x20_5000] {
+            loaded.translate(addr);
+        }
+        for addr in [0x20_0000, 0x20_3000, 0x20_4000, 0x20_6000] {
+            assert!(
+                matches!(
+                    loaded.page_table.translate(VirtAddr::new(addr)),
+                    TranslateResult::NotMapped
+                ),
+                "{addr:#x} is mapped"
+            );
+        }
+
+        // The segments start and end at the boundaries of level 4 entries 2
+        // and 3, so their outer guard pages are in entries 1 and 4.
+        let segments = [
+            segment(FLAG_R, 0x1000, 0x100_0000_0000, 0x1000),
+            segment(FLAG_R, 0x1000, 0x1ff_ffff_f000, 0x1000),
+        ];
+        let elf = build_elf(2, 0x2000, &segments); // executable
+        let elf_file = ElfFile::new(&elf).unwrap();
+        let config = BootloaderConfig::new_default();
+        let mut used_entries = UsedLevel4Entries::new(PhysAddr::new(0x10_0000), 0, None, &config);
+        let before = used_entries.bitmap()[0];
+        used_entries.mark_guard_pages(elf_file.program_iter(), VirtualAddressOffset::zero());
+        assert_eq!(used_entries.bitmap()[0], before | 0b1_1110);
+    }
+
+    #[test]
+    fn many_load_segments() {
+        // The number of Load segments is only limited by the program header
+        // table.
+        let segments: Vec<_> = (0..100)
+            .map(|idx| Phdr {
+                ty: 1, // Load
+                flags: if idx == 0 { FLAG_R | FLAG_X } else { FLAG_R },
+                offset: 0x2000,
+                vaddr: 0x20_0000 + idx * 0x1000,
+                file_size: 0x10,
+                mem_size: 0x1000,
+                align: 0x1000,
+            })
+            .collect();
+        let mut elf = build_elf(2, 0x3000, &segments); // executable
+        elf[0x2000] = 0xaa;
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(loaded.read(0x20_0000 + 99 * 0x1000, 1), [0xaa]);
+    }
+
+    #[test]
+    fn fn_frame_allocator() {
+        let mut next = PhysFrame::containing_address(PhysAddr::new(0x1000));
+        let end = next + 2;
+        let mut allocator = unsafe {
+            FnFrameAllocator::new(|| {
+                let frame = next;
+                next += 1;
+                (frame < end).then_some(frame)
+            })
+        };
+
+        assert_eq!(
+            allocator.allocate_frame(),
+            Some(PhysFrame::containing_address(PhysAddr::new(0x1000)))
+        );
+        assert_eq!(
+            allocator.allocate_frame(),
+            Some(PhysFrame::containing_address(PhysAddr::new(0x2000)))
+        );
+        assert_eq!(allocator.allocate_frame(), None);
+    }
+
+    #[test]
+    fn relocation_in_read_only_segment() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let rodata = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R,
+            offset: 0x2000,
+            vaddr: 0x2000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let relro = Phdr {
+            ty: 0x6474_e552, // GnuRelro
+            flags: FLAG_R,
+            offset: 0x2000,
+            vaddr: 0x2000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 1,
+        };
+        // A `R_AMD64_RELATIVE` relocation in the read-only segment.
+        let elf = |headers: &[Phdr]| dynamic_kernel_with(0x4000, headers, &[0x2008, 8, 0x1234]);
+        let mut options = LoaderOptions::new_default();
+        options.check_relocation_targets = true;
+
+        // The check is disabled by default.
+        let without_relro = elf(&[code, DYNAMIC, rodata]);
+        load(0x40_0000, 0x10_0000, &without_relro);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &without_relro, options),
+            Err(LoadKernelError::ReadOnlyRelocationTarget(0x2008))
+        ));
+
+        // Memory that is made read-only after relocation may be relocated.
+        let with_relro = elf(&[code, DYNAMIC, rodata, relro]);
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &with_relro, options);
+        let base = loaded.virtual_address_offset;
+        let value = loaded.read(base + 0x2008, 8);
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), base + 0x1234);
+
+        // Only the range of the RELRO segment may be relocated.
+        let partial_relro = Phdr {
+            vaddr: 0x2800,
+            mem_size: 0x800,
+            ..relro
+        };
+        assert!(matches!(
+            try_load_with_options(
+                0x40_0000,
+                0x10_0000,
+                &elf(&[code, DYNAMIC, rodata, partial_relro]),
+                options
+            ),
+            Err(LoadKernelError::ReadOnlyRelocationTarget(0x2008))
+        ));
+    }
+
+    #[test]
+    fn relro_segment() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let data = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x2000,
+            vaddr: 0x20_2000,
+            file_size: 0x2000,
+            mem_size: 0x3000,
+            align: 0x1000,
+        };
+        let relro = |vaddr: u64, mem_size: u64| Phdr {
+            ty: 0x6474_e552, // GnuRelro
+            flags: FLAG_R,
+            offset: 0,
+            vaddr,
+            file_size: 0,
+            mem_size,
+            align: 1,
+        };
+        let writable = |loaded: &Loaded| {
+            [0x20_2000, 0x20_3000, 0x20_4000]
+                .map(|addr| loaded.translate(addr).1.contains(Flags::WRITABLE))
+        };
+
+        // Partially covered pages at both ends stay writable.
+        let elf = build_elf(2, 0x5000, &[code, data, relro(0x20_2800, 0x2000)]);
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(writable(&loaded), [true, false, true]);
+
+        let elf = build_elf(2, 0x5000, &[code, data, relro(0x20_2000, 0x2000)]);
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(writable(&loaded), [false, false, true]);
+
+        // Less than a page is not protected at all.
+        let elf = build_elf(2, 0x5000, &[code, data, relro(0x20_2800, 0x100)]);
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(writable(&loaded), [true, true, true]);
+
+        // The segment must be part of a Load segment.
+        for (vaddr, mem_size) in [
+            (0x50_0000, 0x1000),
+            (0x20_4000, 0x2000),
+            (0x20_0000, 0x2000),
+        ] {
+            let elf = build_elf(2, 0x5000, &[code, data, relro(vaddr, mem_size)]);
+            assert!(matches!(
+                loader_error(&elf),
+                LoadKernelError::InvalidRelroSegment(addr) if addr == vaddr
+            ));
+        }
+        let elf = build_elf(
+            2,
+            0x5000,
+            &[code, data, relro(0xffff_ffff_ffff_f000, 0x3000)],
+        );
+        assert!(matches!(
+            loader_error(&elf),
+            LoadKernelError::AddressOverflow(0xffff_ffff_ffff_f000)
+        ));
+    }
+
+    #[test]
+    fn relocate_in_place() {
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x800,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        // A `R_AMD64_RELATIVE` relocation.
+        let elf = dynamic_kernel_with(0x2000, &[segment, DYNAMIC], &[0x1200, 8, 0x1234]);
+
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+
+        // The segment is already identity-mapped, e.g. by the firmware.
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x10_1000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x10_1000));
+        unsafe {
+            page_table
+                .map_to(
+                    page,
+                    frame,
+                    Flags::PRESENT | Flags::WRITABLE,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+        }
+
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let kernel = Kernel {
+            elf: ElfFile::new(input).unwrap(),
+            config,
+            start_address: input.as_ptr(),
+            len: input.len(),
+        };
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        options.identity_map = true;
+
+        let mut loader = Loader::new(
+            kernel,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        assert!(loader.is_position_independent());
+        assert_eq!(loader.load_segments_in_place().unwrap(), None);
+        loader.apply_relocations().unwrap();
+        assert_eq!(loader.entry_point(), VirtAddr::new(0x10_1000));
+        assert!(loader.physical_footprint().ranges().is_empty());
+
+        // The relocation was applied to the resident frame and nothing else
+        // was mapped.
+        assert_eq!(page_table.calls.len(), 1);
+        assert_eq!(
+            memory.read(PhysAddr::new(0x10_1200), 8),
+            0x10_1234u64.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn relocate_in_place_unmapped() {
+        let elf = dynamic_kernel(&[0x1200, 8, 0x1234]);
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        options.identity_map = true;
+
+        // Nothing is mapped, so the segment can't be relocated in place.
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        assert_eq!(
+            loader.load_segments_in_place(),
+            Err(LoadKernelError::InconsistentMapping(0x10_1000))
+        );
+    }
+
+    #[test]
+    fn map_kernel_stack() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x10,
+            mem_size: 0x10,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x2000, &[code]); // executable
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        // Fill the free memory, so that we can check that the stack is zeroed.
+        let free = elf_addr + elf.len() as u64;
+        memory.write(free, &vec![0xff; (memory.size() - free.as_u64()) as usize]);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator = BumpAllocator::new(free, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        // The stack starts at level 4 entry 2, so that its guard page is in
+        // entry 1.
+        let fixed_start = VirtAddr::new(0x100_0000_0000);
+        assert_eq!(
+            loader.map_kernel_stack(0x3000, Some(fixed_start)),
+            Ok(fixed_start + 0x3000u64)
+        );
+        let free_start = loader.map_kernel_stack(0x2000, None).unwrap() - 0x2000u64;
+        for start in [0x100_0000_0800, 0] {
+            assert_eq!(
+                loader.map_kernel_stack(0x1000, Some(VirtAddr::new(start))),
+                Err(LoadKernelError::MisalignedStack(start))
+            );
+        }
+
+        for (start, size) in [(fixed_start, 0x3000u64), (free_start, 0x2000)] {
+            let guard_page = start - Size4KiB::SIZE;
+            assert!(matches!(
+                page_table.translate(guard_page),
+                TranslateResult::NotMapped
+            ));
+            let entry = usize::from(guard_page.p4_index());
+            assert_ne!(used_entries.bitmap()[entry / 64] & 1 << (entry % 64), 0);
+
+            for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+                let TranslateResult::Mapped { frame, flags, .. } =
+                    page_table.translate(start + offset)
+                else {
+                    panic!("stack page at {:#x} isn't mapped", start + offset);
+                };
+                assert_eq!(flags, Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE);
+                assert_eq!(
+                    memory.read(frame.start_address(), Size4KiB::SIZE as usize),
+                    [0; Size4KiB::SIZE as usize]
+                );
+            }
+        }
+        assert_eq!(used_entries.bitmap()[0] & 0b110, 0b110);
+    }
+
+    #[test]
+    fn map_ramdisk() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x10,
+            mem_size: 0x10,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x2000, &[code]); // executable
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        // The ramdisk covers three pages.
+        let ramdisk_addr = PhysAddr::new(0x30_0123);
+        memory.write(ramdisk_addr, &[0xaa; 0x2000]);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let ramdisk = unsafe { core::slice::from_raw_parts(memory.ptr(ramdisk_addr), 0x2000) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        let fixed_start = VirtAddr::new(0x100_0000_0000);
+        assert_eq!(
+            loader.map_ramdisk(ramdisk, Some(fixed_start)),
+            Ok(fixed_start + 0x123u64)
+        );
+        let free_addr = loader.map_ramdisk(ramdisk, None).unwrap();
+        assert_eq!(free_addr.as_u64() % Size4KiB::SIZE, 0x123);
+
+        assert_eq!(
+            loader.map_ramdisk(ramdisk, Some(VirtAddr::new(0x100_0000_0800))),
+            Err(LoadKernelError::MisalignedRamdisk(0x100_0000_0800))
+        );
+        assert_eq!(
+            loader.map_ramdisk(&[], None),
+            Err(LoadKernelError::InvalidRamdisk("ramdisk is empty"))
+        );
+        // The static lies below the memory, which is allocated at runtime.
+        static OUTSIDE: [u8; 1] = [0];
+        assert_eq!(
+            loader.map_ramdisk(&OUTSIDE, None),
+            Err(LoadKernelError::InvalidRamdisk(
+                "ramdisk isn't part of the physical memory mapping"
+            ))
+        );
+
+        for addr in [fixed_start + 0x123u64, free_addr] {
+            for idx in 0..3 {
+                let TranslateResult::Mapped { frame, flags, .. } =
+                    page_table.translate(addr + idx * Size4KiB::SIZE)
+                else {
+                    panic!("ramdisk page {idx} at {addr:#x} isn't mapped");
+                };
+                assert_eq!(
+                    frame.start_address(),
+                    ramdisk_addr.align_down(Size4KiB::SIZE) + idx * Size4KiB::SIZE
+                );
+                assert_eq!(flags, Flags::PRESENT | Flags::NO_EXECUTE);
+            }
+            let after = addr.align_down(Size4KiB::SIZE) + 3 * Size4KiB::SIZE;
+            assert!(matches!(
+                page_table.translate(after),
+                TranslateResult::NotMapped
+            ));
+        }
+    }
+
+    #[test]
+    fn relocation_across_page_boundary() {
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x2000,
+            mem_size: 0x2000,
+            align: 0x1000,
+        };
+        // Two `R_AMD64_RELATIVE` relocations. The first one copies the second
+        // page, so that the pages are backed by frames in reverse order when
+        // the second one is applied across the page boundary.
+        let elf = dynamic_kernel_with(
+            0x4000,
+            &[segment, DYNAMIC],
+            &[0x2008, 8, 0, 0x1ffc, 8, 0x1122_3344_5566],
+        );
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+
+        let base = loaded.virtual_address_offset;
+        let (first, _) = loaded.translate(base + 0x1000);
+        let (second, _) = loaded.translate(base + 0x2000);
+        assert_ne!(first + Size4KiB::SIZE, second);
+        let value = (base + 0x1122_3344_5566).to_le_bytes();
+        assert_eq!(loaded.read(base + 0x1ffc, 4), value[..4]);
+        assert_eq!(loaded.read(base + 0x2000, 4), value[4..]);
+    }
+
+    #[test]
+    fn undefined_weak_symbols() {
+        let elf = |symbol_idx: u64| {
+            // `R_AMD64_64` and `R_AMD64_GLOB_DAT` relocations.
+            let info = symbol_idx << 32;
+            let mut elf = dynamic_kernel(&[0x1200, info | 1, 0x10, 0x1208, info | 6, 0]);
+            // `Rela`, `RelaSize`, `RelaEnt`, `SymTab` and `Null` entries.
+            write_u64s(
+                &mut elf,
+                0x1000,
+                &[7, 0x1100, 8, 2 * 24, 9, 24, 6, 0x1300, 0, 0],
+            );
+            // A null symbol, an undefined weak symbol and an undefined global
+            // symbol.
+            write_u64s(&mut elf, 0x1318, &[2 << 36, 0, 0, 1 << 36, 0, 0]);
+            elf
+        };
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf(1));
+        assert_eq!(loaded.relocations[0].1, 0x10);
+        assert_eq!(loaded.relocations[1].1, 0);
+
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf(2), Default::default()),
+            Err(LoadKernelError::UndefinedSymbol { idx: 2, name: None })
+        ));
+        // Symbol indices past the end of the loaded symbol table are
+        // rejected instead of being read from unmapped memory.
+        assert_eq!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf(0x100_0000), Default::default()).err(),
+            Some(LoadKernelError::TableOutOfBounds(0x1300))
+        );
+    }
+
+    #[test]
+    fn tls_relocations() {
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let tls = Phdr {
+            ty: 7, // Tls
+            flags: FLAG_R,
+            offset: 0x1800,
+            vaddr: 0x1800,
+            file_size: 0x10,
+            mem_size: 0x1c,
+            align: 0x10,
+        };
+        // `R_AMD64_DTPMOD64`, `R_AMD64_DTPOFF64` and `R_AMD64_TPOFF64`
+        // relocations without a symbol.
+        let elf = dynamic_kernel_with(
+            0x3000,
+            &[segment, DYNAMIC, tls],
+            &[0x1200, 16, 0, 0x1208, 17, 0x8, 0x1210, 18, 0x8],
+        );
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+
+        let base = loaded.virtual_address_offset;
+        let read_u64 = |addr| u64::from_le_bytes(loaded.read(addr, 8).try_into().unwrap());
+        assert_eq!(read_u64(base + 0x1200), 1);
+        assert_eq!(read_u64(base + 0x1208), 0x8);
+        // The TLS block is 0x20 bytes large after aligning it.
+        assert_eq!(read_u64(base + 0x1210) as i64, 0x8 - 0x20);
+    }
+
+    #[test]
+    fn base_address_for_position_independent_kernel() {
+        let elf = build_elf(
+            3, // shared object
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        let mut options = LoaderOptions::new_default();
+        options.base_address = Some(0xffff_9000_0000_0000);
+
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        assert_eq!(
+            loaded.virtual_address_offset,
+            0xffff_9000_0000_0000 - 0x1000
+        );
+        assert_eq!(
+            loaded.translate(0xffff_9000_0000_0000).0,
+            PhysAddr::new(0x10_1000)
+        );
+    }
+
+    #[test]
+    fn tls_template_with_base_address() {
+        const BASE: u64 = 0xffff_9000_0000_0000;
+        let elf = build_elf(
+            3, // shared object
+            0x4000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x1000,
+                    file_size: 0x2000,
+                    mem_size: 0x2000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 7, // Tls
+                    flags: FLAG_R,
+                    offset: 0x2800,
+                    vaddr: 0x2800,
+                    file_size: 0x10,
+                    mem_size: 0x20,
+                    align: 0x10,
+                },
+            ],
+        );
+        let mut options = LoaderOptions::new_default();
+        options.base_address = Some(BASE);
+
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        // The template must be moved by the same offset as the segments.
+        let tls = loaded.tls.unwrap();
+        assert_eq!(tls.start_addr, BASE + 0x1800);
+        assert_eq!(loaded.translate(tls.start_addr).0, PhysAddr::new(0x10_2800));
+    }
+
+    #[test]
+    fn load_into_new_page_table() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0,
+                    vaddr: 0x20_3000,
+                    file_size: 0,
+                    mem_size: 0x1234,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let kernel = Kernel {
+            elf: ElfFile::new(input).unwrap(),
+            config,
+            start_address: input.as_ptr(),
+            len: input.len(),
+        };
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+
+        let (level_4_frame, kernel) = unsafe {
+            load_kernel_into_new_page_table(
+                kernel,
+                &mut frame_allocator,
+                &mut used_entries,
+                options,
+            )
+        }
+        .unwrap();
+        assert_eq!(kernel.entry_point, VirtAddr::new(0x20_1000));
+        assert!(!kernel.position_independent);
+        // The range ends with the zeroed memory of the second segment.
+        assert_eq!(kernel.virtual_start, VirtAddr::new(0x20_1000));
+        assert_eq!(kernel.virtual_end_inclusive, VirtAddr::new(0x20_4233));
+
+        // The level 4 table is the first allocated frame.
+        assert_eq!(
+            level_4_frame.start_address(),
+            elf_addr + elf.len() as u64 + Size4KiB::SIZE
+        );
+        let table = memory.ptr(level_4_frame.start_address()) as *mut PageTable;
+        let page_table =
+            unsafe { OffsetPageTable::new(&mut *table, VirtAddr::new(memory.offset())) };
+        assert_eq!(
+            page_table.translate_addr(VirtAddr::new(0x20_1234)),
+            Some(PhysAddr::new(0x10_1234))
+        );
+    }
+
+    #[test]
+    fn accessed_dirty_flags() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0x2000,
+                    vaddr: 0x20_2000,
+                    file_size: 0x800,
+                    mem_size: 0x2000,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let accessed_dirty = Flags::ACCESSED | Flags::DIRTY;
+        let map_flags = |_: &ProgramHeader, flags| flags | accessed_dirty;
+        let mapped_flags = |loaded: &Loaded| {
+            [0x20_1000, 0x20_2000, 0x20_3000].map(|addr| loaded.translate(addr).1)
+        };
+
+        // Freshly mapped pages are neither accessed nor dirty.
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        for flags in mapped_flags(&loaded) {
+            assert!(!flags.intersects(accessed_dirty));
+        }
+
+        // The flags can be set on purpose, unless they must be clear.
+        let options = LoaderOptions::new_default();
+        let loaded = try_load_with_flags(0x40_0000, 0x10_0000, &elf, options, map_flags).unwrap();
+        for flags in mapped_flags(&loaded) {
+            assert!(flags.contains(accessed_dirty));
+        }
+        let mut options = LoaderOptions::new_default();
+        options.clear_accessed_dirty = true;
+        let loaded = try_load_with_flags(0x40_0000, 0x10_0000, &elf, options, map_flags).unwrap();
+        for flags in mapped_flags(&loaded) {
+            assert!(!flags.intersects(accessed_dirty));
+        }
+
+        // Pages accessed while loading are reported.
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        options.physical_memory_offset = memory.offset();
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+        loader.load_segments().unwrap();
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x20_3000));
+        let flags = loader.inner.page_table.translate(page.start_address());
+        let TranslateResult::Mapped { flags, .. } = flags else {
+            panic!("bss page is not mapped");
+        };
+        unsafe {
+            loader
+                .inner
+                .page_table
+                .update_flags(page, flags | Flags::ACCESSED)
+        }
+        .unwrap()
+        .ignore();
+        assert!(matches!(
+            loader.apply_relocations(),
+            Err(LoadKernelError::AccessedOrDirty(0x20_3000))
+        ));
+    }
+
+    #[test]
+    fn load_progress() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0x2000,
+                    vaddr: 0x20_2000,
+                    file_size: 0x800,
+                    mem_size: 0x2800,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+
+        // Progress is reported together with the other callbacks.
+        let mut calls = Vec::new();
+        let mut headers = 0;
+        loader
+            .load_segments_with(LoadCallbacks {
+                inspect: Some(&mut |_, _| {
+                    headers += 1;
+                    Ok(())
+                }),
+                progress: Some(&mut |loaded, total| calls.push((loaded, total))),
+                ..LoadCallbacks::default()
+            })
+            .unwrap();
+        assert_eq!(calls, [(0x1000, 0x3800), (0x3800, 0x3800)]);
+        assert_eq!(headers, 2);
+    }
+
+    #[test]
+    fn file_size_larger_than_mem_size() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        // The file contents would overlap the following segment.
+        let data = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x2000,
+            vaddr: 0x20_2000,
+            file_size: 0x1000,
+            mem_size: 0x800,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x4000, &[code, data]);
+        assert!(matches!(
+            loader_error(&elf),
+            LoadKernelError::InvalidSegmentSize(0x20_2000)
+        ));
+
+        let tls = Phdr {
+            ty: 7, // Tls
+            flags: FLAG_R,
+            offset: 0x1800,
+            vaddr: 0x20_1800,
+            file_size: 0x100,
+            mem_size: 0x80,
+            align: 8,
+        };
+        let elf = build_elf(2, 0x4000, &[code, tls]);
+        assert!(matches!(
+            loader_error(&elf),
+            LoadKernelError::InvalidSegmentSize(0x20_1800)
+        ));
+    }
+
+    #[test]
+    fn entry_point_override() {
+        let elf = build_elf(
+            3, // shared object
+            0x4000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0x2000,
+                    vaddr: 0x2000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let entry_point = |options: LoaderOptions| {
+            let elf_addr = PhysAddr::new(0x10_0000);
+            let mut memory = PhysicalMemory::new(0x40_0000);
+            memory.write(elf_addr, &elf);
+            let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+            let mut frame_allocator =
+                BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+            let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+            let config = BootloaderConfig::new_default();
+            let mut used_entries =
+                UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+            let options = LoaderOptions {
+                physical_memory_offset: memory.offset(),
+                base_address: Some(0x4000_0000),
+                ..options
+            };
+            let loader = Loader::from_unaligned(
+                input,
+                &mut page_table,
+                &mut frame_allocator,
+                &mut used_entries,
+                options,
+            )?;
+            Ok::<_, LoadKernelError>(loader.entry_point().as_u64())
+        };
+
+        // `build_elf` declares the start of the first segment, which is
+        // loaded at the base address.
+        let mut options = LoaderOptions::new_default();
+        assert_eq!(entry_point(options).unwrap(), 0x4000_0000);
+
+        // The override is relocated like the declared entry point.
+        options.entry_point = Some(0x1ff0);
+        assert_eq!(entry_point(options).unwrap(), 0x4000_0ff0);
+
+        // The override must be executable.
+        for addr in [0x2000, 0x3000, 0] {
+            options.entry_point = Some(addr);
+            assert!(matches!(
+                entry_point(options),
+                Err(LoadKernelError::InvalidEntryPoint(entry)) if entry == addr
+            ));
+        }
+    }
+
+    #[test]
+    fn inspect_kernel_data() {
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x2000,
+            align: 0x1000,
+        };
+        // A `R_AMD64_RELATIVE` relocation.
+        let mut elf = dynamic_kernel_with(0x3000, &[segment, DYNAMIC], &[0x1200, 8, 0x1234]);
+        write_u64s(&mut elf, 0x1208, &[0x1122_3344_5566_7788]);
+
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
+
+        let base = loader.virtual_address_offset().virtual_address_offset() as u64;
+        let virt = |addr: u64| VirtAddr::new(base + addr);
+        assert_eq!(loader.virt_to_file_offset(virt(0x1208)), Some(0x1208));
+        assert_eq!(loader.virt_to_file_offset(virt(0x1fff)), Some(0x1fff));
+        // Zeroed memory and addresses outside of the kernel have no offset.
+        assert_eq!(loader.virt_to_file_offset(virt(0x2000)), None);
+        assert_eq!(loader.virt_to_file_offset(virt(0xfff)), None);
+        assert_eq!(loader.virt_to_file_offset(VirtAddr::new(0)), None);
+
+        assert_eq!(loader.read_u64_at_virt(virt(0x1208)), None);
+        loader.load_segments().unwrap();
+        loader.apply_relocations().unwrap();
+        assert_eq!(
+            loader.read_u64_at_virt(virt(0x1208)),
+            Some(0x1122_3344_5566_7788)
+        );
+        assert_eq!(loader.read_u64_at_virt(virt(0x1200)), Some(base + 0x1234));
+        assert_eq!(loader.read_u64_at_virt(virt(0x2ff8)), Some(0));
+        // The last bytes would be read from an unmapped page.
+        assert_eq!(loader.read_u64_at_virt(virt(0x2ffc)), None);
+    }
+
+    #[test]
+    fn load_unaligned_elf() {
+        let mut elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        elf[0x1000..0x2000].fill(0xab);
+
+        // Loads the ELF file at `elf_addr` with frames at 0x20_0000 that are
+        // `frame_step` frames apart. Returns where 0x20_1234 is mapped to.
+        let load = |elf_addr: u64, frame_step: u64| -> Result<u64, LoadKernelError> {
+            let elf_addr = PhysAddr::new(elf_addr);
+            let mut memory = PhysicalMemory::new(0x40_0000);
+            memory.write(elf_addr, &elf);
+            let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+            let mut table_allocator = BumpAllocator::new(PhysAddr::new(0x30_0000), &memory);
+            let mut page_table = RecordingMapper::new(&memory, &mut table_allocator);
+            let mut next = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+            let mut frame_allocator = unsafe {
+                FnFrameAllocator::new(|| {
+                    let frame = next;
+                    next += frame_step;
+                    Some(frame)
+                })
+            };
+            let config = BootloaderConfig::new_default();
+            let mut used_entries =
+                UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+            let mut options = LoaderOptions::new_default();
+            options.physical_memory_offset = memory.offset();
+
+            let mut loader = Loader::from_unaligned(
+                input,
+                &mut page_table,
+                &mut frame_allocator,
+                &mut used_entries,
+                options,
+            )?;
+            loader.load_segments()?;
+            loader.apply_relocations()?;
+
+            let addr = page_table.translate_addr(VirtAddr::new(0x20_1234)).unwrap();
+            assert_eq!(unsafe { *memory.ptr(addr) }, 0xab);
+            Ok(addr.as_u64())
+        };
+
+        // Aligned ELF files are used directly.
+        assert_eq!(load(0x10_0000, 1).unwrap(), 0x10_1234);
+        // Unaligned ELF files are copied to the first allocated frames.
+        assert_eq!(load(0x10_0010, 1).unwrap(), 0x20_1234);
+        assert!(matches!(
+            load(0x10_0010, 2),
+            Err(LoadKernelError::NonConsecutiveFrames(frame))
+                if frame.start_address() == PhysAddr::new(0x20_2000)
+        ));
+    }
+
+    #[test]
+    fn non_canonical_segments() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let tls = Phdr {
+            ty: 7, // Tls
+            flags: FLAG_R,
+            offset: 0x1800,
+            vaddr: 0x20_1800,
+            file_size: 0x10,
+            mem_size: 0x20,
+            align: 0x10,
+        };
+        let non_canonical = 0x8000_0000_1000;
+
+        // A Load segment in the non-canonical hole.
+        let bss = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0,
+            vaddr: non_canonical,
+            file_size: 0,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x3000, &[code, bss]); // executable
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::AddressOverflow(non_canonical)
+        );
+
+        // A TLS segment in the non-canonical hole.
+        let tls = Phdr {
+            vaddr: non_canonical,
+            ..tls
+        };
+        let elf = build_elf(2, 0x3000, &[code, tls]); // executable
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::AddressOverflow(non_canonical)
+        );
+    }
+
+    #[test]
+    fn multiple_tls_segments() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x2000,
+            mem_size: 0x2000,
+            align: 0x1000,
+        };
+        let tls = Phdr {
+            ty: 7, // Tls
+            flags: FLAG_R,
+            offset: 0x2800,
+            vaddr: 0x20_2800,
+            file_size: 0x10,
+            mem_size: 0x20,
+            align: 0x10,
+        };
+
+        // Duplicated headers are tolerated.
+        let elf = build_elf(2, 0x4000, &[code, tls, tls]); // executable
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(loaded.tls.unwrap().start_addr, 0x20_2800);
+
+        let other = Phdr {
+            vaddr: 0x20_2900,
+            offset: 0x2900,
+            ..tls
+        };
+        let elf = build_elf(2, 0x4000, &[code, tls, other]); // executable
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::MultipleTlsSegments {
+                first_addr: 0x20_2800,
+                first_size: 0x20,
+                second_addr: 0x20_2900,
+                second_size: 0x20,
+            }
+        );
+    }
+
+    #[test]
+    fn partial_bss_frame_with_zeroed_frames() {
+        let mut elf = build_elf(
+            2, // executable
+            0x2000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_W | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x800,
+                mem_size: 0x1800,
+                align: 0x1000,
+            }],
+        );
+        elf[0x1000..0x1800].fill(0xab);
+        elf[0x1800..0x2000].fill(0xcd);
+        let mut options = LoaderOptions::new_default();
+        options.frames_are_zeroed = true;
+
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        // The copied frame still needs to be zeroed after the file data.
+        assert_eq!(loaded.read(0x20_1000, 0x800), [0xab; 0x800]);
+        assert_eq!(loaded.read(0x20_1800, 0x800), [0; 0x800]);
+        assert_eq!(loaded.read(0x20_2000, 0x800), [0; 0x800]);
+    }
+
+    #[test]
+    fn big_endian_kernel() {
+        let mut elf = build_elf(2, 0x1000, &[]);
+        elf[5] = 2; // ELFDATA2MSB
+        assert!(matches!(
+            loader_error(&elf),
+            LoadKernelError::UnsupportedDataEncoding(header::Data::BigEndian)
+        ));
+    }
+
+    #[test]
+    fn program_header_table_out_of_bounds() {
+        let phdr = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x100,
+            mem_size: 0x100,
+            align: 0x1000,
+        };
+
+        // `e_phoff` overflows when the table size is added.
+        let mut elf = build_elf(2, 0x2000, &[phdr]); // executable
+        elf[0x20..0x28].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::ProgramHeaderTableOutOfBounds
+        );
+
+        // `e_phnum` is too large.
+        let mut elf = build_elf(2, 0x2000, &[phdr]); // executable
+        elf[0x38..0x3a].copy_from_slice(&0x100u16.to_le_bytes());
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::ProgramHeaderTableOutOfBounds
+        );
+
+        // `e_phentsize` is too small.
+        let mut elf = build_elf(2, 0x2000, &[phdr]); // executable
+        elf[0x36..0x38].copy_from_slice(&0x20u16.to_le_bytes());
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::UnsupportedProgramHeaderSize(0x20)
+        );
+    }
+
+    #[test]
+    fn entry_point_outside_of_code() {
+        let mut elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R,
+                    offset: 0x2000,
+                    vaddr: 0x20_2000,
+                    file_size: 0x800,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let mut entry_point_error = |entry_point: u64| {
+            elf[24..32].copy_from_slice(&entry_point.to_le_bytes());
+            loader_error(&elf)
+        };
+
+        // Unmapped memory and a segment that isn't executable.
+        for entry_point in [0, 0x20_0fff, 0x20_2000, 0x20_3000] {
+            assert!(matches!(
+                entry_point_error(entry_point),
+                LoadKernelError::InvalidEntryPoint(addr) if addr == entry_point
+            ));
+        }
+    }
+
+    #[test]
+    fn expected_abi() {
+        let mut elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        elf[7] = 0xff; // standalone application
+        elf[8] = 2;
+
+        // Any ABI is accepted by default.
+        let mut options = LoaderOptions::new_default();
+        load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        options.expected_os_abi = Some(0xff);
+        options.expected_abi_version = Some(2);
+        load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        for (os_abi, abi_version) in [(0, 2), (0xff, 1)] {
+            options.expected_os_abi = Some(os_abi);
+            options.expected_abi_version = Some(abi_version);
+            assert!(matches!(
+                try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+                Err(LoadKernelError::UnexpectedAbi {
+                    os_abi: 0xff,
+                    abi_version: 2
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn zero_volatile_ranges() {
+        for (start, len) in [(0, 64), (3, 0), (3, 2), (5, 30), (8, 16), (1, 62)] {
+            let mut buf = [u64::MAX; 8];
+            let bytes = buf.as_mut_ptr().cast::<u8>();
+            unsafe { zero_volatile(bytes.add(start), len) };
+            let bytes = unsafe { core::slice::from_raw_parts(bytes, 64) };
+            for (i, &byte) in bytes.iter().enumerate() {
+                let zeroed = (start..start + len).contains(&i);
+                assert_eq!(byte, if zeroed { 0 } else { 0xff }, "{start} {len} {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn slice_at_bounds() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        let elf_file = ElfFile::new(&elf).unwrap();
+
+        assert_eq!(slice_at(&elf_file, 0x1000, 0x2000).unwrap().len(), 0x2000);
+        assert!(slice_at(&elf_file, 0x3000, 0).unwrap().is_empty());
+        for (offset, len) in [(0x1000, 0x2001), (0x3001, 0), (u64::MAX, 2), (1, u64::MAX)] {
+            assert!(matches!(
+                slice_at(&elf_file, offset, len),
+                Err(LoadKernelError::InvalidElf("file offset out of range"))
+            ));
+        }
+    }
+
+    #[test]
+    fn gnu_property_note() {
+        let elf = |note: &[u8]| {
+            let mut elf = build_elf(
+                2, // executable
+                0x3000,
+                &[
+                    Phdr {
+                        ty: 1, // Load
+                        flags: FLAG_R | FLAG_X,
+                        offset: 0x1000,
+                        vaddr: 0x20_1000,
+                        file_size: 0x1000,
+                        mem_size: 0x1000,
+                        align: 0x1000,
+                    },
+                    Phdr {
+                        ty: PT_GNU_PROPERTY,
+                        flags: FLAG_R,
+                        offset: 0x2000,
+                        vaddr: 0x20_2000,
+                        file_size: note.len() as u64,
+                        mem_size: note.len() as u64,
+                        align: 8,
+                    },
+                ],
+            );
+            elf[0x2000..][..note.len()].copy_from_slice(note);
+            elf
+        };
+        let words = |words: &[u32]| -> Vec<u8> {
+            words.iter().flat_map(|word| word.to_le_bytes()).collect()
+        };
+        let gnu = u32::from_le_bytes(*b"GNU\0");
+
+        // The ISA property is skipped.
+        let note = words(&[
+            4,
+            32,
+            5,
+            gnu, // header and name
+            0xc000_8002,
+            4,
+            1,
+            0, // GNU_PROPERTY_X86_ISA_1_NEEDED
+            0xc000_0002,
+            4,
+            3,
+            0, // GNU_PROPERTY_X86_FEATURE_1_AND
+        ]);
+        let features = load(0x40_0000, 0x10_0000, &elf(&note)).x86_features;
+        assert!(features.ibt() && features.shadow_stack());
+        assert_eq!(features.bits(), 3);
+
+        // Notes of other owners are skipped.
+        let note = words(&[4, 16, 5, 0, 0xc000_0002, 4, 2, 0]);
+        let features = load(0x40_0000, 0x10_0000, &elf(&note)).x86_features;
+        assert_eq!(features, X86Features::default());
+
+        let note = words(&[4, 16, 5, gnu, 0xc000_0002, 4, 2, 0]);
+        let features = load(0x40_0000, 0x10_0000, &elf(&note)).x86_features;
+        assert!(!features.ibt() && features.shadow_stack());
+
+        // The property data ends after the note.
+        let note = words(&[4, 16, 5, gnu, 0xc000_0002, 12, 2, 0]);
+        assert!(matches!(
+            loader_error(&elf(&note)),
+            LoadKernelError::InvalidElf("invalid GNU property note")
+        ));
+    }
+
+    #[test]
+    fn section_headers_of_executables_are_ignored() {
+        let mut elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        // A section header table with entries that are too short.
+        elf[40..48].copy_from_slice(&0x200u64.to_le_bytes()); // e_shoff
+        elf[58..60].copy_from_slice(&8u16.to_le_bytes()); // e_shentsize
+        elf[60..62].copy_from_slice(&3u16.to_le_bytes()); // e_shnum
+
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(loaded.x86_features, X86Features::default());
+    }
+
+    #[test]
+    fn mismatched_relocation_entry_size() {
+        let elf = |entry_size: u64| {
+            let mut elf = build_elf(
+                3, // shared object
+                0x3000,
+                &[
+                    Phdr {
+                        ty: 1, // Load
+                        flags: FLAG_R | FLAG_W | FLAG_X,
+                        offset: 0x1000,
+                        vaddr: 0x1000,
+                        file_size: 0x1000,
+                        mem_size: 0x1000,
+                        align: 0x1000,
+                    },
+                    Phdr {
+                        ty: 2, // Dynamic
+                        flags: FLAG_R | FLAG_W,
+                        offset: 0x1000,
+                        vaddr: 0x1000,
+                        file_size: 0x40,
+                        mem_size: 0x40,
+                        align: 8,
+                    },
+                ],
+            );
+            // `Rela`, `RelaSize`, `RelaEnt` and `Null` entries.
+            for (i, value) in [7, 0x1100, 8, 2 * 16, 9, entry_size, 0, 0]
+                .iter()
+                .enumerate()
+            {
+                elf[0x1000 + i * 8..][..8].copy_from_slice(&u64::to_le_bytes(*value));
+            }
+            elf
+        };
+
+        // The entry size of `Rel` relocations, and a zero entry size, which
+        // must not be used as a divisor.
+        for entry_size in [16, 0] {
+            let result = try_load_with_options(
+                0x40_0000,
+                0x10_0000,
+                &elf(entry_size),
+                LoaderOptions::new_default(),
+            );
+            let Err(err) = result else {
+                panic!("the kernel was loaded");
+            };
+            assert!(matches!(
+                err,
+                LoadKernelError::UnsupportedEntrySize {
+                    tag: "RelaEnt",
+                    entry_size: size,
+                    expected: 24,
+                } if size == entry_size
+            ));
+        }
+    }
+
+    #[test]
+    fn huge_bss_pages() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0,
+                    vaddr: 0x40_0000,
+                    file_size: 0,
+                    mem_size: 0x20_1000,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let mut options = LoaderOptions::new_default();
+        options.huge_bss_pages = true;
+        let bss_sizes = |loaded: &Loaded| {
+            loaded
+                .page_table
+                .calls
+                .iter()
+                .filter_map(|call| match *call {
+                    Call::Map { page, size, .. } if page >= 0x40_0000 => Some(size),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Four frames are allocated for page tables before the bss frames,
+        // which start at 2 MiB then.
+        let loaded = load_with_options(0x80_0000, 0x1f_8000, &elf, options);
+        assert_eq!(bss_sizes(&loaded), [Size2MiB::SIZE, Size4KiB::SIZE]);
+        assert_eq!(loaded.translate(0x40_0000).0, PhysAddr::new(0x20_0000));
+        assert_eq!(loaded.translate(0x5f_ffff).0, PhysAddr::new(0x3f_ffff));
+        assert_eq!(loaded.read(0x5f_fff0, 16), [0; 16]);
+
+        // Unaligned frames are mapped with 4 KiB pages.
+        let loaded = load_with_options(0x80_0000, 0x1f_9000, &elf, options);
+        assert_eq!(bss_sizes(&loaded), [Size4KiB::SIZE; 0x201]);
+    }
+
+    #[test]
+    fn copy_segments() {
+        let mut elf = build_elf(
+            2, // executable
+            0x2000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x800,
+                mem_size: 0x1800,
+                align: 0x1000,
+            }],
+        );
+        elf[0x1000..0x1800].fill(0xcc);
+        let mut options = LoaderOptions::new_default();
+        options.copy_segments = true;
+
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        // The segment is mapped to new frames outside of the ELF file.
+        for addr in [0x20_1000, 0x20_2000] {
+            let (phys, flags) = loaded.translate(addr);
+            assert!(phys.as_u64() >= 0x10_2000);
+            assert_eq!(flags, Flags::PRESENT);
+        }
+        assert_eq!(loaded.read(0x20_17ff, 1), [0xcc]);
+        assert_eq!(loaded.read(0x20_1800, 1), [0]);
+    }
+
+    #[test]
+    fn global_pages() {
+        let mut elf = build_elf(
+            2, // executable
+            0x2000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_W | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x800,
+                mem_size: 0x1800,
+                align: 0x1000,
+            }],
+        );
+        elf[0x1000..0x1800].fill(0xab);
+
+        // No page is global by default.
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert!(!loaded.page_table.calls.is_empty());
+        for call in &loaded.page_table.calls {
+            if let Call::Map { flags, .. } = call {
+                assert!(!flags.contains(Flags::GLOBAL));
+            }
+        }
+        for addr in [0x20_1000, 0x20_2000] {
+            assert!(!loaded.translate(addr).1.contains(Flags::GLOBAL));
+        }
+
+        let mut options = LoaderOptions::new_default();
+        options.global_pages = true;
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+
+        // Both the copied data page and the bss page are global.
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::GLOBAL;
+        assert_eq!(loaded.translate(0x20_1000).1, flags);
+        assert_eq!(loaded.translate(0x20_2000).1, flags);
+    }
+
+    #[test]
+    fn mapped_segment_flags() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x800,
+                    mem_size: 0x800,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R,
+                    offset: 0x2000,
+                    vaddr: 0x20_3000,
+                    file_size: 0x800,
+                    mem_size: 0x1800,
+                    align: 0x1000,
+                },
+            ],
+        );
+
+        // Make code global and data writable, and try to unmap the data.
+        let loaded = try_load_with_flags(
+            0x40_0000,
+            0x10_0000,
+            &elf,
+            LoaderOptions::new_default(),
+            |segment, flags| {
+                if segment.flags().is_execute() {
+                    flags | Flags::GLOBAL
+                } else {
+                    (flags | Flags::WRITABLE) & !Flags::PRESENT
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            loaded.translate(0x20_1000).1,
+            Flags::PRESENT | Flags::GLOBAL
+        );
+        // The flags apply to both the data page and the bss page.
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+        assert_eq!(loaded.translate(0x20_3000).1, flags);
+        assert_eq!(loaded.translate(0x20_4000).1, flags);
+    }
+
+    #[test]
+    fn segment_flags_for_ranges() {
+        let elf = build_elf(
+            2, // executable
+            0x4000,
+            &[
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W,
+                    offset: 0x2000,
+                    vaddr: 0x20_3000,
+                    file_size: 0x800,
+                    mem_size: 0x1800,
+                    align: 0x1000,
+                },
+            ],
+        );
+        let uncached = Flags::NO_CACHE | Flags::WRITE_THROUGH;
+
+        // The range only overlaps the bss page of the second segment.
+        let ranges = [(0x20_47ff..0x20_4800, uncached)];
+        let loaded = try_load_with_flags(
+            0x40_0000,
+            0x10_0000,
+            &elf,
+            LoaderOptions::new_default(),
+            range_flags(&ranges),
+        )
+        .unwrap();
+
+        assert_eq!(loaded.translate(0x20_1000).1, Flags::PRESENT);
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+        assert_eq!(loaded.translate(0x20_3000).1, flags | uncached);
+        assert_eq!(loaded.translate(0x20_4000).1, flags | uncached);
+    }
+
+    #[test]
+    fn touch_pages() {
+        let elf = build_elf(
+            3, // shared object
+            0x2000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_W | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x1000,
+                file_size: 0x800,
+                mem_size: 0x2800,
+                align: 0x1000,
+            }],
+        );
+        let mut options = LoaderOptions::new_default();
+        options.touch_pages = true;
+
+        // Both the page of the ELF file and the bss pages are checked.
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        let base = loaded.virtual_address_offset;
+        assert_eq!(
+            loaded.translate(base + 0x3000).1,
+            Flags::PRESENT | Flags::WRITABLE
+        );
     }
 
-    /// This method is intended for making the memory loaded by a Load segment mutable.
-    ///
-    /// All memory from a Load segment starts out by mapped to the same frames that
-    /// contain the elf file. Thus writing to memory in that state will cause aliasing issues.
-    /// To avoid that, we allocate a new frame, copy all bytes from the old frame to the new frame,
-    /// and remap the page to the new frame. At this point the page no longer aliases the elf file
-    /// and we can write to it.
-    ///
-    /// When we map the new frame we also set [`COPIED`] flag in the page table flags, so that
-    /// we can detect if the frame has already been copied when we try to modify the page again.
-    ///
-    /// ## Safety
-    /// - `page` should be a page mapped by a Load segment.
-    ///  
-    /// ## Panics
-    /// Panics if the page is not mapped in `self.page_table`.
-    unsafe fn make_mut(&mut self, page: Page) -> PhysFrame {
-        let (frame, flags) = match self.page_table.translate(page.start_address()) {
-            TranslateResult::Mapped {
+    #[test]
+    fn bss_after_page_aligned_file_data() {
+        const ELF_ADDR: u64 = 0x10_0000;
+        // The memory ends within the last page or exactly at its end.
+        for mem_size in [0x2800, 0x3000] {
+            let elf = build_elf(
+                2, // executable
+                0x3000,
+                &[Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_W | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x20_1000,
+                    file_size: 0x1000,
+                    mem_size,
+                    align: 0x1000,
+                }],
+            );
+
+            let loaded = load(0x40_0000, ELF_ADDR, &elf);
+
+            // The data page isn't copied and the bss starts on a new page.
+            let flags = Flags::PRESENT | Flags::WRITABLE;
+            let map = |page, frame| Call::Map {
+                page,
                 frame,
-                offset: _,
+                size: Size4KiB::SIZE,
                 flags,
-            } => (frame, flags),
-            TranslateResult::NotMapped => panic!("{:?} is not mapped", page),
-            TranslateResult::InvalidFrameAddress(_) => unreachable!(),
-        };
-        let frame = if let MappedFrame::Size4KiB(frame) = frame {
-            frame
-        } else {
-            // We only map 4k pages.
-            unreachable!()
+            };
+            let calls = &loaded.page_table.calls;
+            assert_eq!(calls.len(), 3, "mem_size {mem_size:#x}");
+            assert_eq!(calls[0], map(0x20_1000, ELF_ADDR + 0x1000));
+            let bss_pages: Vec<_> = calls[1..]
+                .iter()
+                .map(|call| match *call {
+                    Call::Map { page, flags, .. } => (page, flags),
+                    Call::Unmap { .. } => panic!("unexpected unmap"),
+                })
+                .collect();
+            assert_eq!(bss_pages, [(0x20_2000, flags), (0x20_3000, flags)]);
+            assert_eq!(loaded.read(0x20_2000, 0x1000), [0; 0x1000]);
+        }
+    }
+
+    #[test]
+    fn identity_mapped_position_independent_kernel() {
+        const ELF_ADDR: u64 = 0x10_0000;
+        let segments = |data_offset| {
+            [
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R | FLAG_X,
+                    offset: 0x1000,
+                    vaddr: 0x1000,
+                    file_size: 0x1000,
+                    mem_size: 0x1000,
+                    align: 0x1000,
+                },
+                Phdr {
+                    ty: 1, // Load
+                    flags: FLAG_R,
+                    offset: data_offset,
+                    vaddr: 0x2000,
+                    file_size: 0x800,
+                    mem_size: 0x800,
+                    align: 0x1000,
+                },
+            ]
         };
+        let mut options = LoaderOptions::new_default();
+        options.identity_map = true;
 
-        if flags.contains(COPIED) {
-            // The frame was already copied, we are free to modify it.
-            return frame;
+        let elf = build_elf(3, 0x4000, &segments(0x2000));
+        let loaded = load_with_options(0x40_0000, ELF_ADDR, &elf, options);
+        assert_eq!(loaded.virtual_address_offset, ELF_ADDR);
+        for addr in [ELF_ADDR + 0x1000, ELF_ADDR + 0x2000] {
+            assert_eq!(loaded.translate(addr).0, PhysAddr::new(addr));
         }
 
-        // Allocate a new frame and copy the memory, utilizing that both frames are identity mapped.
-        let new_frame = self.frame_allocator.allocate_frame().unwrap();
-        let frame_ptr = frame.start_address().as_u64() as *const u8;
-        let new_frame_ptr = new_frame.start_address().as_u64() as *mut u8;
-        unsafe {
-            core::ptr::copy_nonoverlapping(frame_ptr, new_frame_ptr, Size4KiB::SIZE as usize);
-        }
+        // The distance between the segments differs in memory and the file.
+        let elf = build_elf(3, 0x4000, &segments(0x3000));
+        let result = try_load_with_options(0x40_0000, ELF_ADDR, &elf, options);
+        assert!(matches!(
+            result,
+            Err(LoadKernelError::IdentityMappingImpossible(0x2000))
+        ));
+    }
 
-        // Replace the underlying frame and update the flags.
-        self.page_table.unmap(page).unwrap().1.ignore();
-        let new_flags = flags | COPIED;
-        unsafe {
-            self.page_table
-                .map_to(page, new_frame, new_flags, self.frame_allocator)
-                .unwrap()
-                .ignore();
+    /// Builds a relocatable object file with `.text`, `.data` and `.bss`
+    /// sections.
+    ///
+    /// `.text` contains a PC32 relocation against a symbol in `.data` and
+    /// `.data` contains a 64-bit relocation against a symbol in `.bss`.
+    fn build_relocatable(bss_size: u64) -> Vec<u8> {
+        let mut elf = build_elf(1, 0x600, &[]);
+        elf[40..48].copy_from_slice(&0x300u64.to_le_bytes()); // e_shoff
+        elf[58..60].copy_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        elf[60..62].copy_from_slice(&9u16.to_le_bytes()); // e_shnum
+        elf[62..64].copy_from_slice(&8u16.to_le_bytes()); // e_shstrndx
+
+        // .text and .data
+        elf[0x100..0x110].copy_from_slice(&[0x90; 16]);
+        elf[0x140..0x150].copy_from_slice(&[0xaa; 16]);
+
+        // .symtab: a local symbol in .data, a local symbol in .bss and _start
+        let symbols: [(u32, u8, u16, u64); 4] = [
+            (0, 0, 0, 0),
+            (0, 0x01, 2, 8),
+            (0, 0x01, 3, 0x10),
+            (1, 0x12, 1, 0),
+        ];
+        for (i, (name, info, shndx, value)) in symbols.into_iter().enumerate() {
+            let start = 0x180 + i * 24;
+            elf[start..start + 4].copy_from_slice(&name.to_le_bytes());
+            elf[start + 4] = info;
+            elf[start + 6..start + 8].copy_from_slice(&shndx.to_le_bytes());
+            elf[start + 8..start + 16].copy_from_slice(&value.to_le_bytes());
         }
+        elf[0x200..0x208].copy_from_slice(b"\0_start\0");
 
-        new_frame
-    }
+        // .rela.text and .rela.data
+        write_u64s(&mut elf, 0x220, &[4, 1 << 32 | 2, -4i64 as u64]);
+        write_u64s(&mut elf, 0x240, &[0, 2 << 32 | 1, 8]);
 
-    /// Cleans up the custom flags set by [`Inner::make_mut`].
-    fn remove_copied_flags(&mut self, elf_file: &ElfFile) -> Result<(), &'static str> {
-        for program_header in elf_file.program_iter() {
-            if let Type::Load = program_header.get_type()? {
-                let start = self.virtual_address_offset + program_header.virtual_addr();
-                let end = start + program_header.mem_size();
-                let start = VirtAddr::new(start);
-                let end = VirtAddr::new(end);
-                let start_page = Page::containing_address(start);
-                let end_page = Page::containing_address(end - 1u64);
-                for page in Page::<Size4KiB>::range_inclusive(start_page, end_page) {
-                    // Translate the page and get the flags.
-                    let res = self.page_table.translate(page.start_address());
-                    let flags = match res {
-                        TranslateResult::Mapped {
-                            frame: _,
-                            offset: _,
-                            flags,
-                        } => flags,
-                        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
-                            unreachable!("has the elf file not been mapped correctly?")
-                        }
-                    };
+        let names = b"\0.text\0.data\0.bss\0.symtab\0.strtab\0.rela.text\0.rela.data\0.shstrtab\0";
+        elf[0x280..0x280 + names.len()].copy_from_slice(names);
 
-                    if flags.contains(COPIED) {
-                        // Remove the flag.
-                        unsafe {
-                            self.page_table
-                                .update_flags(page, flags & !COPIED)
-                                .unwrap()
-                                .ignore();
-                        }
-                    }
-                }
-            }
+        // name, type, flags, offset, size, link, info, align, entry size
+        let headers = [
+            (0u32, 0u32, 0u64, 0u64, 0u64, 0u32, 0u32, 0u64, 0u64),
+            (1, 1, 0x6, 0x100, 0x10, 0, 0, 16, 0),
+            (7, 1, 0x3, 0x140, 0x10, 0, 0, 8, 0),
+            (13, 8, 0x3, 0x150, bss_size, 0, 0, 8, 0),
+            (18, 2, 0, 0x180, 4 * 24, 5, 3, 8, 24),
+            (26, 3, 0, 0x200, 8, 0, 0, 1, 0),
+            (34, 4, 0, 0x220, 24, 4, 1, 8, 24),
+            (45, 4, 0, 0x240, 24, 4, 2, 8, 24),
+            (56, 3, 0, 0x280, names.len() as u64, 0, 0, 1, 0),
+        ];
+        for (i, header) in headers.into_iter().enumerate() {
+            let (name, ty, flags, offset, size, link, info, align, entry_size) = header;
+            let start = 0x300 + i * 64;
+            elf[start..start + 4].copy_from_slice(&name.to_le_bytes());
+            elf[start + 4..start + 8].copy_from_slice(&ty.to_le_bytes());
+            elf[start + 8..start + 16].copy_from_slice(&flags.to_le_bytes());
+            elf[start + 24..start + 32].copy_from_slice(&offset.to_le_bytes());
+            elf[start + 32..start + 40].copy_from_slice(&size.to_le_bytes());
+            elf[start + 40..start + 44].copy_from_slice(&link.to_le_bytes());
+            elf[start + 44..start + 48].copy_from_slice(&info.to_le_bytes());
+            elf[start + 48..start + 56].copy_from_slice(&align.to_le_bytes());
+            elf[start + 56..start + 64].copy_from_slice(&entry_size.to_le_bytes());
         }
-        Ok(())
+        elf
     }
 
-    fn handle_tls_segment(&mut self, segment: ProgramHeader) -> Result<TlsTemplate, &'static str> {
-        Ok(TlsTemplate {
-            start_addr: self.virtual_address_offset + segment.virtual_addr(),
-            mem_size: segment.mem_size(),
-            file_size: segment.file_size(),
-        })
+    #[test]
+    fn relocatable_kernel() {
+        let loaded = load(0x10_0000, 0x1000, &build_relocatable(0x20));
+        let text = loaded.virtual_address_offset;
+        let data = text + 0x1000;
+        let bss = text + 0x2000;
+
+        // PC32: data + 8 - 4 - (text + 4)
+        // R_64: bss + 0x10 + 8
+        assert_eq!(loaded.relocations, [(text + 4, 0x1000), (data, bss + 0x18)]);
+        assert_eq!(loaded.read(text, 4), [0x90; 4]);
+        assert_eq!(loaded.read(text + 4, 4), 0x1000u32.to_le_bytes());
+        assert_eq!(loaded.read(text + 8, 8), [0x90; 8]);
+        assert_eq!(loaded.read(data, 8), (bss + 0x18).to_le_bytes());
+        assert_eq!(loaded.read(data + 8, 8), [0xaa; 8]);
+        assert_eq!(loaded.read(bss, 0x20), [0; 0x20]);
+
+        assert!(!loaded.translate(text).1.contains(Flags::WRITABLE));
+        assert!(loaded.translate(data).1.contains(Flags::WRITABLE));
+        assert!(loaded.translate(bss).1.contains(Flags::WRITABLE));
     }
 
-    fn handle_dynamic_segment(
-        &mut self,
-        segment: ProgramHeader,
-        elf_file: &ElfFile,
-    ) -> Result<(), &'static str> {
-        let data = segment.get_data(elf_file)?;
-        let data = if let SegmentData::Dynamic64(data) = data {
-            data
-        } else {
-            panic!("expected Dynamic64 segment")
-        };
+    #[test]
+    fn relocatable_section_header_size() {
+        let mut elf = build_relocatable(0x20);
+        elf[58..60].copy_from_slice(&8u16.to_le_bytes()); // e_shentsize
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::UnsupportedSectionHeaderSize(8)
+        );
 
-        // Find the `Rela`, `RelaSize` and `RelaEnt` entries.
-        let mut rela = None;
-        let mut rela_size = None;
-        let mut rela_ent = None;
-        for rel in data {
-            let tag = rel.get_tag()?;
-            match tag {
-                dynamic::Tag::Rela => {
-                    let ptr = rel.get_ptr()?;
-                    let prev = rela.replace(ptr);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one Rela entry");
-                    }
-                }
-                dynamic::Tag::RelaSize => {
-                    let val = rel.get_val()?;
-                    let prev = rela_size.replace(val);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one RelaSize entry");
-                    }
-                }
-                dynamic::Tag::RelaEnt => {
-                    let val = rel.get_val()?;
-                    let prev = rela_ent.replace(val);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one RelaEnt entry");
-                    }
-                }
-                _ => {}
-            }
-        }
-        let offset = if let Some(rela) = rela {
-            rela
-        } else {
-            // The section doesn't contain any relocations.
+        let mut elf = build_relocatable(0x20);
+        elf[60..62].copy_from_slice(&100u16.to_le_bytes()); // e_shnum
+        assert_eq!(
+            loader_error(&elf),
+            LoadKernelError::SectionHeaderTableOutOfBounds
+        );
+    }
 
-            if rela_size.is_some() || rela_ent.is_some() {
-                return Err("Rela entry is missing but RelaSize or RelaEnt have been provided");
-            }
+    #[test]
+    fn relocatable_sections_overflow() {
+        assert_eq!(
+            loader_error(&build_relocatable(u64::MAX - 0x100)),
+            LoadKernelError::InvalidElf("sections don't fit into the address space")
+        );
+    }
 
-            return Ok(());
+    #[test]
+    fn required_frames_bounds() {
+        let required = |elf: &[u8]| {
+            let kernel = Kernel {
+                elf: ElfFile::new(elf).unwrap(),
+                config: BootloaderConfig::new_default(),
+                start_address: elf.as_ptr(),
+                len: elf.len(),
+            };
+            required_frames(&kernel)
         };
-        let total_size = rela_size.ok_or("RelaSize entry is missing")?;
-        let entry_size = rela_ent.ok_or("RelaEnt entry is missing")?;
 
-        // Make sure that the reported size matches our `Rela<u64>`.
+        // One frame per section and two page tables per level.
         assert_eq!(
-            entry_size,
-            size_of::<Rela<u64>>() as u64,
-            "unsupported entry size: {entry_size}"
+            required(&build_relocatable(0x20)),
+            Ok(RequiredFrames {
+                memory: 3,
+                relocations: 0,
+                page_tables: 6,
+            })
+        );
+        assert_eq!(
+            required(&build_relocatable(u64::MAX - 0x100)),
+            Err(LoadKernelError::InvalidElf(
+                "sections don't fit into the address space"
+            ))
+        );
+        let mut elf = build_relocatable(0x20);
+        elf[58..60].copy_from_slice(&8u16.to_le_bytes()); // e_shentsize
+        assert_eq!(
+            required(&elf),
+            Err(LoadKernelError::UnsupportedSectionHeaderSize(8))
         );
 
-        // Apply the relocations.
-        let num_entries = total_size / entry_size;
-        for idx in 0..num_entries {
-            let rela = self.read_relocation(offset, idx);
-            self.apply_relocation(rela, elf_file)?;
-        }
+        let segment = |vaddr, mem_size| Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x1000,
+            vaddr,
+            file_size: 0x10,
+            mem_size,
+            align: 0x1000,
+        };
+        assert_eq!(
+            required(&build_elf(2, 0x2000, &[segment(u64::MAX - 0xfff, 0x2000)])),
+            Err(LoadKernelError::InvalidSegmentSize(u64::MAX - 0xfff))
+        );
+        assert_eq!(
+            required(&build_elf(2, 0x2000, &[segment(0x1000, 0x3000)])),
+            Ok(RequiredFrames {
+                memory: 3,
+                relocations: 0,
+                page_tables: 6,
+            })
+        );
 
-        Ok(())
+        // A trailing partial dynamic entry is ignored.
+        let load = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let dynamic = Phdr {
+            file_size: 0x48,
+            ..DYNAMIC
+        };
+        let relocation = [0x1800, 8, 0]; // R_AMD64_RELATIVE
+        assert_eq!(
+            required(&dynamic_kernel_with(0x3000, &[load, dynamic], &relocation)),
+            Ok(RequiredFrames {
+                memory: 0,
+                relocations: 1,
+                page_tables: 6,
+            })
+        );
     }
 
-    /// Reads a relocation from a relocation table.
-    fn read_relocation(&self, relocation_table: u64, idx: u64) -> Rela<u64> {
-        // Calculate the address of the entry in the relocation table.
-        let offset = relocation_table + size_of::<Rela<u64>>() as u64 * idx;
-        let value = self.virtual_address_offset + offset;
-        let addr = VirtAddr::try_new(value).expect("relocation table is outside the address space");
+    #[test]
+    fn kernel_at_end_of_lower_half() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x7fff_ffff_f000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
+        )
+        .unwrap();
 
-        // Read the Rela from the kernel address space.
-        let mut buf = [0; 24];
-        self.copy_from(addr, &mut buf);
+        // The exclusive end 0x8000_0000_0000 isn't canonical.
+        let start = VirtAddr::new(0x7fff_ffff_f000);
+        let end = VirtAddr::new(0x7fff_ffff_ffff);
+        assert_eq!(loader.virtual_range(), Some(start..=end));
 
-        // Convert the bytes we read into a `Rela<u64>`.
-        unsafe {
-            // SAFETY: Any bitpattern is valid for `Rela<u64>` and buf is
-            // valid for reads.
-            core::ptr::read_unaligned(&buf as *const u8 as *const Rela<u64>)
-        }
+        let tls = loader.load_segments().unwrap();
+        loader.apply_relocations().unwrap();
+        let kernel = loader.loaded_kernel(tls).unwrap();
+        assert_eq!(kernel.virtual_start, start);
+        assert_eq!(kernel.virtual_end_inclusive, end);
     }
 
-    fn apply_relocation(
-        &mut self,
-        rela: Rela<u64>,
-        elf_file: &ElfFile,
-    ) -> Result<(), &'static str> {
-        let symbol_idx = rela.get_symbol_table_index();
-        assert_eq!(
-            symbol_idx, 0,
-            "relocations using the symbol table are not supported"
+    #[test]
+    fn kaslr_offset() {
+        let elf = build_elf(
+            3, // shared object
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
         );
+        let mut options = LoaderOptions::new_default();
+        options.base_address = Some(0xffff_9000_0000_0000);
+        options.kaslr_offset = 0x40_0000;
 
-        match rela.get_type() {
-            // R_AMD64_RELATIVE
-            8 => {
-                // Make sure that the relocation happens in memory mapped
-                // by a Load segment.
-                check_is_in_load(elf_file, rela.get_offset())?;
+        // The kernel is moved up from the base address.
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        assert_eq!(
+            loaded.virtual_address_offset,
+            0xffff_9000_0040_0000 - 0x1000
+        );
+        assert_eq!(
+            loaded.translate(0xffff_9000_0040_0000).0,
+            PhysAddr::new(0x10_1000)
+        );
 
-                // Calculate the destination of the relocation.
-                let addr = self.virtual_address_offset + rela.get_offset();
-                let addr = VirtAddr::new(addr);
+        // The offset must be 2 MiB aligned.
+        options.kaslr_offset = 0x40_1000;
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::InvalidKaslrOffset(0x40_1000))
+        ));
+    }
 
-                // Calculate the relocated value.
-                let value = self.virtual_address_offset + rela.get_addend();
+    #[test]
+    fn enforce_write_xor_execute() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let data = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x2000,
+            vaddr: 0x20_2000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let mut options = LoaderOptions::new_default();
+        options.enforce_write_xor_execute = true;
 
-                // Write the relocated value to memory.
-                unsafe {
-                    // SAFETY: We just verified that the address is in a Load segment.
-                    self.copy_to(addr, &value.to_ne_bytes());
-                }
-            }
-            ty => unimplemented!("relocation type {:x} not supported", ty),
-        }
+        let elf = build_elf(2, 0x4000, &[code, data]);
+        load_with_options(0x40_0000, 0x10_0000, &elf, options);
 
-        Ok(())
+        // Writable and executable segments are only rejected if enabled.
+        let rwx = Phdr {
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            ..data
+        };
+        let elf = build_elf(2, 0x4000, &[code, rwx]);
+        load(0x40_0000, 0x10_0000, &elf);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::WritableAndExecutable(0x20_2000))
+        ));
     }
 
-    /// Mark a region of memory indicated by a GNU_RELRO segment as read-only.
-    ///
-    /// This is a security mitigation used to protect memory regions that
-    /// need to be writable while applying relocations, but should never be
-    /// written to after relocations have been applied.
-    fn handle_relro_segment(&mut self, program_header: ProgramHeader) {
-        let start = self.virtual_address_offset + program_header.virtual_addr();
-        let end = start + program_header.mem_size();
-        let start = VirtAddr::new(start);
-        let end = VirtAddr::new(end);
-        let start_page = Page::containing_address(start);
-        let end_page = Page::containing_address(end - 1u64);
-        for page in Page::<Size4KiB>::range_inclusive(start_page, end_page) {
-            // Translate the page and get the flags.
-            let res = self.page_table.translate(page.start_address());
-            let flags = match res {
-                TranslateResult::Mapped {
-                    frame: _,
-                    offset: _,
-                    flags,
-                } => flags,
-                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
-                    unreachable!("has the elf file not been mapped correctly?")
-                }
-            };
+    #[test]
+    fn overlapping_segments() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x800,
+            mem_size: 0x800,
+            align: 0x1000,
+        };
+        let data = |vaddr: u64| Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: vaddr - 0x20_0000,
+            vaddr,
+            file_size: 0x800,
+            mem_size: 0x800,
+            align: 0x1000,
+        };
 
-            if flags.contains(Flags::WRITABLE) {
-                // Remove the WRITABLE flag.
-                unsafe {
-                    self.page_table
-                        .update_flags(page, flags & !Flags::WRITABLE)
-                        .unwrap()
-                        .ignore();
-                }
-            }
-        }
-    }
-}
+        // Segments in adjacent pages are fine.
+        let elf = build_elf(2, 0x4000, &[code, data(0x20_2800)]);
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(loaded.translate(0x20_2800).0, PhysAddr::new(0x10_2800));
 
-/// Check that the virtual offset belongs to a load segment.
-fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static str> {
-    for program_header in elf_file.program_iter() {
-        if let Type::Load = program_header.get_type()? {
-            if program_header.virtual_addr() <= virt_offset {
-                let offset_in_segment = virt_offset - program_header.virtual_addr();
-                if offset_in_segment < program_header.mem_size() {
-                    return Ok(());
-                }
-            }
-        }
+        // Segments sharing a page would be mapped twice.
+        let elf = build_elf(2, 0x4000, &[code, data(0x20_1800)]);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, LoaderOptions::new_default()),
+            Err(LoadKernelError::OverlappingSegments {
+                first: 0x20_1000,
+                second: 0x20_1800
+            })
+        ));
     }
-    Err("offset is not in load segment")
-}
 
-/// Loads the kernel ELF file given in `bytes` in the given `page_table`.
-///
-/// Returns the kernel entry point address, it's thread local storage template (if any),
-/// and a structure describing which level 4 page table entries are in use.  
-pub fn load_kernel(
-    kernel: Kernel<'_>,
-    page_table: &mut (impl MapperAllSizes + Translate),
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    used_entries: &mut UsedLevel4Entries,
-) -> Result<(VirtAddr, VirtAddr, Option<TlsTemplate>), &'static str> {
-    let mut loader = Loader::new(kernel, page_table, frame_allocator, used_entries)?;
-    let tls_template = loader.load_segments()?;
+    #[test]
+    fn misaligned_segment() {
+        let elf = build_elf(
+            3, // shared object
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0,
+                vaddr: 0,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x20_0000,
+            }],
+        );
 
-    Ok((
-        VirtAddr::new(loader.inner.virtual_address_offset.virtual_address_offset() as u64),
-        loader.entry_point(),
-        tls_template,
-    ))
-}
+        // A free address range is chosen with the alignment of the segment.
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert_eq!(loaded.virtual_address_offset % 0x20_0000, 0);
 
-/// A helper type used to offset virtual addresses for position independent
-/// executables.
-#[derive(Clone, Copy)]
-pub struct VirtualAddressOffset {
-    virtual_address_offset: i128,
-}
+        let mut options = LoaderOptions::new_default();
+        options.base_address = Some(0xffff_9000_0020_0000);
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        assert_eq!(
+            loaded.translate(0xffff_9000_0020_0000).0,
+            PhysAddr::new(0x10_0000)
+        );
 
-impl VirtualAddressOffset {
-    pub fn zero() -> Self {
-        Self::new(0)
+        // The base address moves the segment off its alignment.
+        options.base_address = Some(0xffff_9000_0000_1000);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::MisalignedSegment {
+                addr: 0xffff_9000_0000_1000,
+                align: 0x20_0000
+            })
+        ));
     }
 
-    pub fn new(virtual_address_offset: i128) -> Self {
-        Self {
-            virtual_address_offset,
-        }
+    #[test]
+    fn verified_kernel() {
+        let elf = build_elf(
+            2, // executable
+            0x3000,
+            &[Phdr {
+                ty: 1, // Load
+                flags: FLAG_R | FLAG_X,
+                offset: 0x1000,
+                vaddr: 0x20_1000,
+                file_size: 0x1000,
+                mem_size: 0x1000,
+                align: 0x1000,
+            }],
+        );
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut frame_allocator =
+            BumpAllocator::new(elf_addr + elf.len() as u64 + Size4KiB::SIZE, &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut frame_allocator);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let kernel = || Kernel {
+            elf: ElfFile::new(input).unwrap(),
+            config,
+            start_address: input.as_ptr(),
+            len: input.len(),
+        };
+
+        // Nothing is mapped if the verifier rejects the kernel.
+        let result = load_kernel_verified(
+            kernel(),
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            LoadCallbacks::default(),
+            |_| Err("invalid signature"),
+        );
+        assert!(matches!(
+            result,
+            Err(LoadKernelError::VerificationFailed("invalid signature"))
+        ));
+        assert!(page_table.calls.is_empty());
+
+        // `load_kernel` uses a physical memory offset of zero, so the segment
+        // is mapped to the host address of the ELF file.
+        let loaded = load_kernel_verified(
+            kernel(),
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            LoadCallbacks::default(),
+            |bytes| {
+                assert_eq!(bytes, elf);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(loaded.entry_point, VirtAddr::new(0x20_1000));
+        assert_eq!(
+            page_table.translate_addr(VirtAddr::new(0x20_1000)),
+            Some(PhysAddr::new(input.as_ptr() as u64 + 0x1000))
+        );
     }
 
-    pub fn virtual_address_offset(&self) -> i128 {
-        self.virtual_address_offset
+    #[test]
+    fn dynamic_interpreter() {
+        // Static position independent kernels have a dynamic section, but no
+        // interpreter.
+        let segment = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let elf = dynamic_kernel(&[]);
+        try_load_with_options(0x40_0000, 0x10_0000, &elf, LoaderOptions::new_default()).unwrap();
+
+        let path = b"/lib64/ld-linux-x86-64.so.2\0";
+        let interp = Phdr {
+            ty: 3, // Interp
+            flags: FLAG_R,
+            offset: 0x2000,
+            vaddr: 0x2000,
+            file_size: path.len() as u64,
+            mem_size: path.len() as u64,
+            align: 1,
+        };
+        let mut elf = dynamic_kernel_with(0x3000, &[interp, segment, DYNAMIC], &[]);
+        elf[0x2000..][..path.len()].copy_from_slice(path);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, LoaderOptions::new_default()),
+            Err(LoadKernelError::DynamicInterpreter(name))
+                if name.as_str() == "/lib64/ld-linux-x86-64.so.2"
+        ));
     }
-}
 
-impl Add<u64> for VirtualAddressOffset {
-    type Output = u64;
+    #[test]
+    fn frame_in_kernel_file() {
+        // The zeroed segment comes first, so its frame is the first one that
+        // is allocated.
+        let bss = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x2000,
+            vaddr: 0x20_2000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x4000, &[bss, code]);
 
-    fn add(self, offset: u64) -> Self::Output {
-        u64::try_from(
-            self.virtual_address_offset
-                .checked_add(i128::from(offset))
-                .unwrap(),
+        let loaded = load(0x40_0000, 0x10_0000, &elf);
+        assert!(loaded.translate(0x20_1000).0 >= PhysAddr::new(0x10_4000));
+        assert_eq!(loaded.read(0x20_1000, 0x100), [0; 0x100]);
+
+        // An allocator that returns the frames of the ELF file would
+        // overwrite it.
+        let elf_addr = PhysAddr::new(0x10_0000);
+        let mut memory = PhysicalMemory::new(0x40_0000);
+        memory.write(elf_addr, &elf);
+        let input = unsafe { core::slice::from_raw_parts(memory.ptr(elf_addr), elf.len()) };
+        let mut table_allocator = BumpAllocator::new(PhysAddr::new(0x20_0000), &memory);
+        let mut page_table = RecordingMapper::new(&memory, &mut table_allocator);
+        let mut frame_allocator = BumpAllocator::new(elf_addr, &memory);
+        let config = BootloaderConfig::new_default();
+        let mut used_entries =
+            UsedLevel4Entries::new(PhysAddr::new(memory.size()), 0, None, &config);
+        let mut options = LoaderOptions::new_default();
+        options.physical_memory_offset = memory.offset();
+        let mut loader = Loader::from_unaligned(
+            input,
+            &mut page_table,
+            &mut frame_allocator,
+            &mut used_entries,
+            options,
         )
-        .unwrap()
+        .unwrap();
+
+        assert!(matches!(
+            loader.load_segments(),
+            Err(LoadKernelError::FrameInKernelFile(frame))
+                if frame.start_address() == elf_addr
+        ));
+        assert_eq!(memory.read(elf_addr, 4), [0x7f, b'E', b'L', b'F']);
+    }
+
+    #[test]
+    fn max_physical_address() {
+        let code = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_X,
+            offset: 0x1000,
+            vaddr: 0x20_1000,
+            file_size: 0x1000,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let bss = Phdr {
+            ty: 1, // Load
+            flags: FLAG_R | FLAG_W,
+            offset: 0x2000,
+            vaddr: 0x20_2000,
+            file_size: 0,
+            mem_size: 0x1000,
+            align: 0x1000,
+        };
+        let elf = build_elf(2, 0x3000, &[code, bss]);
+        let mut options = LoaderOptions::new_default();
+
+        // All frames are below the limit.
+        options.max_physical_address = Some(0x40_0000);
+        let loaded = load_with_options(0x40_0000, 0x10_0000, &elf, options);
+        assert_eq!(loaded.translate(0x20_1000).0, PhysAddr::new(0x10_1000));
+
+        // Both the frames of the ELF file and the allocated frames are
+        // checked.
+        options.max_physical_address = Some(0x10_1800);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::FrameAboveLimit(0x10_1000))
+        ));
+        options.max_physical_address = Some(0x10_3000);
+        assert!(matches!(
+            try_load_with_options(0x40_0000, 0x10_0000, &elf, options),
+            Err(LoadKernelError::FrameAboveLimit(addr)) if addr >= 0x10_3000
+        ));
     }
 }
@@ -0,0 +1,209 @@
+use x86_64::{
+    structures::paging::{
+        mapper::{
+            FlagUpdateError, MapToError, MapperFlush, MapperFlushAll, TranslateError,
+            TranslateResult, UnmapError,
+        },
+        page::PageRange,
+        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+/// A page table that only needs to support 4 KiB pages.
+///
+/// The [`Loader`](crate::load_kernel::Loader) requires a
+/// [`MapperAllSizes`](x86_64::structures::paging::mapper::MapperAllSizes)
+/// because it maps large read-only segments with 2 MiB pages. This adapter
+/// allows loading a kernel with a page table that only implements
+/// [`Mapper<Size4KiB>`] by mapping every huge page as 4 KiB pages instead.
+///
+/// Only [`Mapper::map_to`] and [`Mapper::update_flags`] are emulated for
+/// huge pages. Unmapping or translating a huge page reports it as not
+/// mapped, because the page table contains no huge pages.
+pub struct SmallPageMapper<M>(pub M);
+
+impl<M> Translate for SmallPageMapper<M>
+where
+    M: Translate,
+{
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        self.0.translate(addr)
+    }
+}
+
+impl<M> Mapper<Size4KiB> for SmallPageMapper<M>
+where
+    M: Mapper<Size4KiB>,
+{
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        unsafe {
+            self.0
+                .map_to_with_table_flags(page, frame, flags, parent_table_flags, frame_allocator)
+        }
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+        self.0.unmap(page)
+    }
+
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        unsafe { self.0.update_flags(page, flags) }
+    }
+
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe { self.0.set_flags_p4_entry(page, flags) }
+    }
+
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe { self.0.set_flags_p3_entry(page, flags) }
+    }
+
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe { self.0.set_flags_p2_entry(page, flags) }
+    }
+
+    fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        self.0.translate_page(page)
+    }
+}
+
+/// Returns the 4 KiB pages that make up the given page.
+fn small_pages<S: PageSize>(page: Page<S>) -> PageRange<Size4KiB> {
+    let start = Page::containing_address(page.start_address());
+    Page::range(start, start + S::SIZE / Size4KiB::SIZE)
+}
+
+/// Returns the 4 KiB page at the start of the given page.
+fn first_small_page<S: PageSize>(page: Page<S>) -> Page<Size4KiB> {
+    Page::containing_address(page.start_address())
+}
+
+macro_rules! impl_huge_page_mapper {
+    ($size:ty) => {
+        impl<M> Mapper<$size> for SmallPageMapper<M>
+        where
+            M: Mapper<Size4KiB>,
+        {
+            unsafe fn map_to_with_table_flags<A>(
+                &mut self,
+                page: Page<$size>,
+                frame: PhysFrame<$size>,
+                flags: PageTableFlags,
+                parent_table_flags: PageTableFlags,
+                frame_allocator: &mut A,
+            ) -> Result<MapperFlush<$size>, MapToError<$size>>
+            where
+                Self: Sized,
+                A: FrameAllocator<Size4KiB> + ?Sized,
+            {
+                let start_frame = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+                for (offset, small_page) in small_pages(page).enumerate() {
+                    let small_frame = start_frame + offset as u64;
+                    let flusher = unsafe {
+                        self.0.map_to_with_table_flags(
+                            small_page,
+                            small_frame,
+                            flags,
+                            parent_table_flags,
+                            frame_allocator,
+                        )
+                    }
+                    .map_err(|err| match err {
+                        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+                        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                        MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+                            PhysFrame::containing_address(frame.start_address()),
+                        ),
+                    })?;
+                    // The caller flushes the whole huge page.
+                    flusher.ignore();
+                }
+                Ok(MapperFlush::new(page))
+            }
+
+            fn unmap(
+                &mut self,
+                _page: Page<$size>,
+            ) -> Result<(PhysFrame<$size>, MapperFlush<$size>), UnmapError> {
+                Err(UnmapError::PageNotMapped)
+            }
+
+            unsafe fn update_flags(
+                &mut self,
+                page: Page<$size>,
+                flags: PageTableFlags,
+            ) -> Result<MapperFlush<$size>, FlagUpdateError> {
+                for small_page in small_pages(page) {
+                    unsafe { self.0.update_flags(small_page, flags) }?.ignore();
+                }
+                Ok(MapperFlush::new(page))
+            }
+
+            unsafe fn set_flags_p4_entry(
+                &mut self,
+                page: Page<$size>,
+                flags: PageTableFlags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.0.set_flags_p4_entry(first_small_page(page), flags) }
+            }
+
+            unsafe fn set_flags_p3_entry(
+                &mut self,
+                page: Page<$size>,
+                flags: PageTableFlags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.0.set_flags_p3_entry(first_small_page(page), flags) }
+            }
+
+            unsafe fn set_flags_p2_entry(
+                &mut self,
+                page: Page<$size>,
+                flags: PageTableFlags,
+            ) -> Result<MapperFlushAll, FlagUpdateError> {
+                unsafe { self.0.set_flags_p2_entry(first_small_page(page), flags) }
+            }
+
+            fn translate_page(
+                &self,
+                _page: Page<$size>,
+            ) -> Result<PhysFrame<$size>, TranslateError> {
+                Err(TranslateError::PageNotMapped)
+            }
+        }
+    };
+}
+
+impl_huge_page_mapper!(Size2MiB);
+impl_huge_page_mapper!(Size1GiB);
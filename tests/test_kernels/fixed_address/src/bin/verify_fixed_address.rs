@@ -0,0 +1,33 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader_api::{entry_point, BootInfo};
+use test_kernel_fixed_address::{exit_qemu, QemuExitCode, BOOTLOADER_CONFIG, IMAGE_BASE};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
+    // The kernel is not position independent, so it must be loaded exactly at
+    // its link address (set through `profile.test.package.rustflags` in the
+    // top-level Cargo.toml).
+    let rip = x86_64::registers::read_rip().as_u64();
+    assert_eq!(rip & 0xffff_ffff_ff00_0000, IMAGE_BASE);
+
+    // Function addresses are absolute addresses in non-PIE executables, so
+    // they only match the instruction pointer if the kernel was loaded at
+    // its link address.
+    let kernel_main_addr = kernel_main as usize as u64;
+    assert!(kernel_main_addr <= rip && rip - kernel_main_addr < 0x1000);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_fixed_address::serial(), "PANIC: {info}");
+    exit_qemu(QemuExitCode::Failed);
+}